@@ -0,0 +1,65 @@
+// Calendar helpers. Timestamps are nanoseconds since the Unix epoch, as returned
+// by `ic_cdk::api::time()`, and calendar fields are in East Africa Time
+// (UTC+3, no daylight saving).
+
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, TimeZone, Timelike};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const EAT_OFFSET_SECONDS: i32 = 3 * 60 * 60;
+
+fn eat() -> FixedOffset {
+    FixedOffset::east_opt(EAT_OFFSET_SECONDS).expect("valid offset")
+}
+
+// Local date and time of a timestamp
+pub fn to_local(timestamp: u64) -> DateTime<FixedOffset> {
+    let seconds = (timestamp / NANOS_PER_SECOND) as i64;
+    let nanos = (timestamp % NANOS_PER_SECOND) as u32;
+
+    DateTime::from_timestamp(seconds, nanos)
+        .unwrap_or_default()
+        .with_timezone(&eat())
+}
+
+// Timestamp of local midnight at the start of a date
+fn midnight(date: NaiveDate) -> u64 {
+    let seconds = eat()
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0).unwrap_or_default())
+        .single()
+        .map_or(0, |local| local.timestamp());
+
+    seconds.max(0) as u64 * NANOS_PER_SECOND
+}
+
+// Day of the week, 0 being Sunday
+pub fn day_of_week(timestamp: u64) -> u8 {
+    to_local(timestamp).weekday().num_days_from_sunday() as u8
+}
+
+pub fn hour_of_day(timestamp: u64) -> u8 {
+    to_local(timestamp).hour() as u8
+}
+
+// Local midnight at the start of the day a timestamp falls on
+pub fn start_of_day(timestamp: u64) -> u64 {
+    midnight(to_local(timestamp).date_naive())
+}
+
+// Calendar month of a timestamp as YYYYMM, e.g. 202410 for October 2024
+pub fn month_of(timestamp: u64) -> u64 {
+    let local = to_local(timestamp);
+    local.year() as u64 * 100 + local.month() as u64
+}
+
+// Start and end of a YYYYMM calendar month, None if the month isn't valid
+pub fn month_bounds(month: u64) -> Option<(u64, u64)> {
+    let (year, month) = ((month / 100) as i32, (month % 100) as u32);
+    let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+    let next = if month == 12 {
+        NaiveDate::from_ymd_opt(year + 1, 1, 1)?
+    } else {
+        NaiveDate::from_ymd_opt(year, month + 1, 1)?
+    };
+
+    Some((midnight(first), midnight(next)))
+}
@@ -0,0 +1,182 @@
+// Geographic helpers used by tracking, geofencing and ETA computation.
+// All distances are in meters and all coordinates are WGS84 degrees.
+
+const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+
+// Great-circle distance between two points
+pub fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let d_lat = (lat2 - lat1).to_radians();
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+
+    2.0 * EARTH_RADIUS_METERS * a.sqrt().atan2((1.0 - a).sqrt())
+}
+
+// Result of snapping a point onto a polyline
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Projection {
+    pub segment_index: usize,
+    pub distance_from_line: f64,
+    pub distance_along: f64,
+}
+
+// Projects a point onto a polyline of (latitude, longitude) vertices.
+// Each segment is treated as flat using an equirectangular approximation around
+// the point, which is accurate enough for the few-kilometre segments of a route.
+pub fn project_onto_polyline(points: &[(f64, f64)], lat: f64, lon: f64) -> Option<Projection> {
+    match points.len() {
+        0 => None,
+        1 => Some(Projection {
+            segment_index: 0,
+            distance_from_line: haversine_distance(lat, lon, points[0].0, points[0].1),
+            distance_along: 0.0,
+        }),
+        _ => {
+            let mut best: Option<Projection> = None;
+            let mut travelled = 0.0;
+
+            for (index, pair) in points.windows(2).enumerate() {
+                let (a, b) = (pair[0], pair[1]);
+                let segment_length = haversine_distance(a.0, a.1, b.0, b.1);
+                let fraction = segment_fraction(a, b, (lat, lon));
+                let snapped = (a.0 + (b.0 - a.0) * fraction, a.1 + (b.1 - a.1) * fraction);
+                let distance = haversine_distance(lat, lon, snapped.0, snapped.1);
+
+                let closer = match best {
+                    Some(p) => distance < p.distance_from_line,
+                    None => true,
+                };
+                if closer {
+                    best = Some(Projection {
+                        segment_index: index,
+                        distance_from_line: distance,
+                        distance_along: travelled + segment_length * fraction,
+                    });
+                }
+
+                travelled += segment_length;
+            }
+
+            best
+        }
+    }
+}
+
+// Total length of a polyline
+pub fn polyline_length(points: &[(f64, f64)]) -> f64 {
+    points
+        .windows(2)
+        .map(|pair| haversine_distance(pair[0].0, pair[0].1, pair[1].0, pair[1].1))
+        .sum()
+}
+
+// Distance along a polyline at each of its vertices
+pub fn cumulative_distances(points: &[(f64, f64)]) -> Vec<f64> {
+    let mut travelled = 0.0;
+    let mut distances = Vec::with_capacity(points.len());
+
+    for (index, point) in points.iter().enumerate() {
+        if index > 0 {
+            let previous = points[index - 1];
+            travelled += haversine_distance(previous.0, previous.1, point.0, point.1);
+        }
+        distances.push(travelled);
+    }
+
+    distances
+}
+
+// Whether a point lies inside a circular zone
+pub fn within_radius(lat: f64, lon: f64, center_lat: f64, center_lon: f64, radius: f64) -> bool {
+    haversine_distance(lat, lon, center_lat, center_lon) <= radius
+}
+
+// Douglas-Peucker simplification, returns the indices of the points to keep.
+// Points closer than `tolerance` meters to the simplified line are dropped.
+pub fn simplify(points: &[(f64, f64)], tolerance: f64) -> Vec<usize> {
+    if points.len() < 3 {
+        return (0..points.len()).collect();
+    }
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    let mut stack = vec![(0, points.len() - 1)];
+    while let Some((first, last)) = stack.pop() {
+        let mut furthest = (0, 0.0);
+        for index in first + 1..last {
+            let p = points[index];
+            let fraction = segment_fraction(points[first], points[last], p);
+            let snapped = (
+                points[first].0 + (points[last].0 - points[first].0) * fraction,
+                points[first].1 + (points[last].1 - points[first].1) * fraction,
+            );
+            let distance = haversine_distance(p.0, p.1, snapped.0, snapped.1);
+            if distance > furthest.1 {
+                furthest = (index, distance);
+            }
+        }
+
+        if furthest.1 > tolerance {
+            keep[furthest.0] = true;
+            stack.push((first, furthest.0));
+            stack.push((furthest.0, last));
+        }
+    }
+
+    keep.iter()
+        .enumerate()
+        .filter(|(_, kept)| **kept)
+        .map(|(index, _)| index)
+        .collect()
+}
+
+// Encodes points with the encoded polyline algorithm at 5 decimal places
+pub fn encode_polyline(points: &[(f64, f64)]) -> String {
+    let mut encoded = String::new();
+    let mut previous = (0i64, 0i64);
+
+    for &(lat, lon) in points {
+        let current = ((lat * 1e5).round() as i64, (lon * 1e5).round() as i64);
+        encode_value(current.0 - previous.0, &mut encoded);
+        encode_value(current.1 - previous.1, &mut encoded);
+        previous = current;
+    }
+
+    encoded
+}
+
+fn encode_value(value: i64, encoded: &mut String) {
+    let mut value = if value < 0 { !(value << 1) } else { value << 1 };
+    while value >= 0x20 {
+        encoded.push(char::from((((value & 0x1f) | 0x20) + 63) as u8));
+        value >>= 5;
+    }
+    encoded.push(char::from((value + 63) as u8));
+}
+
+// Checks that a coordinate is a valid latitude/longitude pair
+pub fn is_valid_coordinate(lat: f64, lon: f64) -> bool {
+    lat.is_finite()
+        && lon.is_finite()
+        && (-90.0..=90.0).contains(&lat)
+        && (-180.0..=180.0).contains(&lon)
+}
+
+// Position of the projection of `p` along segment a-b, clamped to [0, 1]
+fn segment_fraction(a: (f64, f64), b: (f64, f64), p: (f64, f64)) -> f64 {
+    let scale = p.0.to_radians().cos();
+    let (ax, ay) = (a.1 * scale, a.0);
+    let (bx, by) = (b.1 * scale, b.0);
+    let (px, py) = (p.1 * scale, p.0);
+
+    let (dx, dy) = (bx - ax, by - ay);
+    let length_squared = dx * dx + dy * dy;
+    if length_squared == 0.0 {
+        return 0.0;
+    }
+
+    (((px - ax) * dx + (py - ay) * dy) / length_squared).clamp(0.0, 1.0)
+}
@@ -0,0 +1,130 @@
+// HTTP gateway helpers: the request and response records of the gateway
+// protocol, URL parsing and JSON responses. Routing to the endpoints lives in
+// the canister itself so both interfaces share the same checks.
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub type HeaderField = (String, String);
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<HeaderField>,
+    pub body: Vec<u8>,
+    pub certificate_version: Option<u16>,
+}
+
+#[derive(CandidType, Serialize, Clone)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<HeaderField>,
+    pub body: Vec<u8>,
+    pub upgrade: Option<bool>, // asks the gateway to repeat the request as an update call
+}
+
+// Token of an `Authorization: Bearer <token>` header
+pub fn bearer_token(headers: &[HeaderField]) -> Option<&str> {
+    headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+// Path segments and query parameters of a request URL
+pub fn split_url(url: &str) -> (Vec<String>, HashMap<String, String>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+
+    let segments = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(percent_decode)
+        .collect();
+    let params = query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (name, value) = p.split_once('=').unwrap_or((p, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect();
+
+    (segments, params)
+}
+
+// Values of the `{name}` placeholders when a path matches a template such as
+// "/saccos/{sacco_id}/matatus"
+pub fn match_path(template: &str, segments: &[String]) -> Option<Vec<String>> {
+    let parts: Vec<&str> = template.split('/').filter(|s| !s.is_empty()).collect();
+    if parts.len() != segments.len() {
+        return None;
+    }
+
+    let mut values = Vec::new();
+    for (part, segment) in parts.iter().zip(segments) {
+        if part.starts_with('{') && part.ends_with('}') {
+            values.push(segment.clone());
+        } else if part != segment {
+            return None;
+        }
+    }
+    Some(values)
+}
+
+// Names of the placeholders in a path template
+pub fn path_parameters(template: &str) -> Vec<&str> {
+    template
+        .split('/')
+        .filter_map(|s| s.strip_prefix('{').and_then(|s| s.strip_suffix('}')))
+        .collect()
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Response with a JSON body
+pub fn json<T: Serialize>(status_code: u16, value: &T) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Cache-Control".to_string(), "no-store".to_string()), // replies can hold a SACCO's data
+        ],
+        body: serde_json::to_vec(value).unwrap_or_default(),
+        upgrade: None,
+    }
+}
+
+// Response telling the gateway to send the request again as an update call
+pub fn upgrade() -> HttpResponse {
+    HttpResponse {
+        status_code: 200,
+        headers: Vec::new(),
+        body: Vec::new(),
+        upgrade: Some(true),
+    }
+}
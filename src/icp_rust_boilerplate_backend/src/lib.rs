@@ -1,24 +1,165 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+use candid::{CandidType, Decode, Encode, Principal};
 use ic_cdk::api::time;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell, time::Duration};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
-use std::collections::HashMap;
+type CsvRow = Vec<(String, String)>; // column and value of each field
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+const DEFAULT_CORRIDOR_BUFFER: f64 = 150.0; // meters
+const DEFAULT_STAGE_RADIUS: f64 = 50.0; // meters
+const PSV_SPEED_LIMIT: f64 = 80.0; // km/h, enforced by speed governors
+const DEFAULT_SUSTAINED_PINGS: u32 = 3;
+const OVERSPEED_PENALTY: f32 = 5.0;
+const GOVERNOR_PENALTY: f32 = 10.0;
+const MIN_ETA_SPEED: f64 = 5.0; // km/h, keeps ETAs finite while stationary
+const RECENT_SPEED_WEIGHT: f64 = 0.6;
+const MAX_SEGMENT_SAMPLES: u32 = 50;
+const STALE_TRACKING_NANOS: u64 = 10 * 60 * 1_000_000_000; // positions older than this are ignored
+const NEXT_MATATU_LIMIT: usize = 5;
+const DEFAULT_RAW_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000; // one day of raw pings
+const DEFAULT_COMPRESSION_TOLERANCE: f64 = 10.0; // meters
+const HISTORY_BUCKET_NANOS: u64 = 60 * 60 * 1_000_000_000; // history is compressed an hour at a time
+const MIN_RAW_WINDOW: u64 = 60 * 60 * 1_000_000_000;
+const MAX_LOCATION_BATCH: usize = 1000;
+const MAX_STAGE_FARES: usize = 100; // keeps fare rules within their stable storage bound
+const MAX_FARE_OVERRIDES: usize = 20;
+const MAX_FARE_DISCOUNTS: usize = 10;
+const MAX_FARE_LABEL: usize = 32; // override names and discount categories, snapshotted on tickets
+const MAX_CLOCK_SKEW: u64 = 60 * 1_000_000_000; // how far ahead of the canister a device clock may run
+const DEFAULT_DEVICE_OFFLINE_AFTER: u64 = 5 * 60 * 1_000_000_000;
+const HOUR_NANOS: u64 = 60 * 60 * 1_000_000_000;
+const DAY_NANOS: u64 = 24 * HOUR_NANOS;
+const MAX_DAILY_DRIVING: u64 = 8 * HOUR_NANOS; // NTSA limit for PSV drivers
+const MAX_CONTINUOUS_DRIVING: u64 = 4 * HOUR_NANOS;
+const MIN_DRIVING_BREAK: u64 = HOUR_NANOS / 2; // a shorter gap doesn't interrupt continuous driving
+const MIN_DAILY_REST: u64 = 10 * HOUR_NANOS;
+const MAX_WEEKLY_DRIVING: u64 = 48 * HOUR_NANOS;
+const FATIGUE_PENALTY: f32 = 5.0;
+const MIN_TRAFFIC_SAMPLES: u32 = 3; // observations needed before a window's traffic is trusted
+const TRAFFIC_LEARNING_INTERVAL: u64 = HOUR_NANOS;
+const LEGACY_MONTH_NANOS: u64 = 30 * DAY_NANOS; // performance was kept per 30-day period before calendar months
+const FIRST_CALENDAR_MONTH: u64 = 197001; // smaller months are legacy 30-day period numbers
+const MIN_DEMAND_SAMPLES: u32 = 3; // service days observed before an hour's demand is forecast
+const MAX_DEMAND_SAMPLES: u32 = 52; // the seasonal average covers about a year of weeks
+const DEMAND_SMOOTHING: f64 = 0.3; // weight of the latest day in the smoothed forecast
+const TYPICAL_MATATU_CAPACITY: u32 = 14; // used when a route has no licensed matatu
+const MAX_SACCO_CODE: usize = 6;
+// Text and list limits that keep records within their stable storage bounds
+const MAX_TEXT: usize = 100; // names, places, emails and license numbers, in bytes
+const MAX_ROUTE_NAME: usize = 40; // matatus and trips repeat it
+const MAX_ROUTE_PLACE: usize = 32; // start and end points
+const MAX_PEAK_WINDOWS: usize = 8;
+const MAX_ROUTE_STOPS: usize = 100;
+const ROUTE_MAX_SIZE: u32 = 512;
+const ROUTE_FIXED_SIZE: usize = 224; // Candid header, type table and fixed-size fields of a route, rounded up
+const TRAFFIC_PATTERN_SIZE: usize = 8; // encoded size of one learned pattern
+                                       // Learned patterns fill what a route with the longest allowed text and peak windows leaves free
+const MAX_ROUTE_PATTERNS: usize = (ROUTE_MAX_SIZE as usize
+    - ROUTE_FIXED_SIZE
+    - MAX_ROUTE_NAME
+    - 2 * MAX_ROUTE_PLACE
+    - 3 * MAX_PEAK_WINDOWS)
+    / TRAFFIC_PATTERN_SIZE;
+const LEGACY_MAINTENANCE_DOWNTIME: u32 = 24; // hours, for records kept before downtime was recorded
+const MAX_EXPORT_BYTES: usize = 1_000_000; // keeps an export chunk well inside the reply size limit
+const MAX_EXPORT_SCAN: usize = 20_000; // records an export chunk may look at
+const MAX_IMPORT_ROWS: usize = 500; // rows a single import may hold
+const DEFAULT_API_TOKEN_TTL: u64 = 7 * DAY_NANOS;
+const MAX_API_TOKEN_TTL: u64 = 30 * DAY_NANOS;
+const DEFAULT_AUDIT_MAX_ENTRIES: u64 = 200_000;
+const DEFAULT_AUDIT_MAX_AGE: u64 = 365 * DAY_NANOS;
+const AUDIT_PRUNE_BATCH: usize = 16; // oldest entries removed per call, keeps pruning cheap
+const AUDIT_PAGE_SIZE: u32 = 100;
+const MAX_AUDIT_SCAN: u64 = 10_000; // entries a single audit query may look at
+const TRANSFER_PENALTY: u64 = 5 * 60 * 1_000_000_000; // walking and waiting when changing matatus
+const MAX_ALTERNATE_ROUTES: usize = 3;
+const SCHEDULE_GRACE_PERIOD: u64 = 15 * 60 * 1_000_000_000; // a schedule not started by then is missed
+const ON_TIME_TOLERANCE: u64 = 5 * 60 * 1_000_000_000;
+const SCHEDULE_SWEEP_INTERVAL: u64 = 60 * 1_000_000_000;
+const MIGRATION_BATCH: usize = 1000; // records an upgrade migration step looks at per timer tick
+const MAX_MIGRATION_PROBLEMS: usize = 20;
+
+mod calendar;
+mod geo;
+mod http;
 
 // SACCO struct
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct SACCO {
     id: u64,
+    code: String, // short unique code, prefixes fleet and staff numbers
     name: String,
     location: String,
     contact: String,
     email: String,
     created_at: u64,
+    status: String, // "active", "inactive", "archived"
+    archived_at: Option<u64>,
+    version: u64, // bumped on every change, updates must name the version they read
+}
+
+// Key for a SACCO member
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+struct MemberKey {
+    sacco_id: u64,
+    principal: Principal,
+}
+
+// Someone allowed to act for a SACCO
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SaccoMember {
+    sacco_id: u64,
+    principal: Principal,
+    role: String, // "admin", "staff"; only admins manage members
+    added_at: u64,
+}
+
+// Token a client of the HTTP gateway sends to act as the principal it was issued to
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ApiToken {
+    id: u64,
+    principal: Principal,
+    secret_hash: Vec<u8>, // SHA-256 of the secret, which is only returned when the token is issued
+    created_at: u64,
+    expires_at: u64,
+    status: String, // "active", "revoked"
+}
+
+// API token as stored by earlier versions, with the secret in the clear
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LegacyApiToken {
+    id: u64,
+    principal: Principal,
+    secret: String,
+    created_at: u64,
+    expires_at: u64,
+    status: String,
+}
+
+// API token as shown to its holder, without the secret
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ApiTokenInfo {
+    id: u64,
+    principal: Principal,
+    created_at: u64,
+    expires_at: u64,
+    status: String,
+}
+
+// Newly issued API token, sent as `Authorization: Bearer <token>`
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ApiTokenGrant {
+    token: String,
+    info: ApiTokenInfo,
 }
 
 // Matatu struct
@@ -26,10 +167,13 @@ struct SACCO {
 struct Matatu {
     id: u64,
     sacco_id: u64,
+    fleet_number: String, // e.g. "KBS-007", numbered within the SACCO
     plate_number: String,
     capacity: u32,
     route: String,
-    status: String, // "active", "inactive", "maintenance"
+    status: String, // "active", "inactive", "maintenance", "archived"
+    archived_at: Option<u64>,
+    version: u64,
 }
 
 // Matatu Analytics struct
@@ -47,10 +191,39 @@ struct MatatuAnalytics {
 struct Driver {
     id: u64,
     sacco_id: u64,
+    staff_number: String, // e.g. "KBS-D012", numbered within the SACCO
     name: String,
     license_number: String,
     contact: String,
     assigned_matatu: Option<u64>, // Matatu ID
+    principal: Option<Principal>, // identity the driver signs in with
+    working_days: Vec<u8>,        // 0-6 representing Sunday-Saturday, empty means every day
+    status: String,               // "active", "inactive", "archived"
+    archived_at: Option<u64>,
+    version: u64,
+}
+
+// Shift worked by a driver, recorded when they check in and out
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Shift {
+    id: u64,
+    driver_id: u64,
+    check_in: u64,
+    check_out: Option<u64>,
+}
+
+// Attendance of a driver over a calendar month
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct AttendanceReport {
+    driver_id: u64,
+    month: u64,        // YYYYMM
+    working_days: u32, // days the driver was expected to work so far
+    days_worked: u32,
+    leave_days: u32,
+    sick_days: u32,
+    suspended_days: u32,
+    absent_days: u32,
+    hours_on_shift: f64,
 }
 
 // Trip struct
@@ -65,6 +238,22 @@ struct Trip {
     route: String,
     status: String, // "ongoing", "completed", "cancelled"
     revenue: f64,
+    schedule_id: Option<u64>, // schedule the trip was run for
+}
+
+// Ticket struct
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Ticket {
+    id: u64,
+    trip_id: u64,
+    matatu_id: u64,
+    route_id: Option<u64>,
+    boarding_stop_id: Option<u64>,
+    alighting_stop_id: Option<u64>,
+    fare: f64,
+    status: String, // "onboard", "completed", "cancelled"
+    issued_at: u64,
+    fare_quote: Option<FareQuote>, // fare rule applied when the ticket was issued
 }
 
 // Maintenance struct
@@ -73,17 +262,32 @@ struct Maintenance {
     id: u64,
     matatu_id: u64,
     date: u64,
+    downtime_hours: u32, // how long the matatu is off the road from `date`
     description: String,
     cost: f64,
     status: String, // "scheduled", "in_progress", "completed"
 }
 
+// Period a driver is off work
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DriverLeave {
+    id: u64,
+    driver_id: u64,
+    start_date: u64,
+    end_date: u64,
+    leave_type: String, // "leave", "sick", "suspension"
+    status: String,     // "pending", "approved", "rejected"
+    reason: String,
+    created_at: u64,
+    reviewed_at: Option<u64>,
+}
+
 // Driver Performance struct
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct DriverPerformance {
     id: u64,
     driver_id: u64,
-    month: u64,
+    month: u64, // calendar month in East Africa Time as YYYYMM
     trips_completed: u32,
     total_revenue: f64,
     customer_rating: f32,
@@ -126,6 +330,7 @@ struct FuelConsumption {
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Route {
     id: u64,
+    sacco_id: u64, // SACCO that runs the route, names are unique within it
     name: String,
     start_point: String,
     end_point: String,
@@ -135,6 +340,42 @@ struct Route {
     traffic_patterns: Vec<TrafficPattern>,
     average_passengers: u32,
     price: f64,
+    corridor_buffer: f64, // allowed deviation either side of the route line, in meters
+    status: String,       // "active", "inactive", "archived"
+    archived_at: Option<u64>,
+}
+
+// Route Stop struct
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RouteStop {
+    id: u64,
+    route_id: u64,
+    sequence: u32,
+    name: String,
+    latitude: f64,
+    longitude: f64,
+    radius: f64, // stage geofence radius in meters
+}
+
+// Predicted arrival of a matatu at one stop
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct StopPrediction {
+    stop_id: u64,
+    stop_name: String,
+    sequence: u32,
+    predicted_arrival: u64,
+}
+
+// A matatu heading to a stop, as shown to waiting passengers
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct NextMatatu {
+    matatu_id: u64,
+    plate_number: String,
+    trip_id: Option<u64>,     // set when the matatu is already on the road
+    schedule_id: Option<u64>, // set when the matatu hasn't departed yet
+    predicted_arrival: u64,
+    free_seats: u32,
+    distance_away: f64, // along the route, in meters
 }
 
 // Route Optimization struct
@@ -144,12 +385,22 @@ struct RouteOptimization {
     optimal_start_time: u64,
     estimated_duration: u32,
     congestion_level: u8,
-    alternate_routes: Vec<Route>,
+    alternate_routes: Vec<Route>, // routes used by the alternatives, best first
+    alternatives: Vec<AlternateRoute>,
+}
+
+// A way between the same start and end points over other routes of the SACCO
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct AlternateRoute {
+    route_ids: Vec<u64>, // routes ridden, in order
+    stop_ids: Vec<u64>,  // stops passed through, including transfers
+    transfers: u32,
+    expected_duration: u32, // in minutes, for the requested departure time
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct TimeWindow {
-    start_hour: u8,
+    start_hour: u8, // 0-23 in East Africa Time
     end_hour: u8,
     day_of_week: u8, // 0-6 representing Sunday-Saturday
 }
@@ -161,6 +412,59 @@ struct TrafficPattern {
     average_delay: u32,   // in minutes
 }
 
+// Fare rules of a route. A quote starts from the stage fare between the
+// boarding and alighting stops, or the base fare, adds the peak surcharge
+// inside the route's peak hours, then applies the latest matching override
+// and the passenger's discount.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct FareRule {
+    route_id: u64,
+    base_fare: f64, // whole route, or when the stops aren't known
+    stage_fares: Vec<StageFare>,
+    peak_surcharge: f64, // added during the route's peak hours
+    overrides: Vec<FareOverride>,
+    discounts: Vec<FareDiscount>,
+    updated_at: u64,
+}
+
+// Fare between two stops of a route, in either direction
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct StageFare {
+    from_stop_id: u64,
+    to_stop_id: u64,
+    fare: f64,
+}
+
+// Fare change for a period, e.g. rain, a holiday or a fuel price rise
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct FareOverride {
+    name: String,
+    start_time: u64,
+    end_time: u64,
+    multiplier: f64, // applied to the fare before the surcharge
+    surcharge: f64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct FareDiscount {
+    category: String, // e.g. "student", "member"
+    percent: f64,     // 0-100
+}
+
+// How a fare was worked out
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct FareQuote {
+    route_id: u64,
+    base_fare: f64, // stage fare when one applies
+    peak_surcharge: f64,
+    override_name: Option<String>,
+    override_adjustment: f64, // change made by the override
+    category: Option<String>,
+    discount: f64, // amount taken off
+    fare: f64,
+    rule_updated_at: u64, // version of the rule applied, 0 for the route's flat price
+}
+
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct CustomerFeedback {
     id: u64,
@@ -181,1182 +485,9859 @@ struct Schedule {
     route_id: u64,
     start_time: u64,
     end_time: u64,
-    status: String, // "scheduled", "in_progress", "completed", "cancelled"
+    status: String, // "scheduled", "in_progress", "completed", "missed", "cancelled"
     created_at: u64,
+    estimated_arrival: Option<u64>, // projected from live tracking, end_time stays as planned
+    trip_id: Option<u64>,
+    actual_start: Option<u64>,
+    actual_end: Option<u64>,
 }
 
+// How reliably a driver starts scheduled departures on time
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct LocationUpdate {
-    id: u64,
-    matatu_id: u64,
-    latitude: f64,
-    longitude: f64,
-    speed: f64,
-    timestamp: u64,
+struct DriverPunctuality {
+    driver_id: u64,
+    scheduled: u32,
+    on_time: u32,
+    late: u32,
+    missed: u32,
+    average_delay: f64, // minutes past the planned start, over schedules that ran
+    on_time_rate: f64,  // 0.0 - 1.0
 }
 
+// Roster produced for a SACCO's day of service
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct FinancialReport {
-    id: u64,
+struct Roster {
     sacco_id: u64,
-    period_start: u64,
-    period_end: u64,
-    total_revenue: f64,
-    total_expenses: f64,
-    expense_breakdown: Vec<ExpenseCategory>,
-    revenue_breakdown: Vec<RevenueSource>,
-    profit_margin: f64,
-    created_at: u64,
+    date: u64, // start of the day in nanoseconds
+    schedules: Vec<Schedule>,
+    uncovered: Vec<UncoveredDemand>,
 }
 
+// A proposed roster and how it differs from the schedules it would replace
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct ExpenseCategory {
-    category: String,
-    amount: f64,
-    percentage: f64,
+struct RosterPlan {
+    roster: Roster, // schedules that would be created have id 0
+    added: Vec<Schedule>,
+    removed: Vec<Schedule>,
+    reassigned: Vec<ScheduleChange>,
+    unchanged: u32,
 }
 
+// A departure that keeps its slot but gets a different matatu or driver
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct RevenueSource {
-    source: String,
-    amount: f64,
-    percentage: f64,
+struct ScheduleChange {
+    before: Schedule,
+    after: Schedule,
 }
 
-// Payload structs
+// Demand in a peak window the roster couldn't serve
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct CreateSACCOPayload {
+struct UncoveredDemand {
+    route_id: u64,
+    start_time: u64,
+    end_time: u64,
+    passengers: u32,
+    reason: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct LocationUpdate {
+    matatu_id: u64,
+    latitude: f64,
+    longitude: f64,
+    speed: f64, // in km/h
+    timestamp: u64,
+}
+
+// Key for location history, ordered by matatu then time
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+struct LocationKey {
+    matatu_id: u64,
+    timestamp: u64,
+}
+
+// Location update as stored before history was keyed by matatu and time
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct LegacyLocationUpdate {
+    id: u64,
+    matatu_id: u64,
+    latitude: f64,
+    longitude: f64,
+    speed: f64,
+    timestamp: u64,
+}
+
+// SACCO as stored by earlier versions, fields added since are optional so
+// every older layout decodes into it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct LegacySACCO {
+    id: u64,
+    code: Option<String>,
     name: String,
     location: String,
     contact: String,
     email: String,
+    created_at: u64,
+    status: Option<String>,
+    archived_at: Option<u64>,
+    version: Option<u64>,
 }
 
+// Route as stored by earlier versions, fields added since are optional so
+// every older layout decodes into it
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct RegisterMatatuPayload {
-    sacco_id: u64,
-    plate_number: String,
-    capacity: u32,
-    route: String,
+struct LegacyRoute {
+    id: u64,
+    sacco_id: Option<u64>,
+    name: String,
+    start_point: String,
+    end_point: String,
+    distance: f64,
+    estimated_time: u32,
+    peak_hours: Vec<TimeWindow>,
+    traffic_patterns: Vec<TrafficPattern>,
+    average_passengers: u32,
+    price: f64,
+    corridor_buffer: Option<f64>,
+    status: Option<String>,
+    archived_at: Option<u64>,
 }
 
+// Driver as stored by earlier versions, fields added since are optional so
+// every older layout decodes into it
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct RegisterDriverPayload {
+struct LegacyDriver {
+    id: u64,
     sacco_id: u64,
+    staff_number: Option<String>,
     name: String,
     license_number: String,
     contact: String,
+    assigned_matatu: Option<u64>,
+    principal: Option<Principal>,
+    working_days: Option<Vec<u8>>,
+    status: Option<String>,
+    archived_at: Option<u64>,
+    version: Option<u64>,
 }
 
+// Matatu as stored by earlier versions, fields added since are optional so
+// every older layout decodes into it
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct RecordExpensePayload {
+struct LegacyMatatu {
+    id: u64,
     sacco_id: u64,
-    category: String,
-    amount: f64,
-    description: String,
-}
-
-// LocationUpdatePayload
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct LocationUpdatePayload {
-    matatu_id: u64,
-    latitude: f64,
-    longitude: f64,
-    speed: f64,
+    fleet_number: Option<String>,
+    plate_number: String,
+    capacity: u32,
+    route: String,
+    status: String,
+    archived_at: Option<u64>,
+    version: Option<u64>,
 }
 
+// Maintenance record as stored by earlier versions
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct RecordRevenuePayload {
-    sacco_id: u64,
+struct LegacyMaintenance {
+    id: u64,
     matatu_id: u64,
-    amount: f64,
+    date: u64,
+    downtime_hours: Option<u32>,
     description: String,
+    cost: f64,
+    status: String,
 }
 
+// How long raw pings are kept before history is compressed
 #[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct StartTripPayload {
-    matatu_id: u64,
-    driver_id: u64,
-    route: String,
+struct LocationRetention {
+    raw_window: u64, // in nanoseconds
+    tolerance: f64,  // Douglas-Peucker tolerance in meters
+    updated_at: u64,
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct EndTripPayload {
-    trip_id: u64,
-    passengers: u32,
-    revenue: f64,
+impl Default for LocationRetention {
+    fn default() -> Self {
+        LocationRetention {
+            raw_window: DEFAULT_RAW_WINDOW,
+            tolerance: DEFAULT_COMPRESSION_TOLERANCE,
+            updated_at: 0,
+        }
+    }
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct RecordMaintenancePayload {
-    matatu_id: u64,
-    description: String,
-    cost: f64,
+// One state-changing call, recorded in the append-only audit trail
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct AuditEntry {
+    id: u64, // sequence number, increases with every entry
+    caller: Option<Principal>,
+    method: String,
+    entity_type: String,
+    entity_id: u64,           // 0 when the call touches many entities
+    before_hash: Option<u64>, // hash of the entity's candid encoding, None when created
+    after_hash: Option<u64>,  // None when removed
+    timestamp: u64,
+    sacco_id: Option<u64>, // SACCO the entity belongs to, None for canister-wide calls and older entries
 }
 
+// How much of the audit trail is kept, older entries are pruned first
 #[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct RecordFuelPayload {
-    matatu_id: u64,
-    liters: f64,
-    cost: f64,
-    odometer_reading: u64,
+struct AuditRetention {
+    max_entries: u64,
+    max_age: u64, // in nanoseconds
+    updated_at: u64,
 }
 
-// Customer Feedback Payload
-#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct CustomerFeedbackPayload {
-    trip_id: u64,
-    rating: u8,
-    cleanliness: u8,
-    punctuality: u8,
-    safety: u8,
-    comment: String,
+impl Default for AuditRetention {
+    fn default() -> Self {
+        AuditRetention {
+            max_entries: DEFAULT_AUDIT_MAX_ENTRIES,
+            max_age: DEFAULT_AUDIT_MAX_AGE,
+            updated_at: 0,
+        }
+    }
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-enum Message {
-    Success(String),
-    Error(String),
-    NotFound(String),
-    InvalidPayload(String),
+// Progress of the data migrations run in batches on a timer after an upgrade
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct UpgradeMigration {
+    step: u32,                 // index of the running step in MIGRATION_STEPS
+    part: u32,                 // sequence the id check has reached
+    cursor: u64,               // key the running step resumes from
+    numbered: Vec<(u64, u64)>, // records numbered so far per SACCO id
+    problems: Vec<String>,     // records stored under another key than their id
+    started_at: u64,
+    finished_at: Option<u64>,
 }
 
-// Implementing Storable for SACCO
-impl Storable for SACCO {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// A page of the audit trail, newest first
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct AuditPage {
+    entries: Vec<AuditEntry>,
+    next_cursor: Option<u64>, // pass back to continue with older entries
 }
 
-impl BoundedStorable for SACCO {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// Telematics device bound to a matatu
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Device {
+    id: u64,
+    sacco_id: u64,
+    matatu_id: u64,
+    principal: Principal, // identity the tracker calls the canister with
+    label: String,        // serial number or IMEI
+    status: String,       // "active", "revoked"
+    last_nonce: u64,      // highest nonce accepted, older ones are replays
+    last_seen: Option<u64>,
+    registered_at: u64,
 }
 
-// Implementing Storable for Matatu
-impl Storable for Matatu {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// When a device was last heard from
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DeviceHeartbeat {
+    device_id: u64,
+    matatu_id: u64,
+    plate_number: String,
+    label: String,
+    last_seen: Option<u64>,
+    offline: bool,
 }
 
-impl BoundedStorable for Matatu {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
-}
+// Geofence struct for depots and restricted zones
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Geofence {
+    id: u64,
+    sacco_id: u64,
+    name: String,
+    zone_type: String, // "depot", "restricted", "speed_zone"
+    latitude: f64,
+    longitude: f64,
+    radius: f64,              // in meters
+    speed_limit: Option<f64>, // in km/h, applies while inside the zone
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct GeofenceEvent {
+    id: u64,
+    matatu_id: u64,
+    trip_id: Option<u64>,
+    zone_type: String, // "stage", "depot", "restricted"
+    zone_id: u64,      // RouteStop ID for stages, Geofence ID otherwise
+    zone_name: String,
+    event_type: String, // "enter", "exit"
+    latitude: f64,
+    longitude: f64,
+    timestamp: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Incident {
+    id: u64,
+    sacco_id: u64,
+    matatu_id: u64,
+    trip_id: Option<u64>,
+    driver_id: Option<u64>,
+    route_id: Option<u64>,
+    incident_type: String, // "route_deviation", "restricted_zone"
+    description: String,
+    latitude: f64,
+    longitude: f64,
+    distance_from_route: f64, // in meters
+    status: String,           // "open", "resolved"
+    created_at: u64,
+    resolved_at: Option<u64>,
+}
+
+// Last known position and geofence state of a matatu
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct TrackingState {
+    matatu_id: u64,
+    latitude: f64,
+    longitude: f64,
+    timestamp: u64,
+    off_route: bool,
+    deviation_incident: Option<u64>,
+    overspeed_pings: u32,
+    speed_violation: Option<u64>,
+    route_id: Option<u64>,
+    segment_index: u32,
+    route_progress: f64,  // distance travelled along the route, in meters
+    average_speed: f64,   // smoothed recent speed, in km/h
+    compacted_until: u64, // history before this timestamp has been compressed
+}
+
+// Key for per-segment statistics of a route
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+struct SegmentKey {
+    route_id: u64,
+    segment_index: u32, // segment between stop `segment_index` and the next one
+}
+
+// Observed travel speed over a route segment
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SegmentStats {
+    route_id: u64,
+    segment_index: u32,
+    average_speed: f64, // in km/h
+    samples: u32,
+    updated_at: u64,
+}
+
+// Key for traffic statistics of a route in one hour of the week
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+struct TrafficKey {
+    route_id: u64,
+    day_of_week: u8, // 0-6 representing Sunday-Saturday
+    hour: u8,
+}
+
+// Observed travel on a route in one hour of the week
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct TrafficStats {
+    route_id: u64,
+    day_of_week: u8,
+    hour: u8,
+    trips: u32,
+    average_duration: f64, // minutes, of trips started in this hour
+    segment_samples: u32,
+    average_segment_speed: f64, // in km/h, from location pings
+    updated_at: u64,
+}
+
+// Key for passenger demand of a route in one hour of the week
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+struct DemandKey {
+    route_id: u64,
+    day_of_week: u8, // 0-6 representing Sunday-Saturday
+    hour: u8,
+}
+
+// Passengers boarding a route in one hour of the week, one observation per service day.
+// Averages are scaled back to an ordinary day using the demand adjustments.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DemandStats {
+    route_id: u64,
+    day_of_week: u8,
+    hour: u8,
+    days: u32, // service days observed, capped at a year of weeks
+    average_passengers: f64,
+    smoothed_passengers: f64, // exponentially smoothed, follows recent changes faster
+    current_day: u64,         // service day still being counted
+    current_passengers: u32,
+    updated_at: u64,
+}
+
+// Holiday or school calendar period when demand differs from an ordinary day
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DemandAdjustment {
+    id: u64,
+    name: String,
+    kind: String,          // "holiday", "school_term", "school_holiday"
+    route_id: Option<u64>, // None applies to every route
+    start_date: u64,
+    end_date: u64,
+    factor: f64, // multiplier on ordinary demand, e.g. 0.6 on a public holiday
+    created_at: u64,
+}
+
+// Expected passengers boarding a route in one hour of a day
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DemandForecast {
+    route_id: u64,
+    start_time: u64,
+    end_time: u64,
+    days: u32, // service days the forecast is based on
+    seasonal_average: f64,
+    smoothed: f64,
+    adjustment: f64, // holiday and school calendar multiplier for the day
+    passengers: u32,
+}
+
+// Demand on a route in a time window and the service recommended for it.
+// Recommendations can be edited and passed to the automated schedule.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DemandWindow {
+    route_id: u64,
+    start_time: u64,
+    end_time: u64,
+    passengers: u32,
+    departures: u32,
+    vehicles: u32, // matatus needed when each runs departures back to back
+    basis: String, // "forecast", "peak_hours"
+}
+
+// Speed policy for a SACCO
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SpeedPolicy {
+    sacco_id: u64,
+    speed_limit: f64,     // in km/h, never above the PSV limit
+    sustained_pings: u32, // consecutive pings above the limit before a violation is raised
+    updated_at: u64,
+}
+
+// Hours-of-service limits for a SACCO's drivers, all in minutes
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct HoursOfServicePolicy {
+    sacco_id: u64,
+    max_continuous_driving: u32,
+    min_break: u32,          // a shorter stop doesn't interrupt continuous driving
+    max_daily_driving: u32,  // in any 24 hours
+    max_weekly_driving: u32, // in any 7 days
+    min_daily_rest: u32,
+    updated_at: u64,
+}
+
+// A driver's recent driving time, in minutes
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DrivingHours {
+    driver_id: u64,
+    continuous_driving: u32,
+    last_24_hours: u32,
+    last_7_days: u32,
+    resting_for: Option<u32>, // time since the last trip ended, none while driving
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SpeedViolation {
+    id: u64,
+    sacco_id: u64,
+    matatu_id: u64,
+    driver_id: Option<u64>,
+    trip_id: Option<u64>,
+    zone_id: Option<u64>,   // Geofence ID when a zone limit applied
+    violation_type: String, // "overspeed", "speed_governor"
+    speed_limit: f64,       // in km/h
+    max_speed: f64,         // in km/h
+    latitude: f64,
+    longitude: f64,
+    started_at: u64,
+    ended_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct FinancialReport {
+    id: u64,
+    sacco_id: u64,
+    period_start: u64,
+    period_end: u64,
+    total_revenue: f64,
+    total_expenses: f64,
+    expense_breakdown: Vec<ExpenseCategory>,
+    revenue_breakdown: Vec<RevenueSource>,
+    profit_margin: f64,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ExpenseCategory {
+    category: String,
+    amount: f64,
+    percentage: f64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RevenueSource {
+    source: String,
+    amount: f64,
+    percentage: f64,
+}
+
+// One chunk of an export, `continuation` is passed back to get the next one
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ExportChunk {
+    dataset: String,
+    format: String,
+    data: String, // CSV rows, with the header in the first chunk, or a JSON array
+    rows: u32,
+    continuation: Option<String>,
+}
+
+// Outcome of a bulk import
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ImportReport {
+    entity: String,
+    mode: String,
+    rows: u32,
+    imported: u32,
+    ids: Vec<u64>, // ids of the records created, in row order
+    errors: Vec<ImportError>,
+}
+
+// Why a row of an import was rejected, rows are numbered from 1 not counting a CSV header
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ImportError {
+    row: u32,
+    message: String,
+}
+
+// Payload structs
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CreateSACCOPayload {
+    code: Option<String>, // derived from the name when not given
+    name: String,
+    location: String,
+    contact: String,
+    email: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RegisterMatatuPayload {
+    sacco_id: u64,
+    plate_number: String,
+    capacity: u32,
+    route: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RegisterDriverPayload {
+    sacco_id: u64,
+    name: String,
+    license_number: String,
+    contact: String,
+    principal: Option<Principal>,
+}
+
+// Partial updates, fields left out are unchanged. `version` is the version the
+// caller last read, the update is refused if the record changed since.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct UpdateSACCOPayload {
+    sacco_id: u64,
+    version: u64,
+    name: Option<String>,
+    location: Option<String>,
+    contact: Option<String>,
+    email: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct UpdateMatatuPayload {
+    matatu_id: u64,
+    version: u64,
+    plate_number: Option<String>,
+    capacity: Option<u32>,
+    route: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct UpdateDriverPayload {
+    driver_id: u64,
+    version: u64,
+    name: Option<String>,
+    license_number: Option<String>,
+    contact: Option<String>,
+    principal: Option<Principal>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RecordExpensePayload {
+    sacco_id: u64,
+    category: String,
+    amount: f64,
+    description: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RouteStopPayload {
+    name: String,
+    latitude: f64,
+    longitude: f64,
+    radius: f64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CreateRoutePayload {
+    sacco_id: u64,
+    name: String,
+    start_point: String,
+    end_point: String,
+    estimated_time: u32,
+    peak_hours: Vec<TimeWindow>,
+    average_passengers: u32,
+    price: f64,
+    corridor_buffer: f64,
+    stops: Vec<RouteStopPayload>, // ordered from start_point to end_point
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CreateGeofencePayload {
+    sacco_id: u64,
+    name: String,
+    zone_type: String,
+    latitude: f64,
+    longitude: f64,
+    radius: f64,
+    speed_limit: Option<f64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct LocationRetentionPayload {
+    raw_window: u64,
+    tolerance: f64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct AuditRetentionPayload {
+    max_entries: u64,
+    max_age: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct AuditQuery {
+    sacco_id: Option<u64>, // required unless the caller is a controller
+    entity_type: Option<String>,
+    entity_id: Option<u64>,
+    caller: Option<Principal>,
+    start_time: Option<u64>,
+    end_time: Option<u64>,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SpeedPolicyPayload {
+    sacco_id: u64,
+    speed_limit: f64,
+    sustained_pings: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct HoursOfServicePayload {
+    sacco_id: u64,
+    max_continuous_driving: u32,
+    min_break: u32,
+    max_daily_driving: u32,
+    max_weekly_driving: u32,
+    min_daily_rest: u32,
+}
+
+// LocationUpdatePayload
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct LocationUpdatePayload {
+    matatu_id: u64,
+    latitude: f64,
+    longitude: f64,
+    speed: f64,
+    timestamp: Option<u64>, // device time in nanoseconds, defaults to the time of the call
+    nonce: Option<u64>,     // must increase with every ping from the same sender
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RegisterDevicePayload {
+    matatu_id: u64,
+    principal: Principal,
+    label: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RejectedLocation {
+    index: u32, // position in the submitted batch
+    reason: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct LocationBatchResult {
+    accepted: u32,
+    duplicates: u32,
+    rejected: Vec<RejectedLocation>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RecordRevenuePayload {
+    sacco_id: u64,
+    matatu_id: u64,
+    amount: f64,
+    description: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StartTripPayload {
+    matatu_id: u64,
+    driver_id: u64,
+    route: String,
+    schedule_id: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct IssueTicketPayload {
+    trip_id: u64,
+    boarding_stop_id: Option<u64>,
+    alighting_stop_id: Option<u64>,
+    fare: f64, // only used when the trip's route isn't known, otherwise the quoted fare is charged
+    category: Option<String>, // discount category of the passenger
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct FareRulePayload {
+    route_id: u64,
+    base_fare: f64,
+    stage_fares: Vec<StageFare>,
+    peak_surcharge: f64,
+    overrides: Vec<FareOverride>,
+    discounts: Vec<FareDiscount>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct FareQuotePayload {
+    route_id: u64,
+    boarding_stop_id: Option<u64>,
+    alighting_stop_id: Option<u64>,
+    category: Option<String>,
+    time: Option<u64>, // defaults to now
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ExportPayload {
+    sacco_id: u64,
+    dataset: String, // "trips", "revenues", "expenses", "fuel", "maintenance"
+    format: String,  // "csv", "json"
+    start_time: u64,
+    end_time: u64,
+    continuation: Option<String>, // from the previous chunk, None for the first
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ImportPayload {
+    entity: String, // "saccos", "matatus", "drivers", "routes", "trips"
+    format: String, // "csv", "json"
+    data: String,   // CSV with a header row, or a JSON array of payload records
+    mode: String,   // "all_or_nothing", "best_effort"
+}
+
+// A trip that ran before the SACCO was onboarded
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct HistoricalTripPayload {
+    matatu_id: u64,
+    driver_id: u64,
+    route: String,
+    start_time: u64,
+    end_time: u64,
+    passengers: u32,
+    revenue: f64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EndTripPayload {
+    trip_id: u64,
+    passengers: u32,
+    revenue: f64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RecordMaintenancePayload {
+    matatu_id: u64,
+    date: u64,
+    downtime_hours: u32,
+    description: String,
+    cost: f64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DriverLeavePayload {
+    driver_id: u64,
+    start_date: u64,
+    end_date: u64,
+    leave_type: String,
+    reason: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DemandAdjustmentPayload {
+    name: String,
+    kind: String,
+    route_id: Option<u64>,
+    start_date: u64,
+    end_date: u64,
+    factor: f64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RecordFuelPayload {
+    matatu_id: u64,
+    liters: f64,
+    cost: f64,
+    odometer_reading: u64,
+}
+
+// Customer Feedback Payload
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CustomerFeedbackPayload {
+    trip_id: u64,
+    rating: u8,
+    cleanliness: u8,
+    punctuality: u8,
+    safety: u8,
+    comment: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum Message {
+    Success(String),
+    Error(String),
+    NotFound(String),
+    InvalidPayload(String),
+}
+
+// Implementing Storable for SACCO
+impl Storable for SACCO {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .unwrap_or_else(|_| Decode!(bytes.as_ref(), LegacySACCO).unwrap().into())
+    }
+}
+
+// SACCOs stored before deactivation are active. Those stored before codes,
+// and their matatus and drivers, are numbered after the upgrade, see
+// assign_record_numbers.
+impl From<LegacySACCO> for SACCO {
+    fn from(sacco: LegacySACCO) -> Self {
+        SACCO {
+            id: sacco.id,
+            code: sacco.code.unwrap_or_default(),
+            name: sacco.name,
+            location: sacco.location,
+            contact: sacco.contact,
+            email: sacco.email,
+            created_at: sacco.created_at,
+            status: sacco.status.unwrap_or_else(|| "active".to_string()),
+            archived_at: sacco.archived_at,
+            version: sacco.version.unwrap_or_default(),
+        }
+    }
+}
+
+impl BoundedStorable for SACCO {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for Matatu
+impl Storable for Matatu {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .unwrap_or_else(|_| Decode!(bytes.as_ref(), LegacyMatatu).unwrap().into())
+    }
+}
+
+// Matatus stored before versioning start at version 0
+impl From<LegacyMatatu> for Matatu {
+    fn from(matatu: LegacyMatatu) -> Self {
+        Matatu {
+            id: matatu.id,
+            sacco_id: matatu.sacco_id,
+            fleet_number: matatu.fleet_number.unwrap_or_default(),
+            plate_number: matatu.plate_number,
+            capacity: matatu.capacity,
+            route: matatu.route,
+            status: matatu.status,
+            archived_at: matatu.archived_at,
+            version: matatu.version.unwrap_or_default(),
+        }
+    }
+}
+
+impl BoundedStorable for Matatu {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for Driver
+impl Storable for Driver {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .unwrap_or_else(|_| Decode!(bytes.as_ref(), LegacyDriver).unwrap().into())
+    }
+}
+
+// Drivers stored without working days are expected every day, and those
+// stored before deactivation are active
+impl From<LegacyDriver> for Driver {
+    fn from(driver: LegacyDriver) -> Self {
+        Driver {
+            id: driver.id,
+            sacco_id: driver.sacco_id,
+            staff_number: driver.staff_number.unwrap_or_default(),
+            name: driver.name,
+            license_number: driver.license_number,
+            contact: driver.contact,
+            assigned_matatu: driver.assigned_matatu,
+            principal: driver.principal,
+            working_days: driver.working_days.unwrap_or_default(),
+            status: driver.status.unwrap_or_else(|| "active".to_string()),
+            archived_at: driver.archived_at,
+            version: driver.version.unwrap_or_default(),
+        }
+    }
+}
+
+impl BoundedStorable for Driver {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for Expense
+impl Storable for Expense {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Expense {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for Revenue
+impl Storable for Revenue {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Revenue {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for Trip
+impl Storable for Trip {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Trip {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for Maintenance
+impl Storable for Maintenance {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .unwrap_or_else(|_| Decode!(bytes.as_ref(), LegacyMaintenance).unwrap().into())
+    }
+}
+
+// Maintenance recorded without a downtime keeps the matatu off the road for the day
+impl From<LegacyMaintenance> for Maintenance {
+    fn from(record: LegacyMaintenance) -> Self {
+        Maintenance {
+            id: record.id,
+            matatu_id: record.matatu_id,
+            date: record.date,
+            downtime_hours: record.downtime_hours.unwrap_or(LEGACY_MAINTENANCE_DOWNTIME),
+            description: record.description,
+            cost: record.cost,
+            status: record.status,
+        }
+    }
+}
+
+impl BoundedStorable for Maintenance {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for DriverLeave
+impl Storable for DriverLeave {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DriverLeave {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for Shift
+impl Storable for Shift {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Shift {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for DriverPerformance
+impl Storable for DriverPerformance {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DriverPerformance {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for FuelConsumption
+impl Storable for FuelConsumption {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for FuelConsumption {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for Route
+impl Storable for Route {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .unwrap_or_else(|_| Decode!(bytes.as_ref(), LegacyRoute).unwrap().into())
+    }
+}
+
+// Routes written before the corridor buffer use the default one, and those
+// written before deactivation are active. Routes from before they belonged to
+// a SACCO get one after the upgrade, see assign_route_saccos.
+impl From<LegacyRoute> for Route {
+    fn from(route: LegacyRoute) -> Self {
+        Route {
+            id: route.id,
+            sacco_id: route.sacco_id.unwrap_or_default(),
+            name: route.name,
+            start_point: route.start_point,
+            end_point: route.end_point,
+            distance: route.distance,
+            estimated_time: route.estimated_time,
+            peak_hours: route.peak_hours,
+            traffic_patterns: route.traffic_patterns,
+            average_passengers: route.average_passengers,
+            price: route.price,
+            corridor_buffer: route.corridor_buffer.unwrap_or(DEFAULT_CORRIDOR_BUFFER),
+            status: route.status.unwrap_or_else(|| "active".to_string()),
+            archived_at: route.archived_at,
+        }
+    }
+}
+
+impl BoundedStorable for Route {
+    const MAX_SIZE: u32 = ROUTE_MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for TimeWindow
+impl Storable for TimeWindow {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TimeWindow {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for TrafficPattern
+impl Storable for TrafficPattern {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TrafficPattern {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for CustomerFeedback
+impl Storable for CustomerFeedback {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CustomerFeedback {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for Schedule
+impl Storable for Schedule {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Schedule {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for LocationUpdate
+impl Storable for LocationUpdate {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LocationUpdate {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for FinancialReport
+impl Storable for FinancialReport {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for FinancialReport {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for ExpenseCategory
+impl Storable for ExpenseCategory {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ExpenseCategory {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for RevenueSource
+impl Storable for RevenueSource {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RevenueSource {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for RouteStop
+impl Storable for RouteStop {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RouteStop {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for Geofence
+impl Storable for Geofence {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Geofence {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for GeofenceEvent
+impl Storable for GeofenceEvent {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for GeofenceEvent {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for Incident
+impl Storable for Incident {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Incident {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for TrackingState
+impl Storable for TrackingState {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TrackingState {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for SpeedPolicy
+impl Storable for SpeedPolicy {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SpeedPolicy {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for HoursOfServicePolicy
+impl Storable for HoursOfServicePolicy {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for HoursOfServicePolicy {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for SpeedViolation
+impl Storable for SpeedViolation {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SpeedViolation {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for SegmentKey
+impl Storable for SegmentKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SegmentKey {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for TrafficKey
+impl Storable for TrafficKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TrafficKey {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for TrafficStats
+impl Storable for TrafficStats {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TrafficStats {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for DemandKey
+impl Storable for DemandKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DemandKey {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for DemandStats
+impl Storable for DemandStats {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DemandStats {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for DemandAdjustment
+impl Storable for DemandAdjustment {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DemandAdjustment {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for FareRule
+impl Storable for FareRule {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for FareRule {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for AuditEntry
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AuditEntry {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for AuditRetention
+impl Storable for AuditRetention {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AuditRetention {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for UpgradeMigration
+impl Storable for UpgradeMigration {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing Storable for MemberKey
+impl Storable for MemberKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for MemberKey {
+    const MAX_SIZE: u32 = 96;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for SaccoMember
+impl Storable for SaccoMember {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SaccoMember {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for ApiToken
+impl Storable for ApiToken {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .unwrap_or_else(|_| Decode!(bytes.as_ref(), LegacyApiToken).unwrap().into())
+    }
+}
+
+// Tokens stored with their secret are hashed on decode, and stored again
+// hashed after the upgrade, see hash_api_token_secrets
+impl From<LegacyApiToken> for ApiToken {
+    fn from(token: LegacyApiToken) -> Self {
+        ApiToken {
+            id: token.id,
+            principal: token.principal,
+            secret_hash: secret_hash(&token.secret),
+            created_at: token.created_at,
+            expires_at: token.expires_at,
+            status: token.status,
+        }
+    }
+}
+
+impl BoundedStorable for ApiToken {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for SegmentStats
+impl Storable for SegmentStats {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SegmentStats {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for Ticket
+impl Storable for Ticket {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Ticket {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for LocationKey
+impl Storable for LocationKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LocationKey {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for LegacyLocationUpdate
+impl Storable for LegacyLocationUpdate {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LegacyLocationUpdate {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for LocationRetention
+impl Storable for LocationRetention {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LocationRetention {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for Device
+impl Storable for Device {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Device {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Kinds of record, each numbered by its own id sequence
+#[derive(Clone, Copy, Debug)]
+enum Sequence {
+    Sacco = 1,
+    Matatu = 2,
+    Driver = 3,
+    Trip = 4,
+    Route = 5,
+    RouteStop = 6,
+    Schedule = 7,
+    Ticket = 8,
+    Shift = 9,
+    DriverLeave = 10,
+    Maintenance = 11,
+    CustomerFeedback = 12,
+    DriverPerformance = 13,
+    DemandAdjustment = 14,
+    Device = 15,
+    Geofence = 16,
+    GeofenceEvent = 17,
+    Incident = 18,
+    SpeedViolation = 19,
+    FinancialReport = 20,
+    ApiToken = 21,
+}
+
+impl Sequence {
+    const ALL: [Sequence; 21] = [
+        Sequence::Sacco,
+        Sequence::Matatu,
+        Sequence::Driver,
+        Sequence::Trip,
+        Sequence::Route,
+        Sequence::RouteStop,
+        Sequence::Schedule,
+        Sequence::Ticket,
+        Sequence::Shift,
+        Sequence::DriverLeave,
+        Sequence::Maintenance,
+        Sequence::CustomerFeedback,
+        Sequence::DriverPerformance,
+        Sequence::DemandAdjustment,
+        Sequence::Device,
+        Sequence::Geofence,
+        Sequence::GeofenceEvent,
+        Sequence::Incident,
+        Sequence::SpeedViolation,
+        Sequence::FinancialReport,
+        Sequence::ApiToken,
+    ];
+}
+
+// Memory management
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    // Shared counter every record was numbered from before each kind got its own
+    // sequence, only read to seed new sequences
+    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static SACCOS: RefCell<StableBTreeMap<u64, SACCO, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+        ));
+
+    static MATATUS: RefCell<StableBTreeMap<u64, Matatu, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+        ));
+
+    static DRIVERS: RefCell<StableBTreeMap<u64, Driver, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+        ));
+
+    static EXPENSES: RefCell<StableBTreeMap<u64, Expense, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+        ));
+
+    static REVENUES: RefCell<StableBTreeMap<u64, Revenue, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+        ));
+
+    static TRIPS: RefCell<StableBTreeMap<u64, Trip, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+        ));
+
+    static MAINTENANCE_RECORDS: RefCell<StableBTreeMap<u64, Maintenance, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+        ));
+
+    static DRIVER_PERFORMANCE: RefCell<StableBTreeMap<u64, DriverPerformance, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+        ));
+
+    static FUEL_RECORDS: RefCell<StableBTreeMap<u64, FuelConsumption, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+        ));
+
+    static ROUTES: RefCell<StableBTreeMap<u64, Route, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+        ));
+
+    static CUSTOMER_FEEDBACK: RefCell<StableBTreeMap<u64, CustomerFeedback, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+        ));
+
+    static SCHEDULES: RefCell<StableBTreeMap<u64, Schedule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+        ));
+
+    // Only read to migrate pings recorded before LOCATION_UPDATES was keyed by matatu and time
+    static LEGACY_LOCATION_UPDATES: RefCell<StableBTreeMap<u64, LegacyLocationUpdate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+        ));
+
+    static FINANCIAL_REPORTS: RefCell<StableBTreeMap<u64, FinancialReport, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+        ));
+
+    static EXPENSE_CATEGORIES: RefCell<StableBTreeMap<u64, ExpenseCategory, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+        ));
+
+    static REVENUE_SOURCES: RefCell<StableBTreeMap<u64, RevenueSource, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+        ));
+
+    // MemoryIds 26 and 27 held time window and traffic pattern maps that were never
+    // written to. Patterns now live on each route, learned from TRAFFIC_STATS.
+
+    static ROUTE_STOPS: RefCell<StableBTreeMap<u64, RouteStop, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+        ));
+
+    static GEOFENCES: RefCell<StableBTreeMap<u64, Geofence, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+        ));
+
+    static GEOFENCE_EVENTS: RefCell<StableBTreeMap<u64, GeofenceEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+        ));
+
+    static INCIDENTS: RefCell<StableBTreeMap<u64, Incident, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+        ));
+
+    static TRACKING_STATES: RefCell<StableBTreeMap<u64, TrackingState, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+        ));
+
+    static SPEED_POLICIES: RefCell<StableBTreeMap<u64, SpeedPolicy, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+        ));
+
+    static SPEED_VIOLATIONS: RefCell<StableBTreeMap<u64, SpeedViolation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+        ));
+
+    static SEGMENT_STATS: RefCell<StableBTreeMap<SegmentKey, SegmentStats, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+        ));
+
+    static TICKETS: RefCell<StableBTreeMap<u64, Ticket, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+        ));
+
+    static LOCATION_UPDATES: RefCell<StableBTreeMap<LocationKey, LocationUpdate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+        ));
+
+    static LOCATION_RETENTION: RefCell<Cell<LocationRetention, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38))),
+            LocationRetention::default(),
+        )
+        .expect("Cannot create the location retention policy")
+    );
+
+    static DEVICES: RefCell<StableBTreeMap<u64, Device, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))
+        ));
+
+    // Highest ping nonce accepted from each driver's own phone
+    static DRIVER_PING_NONCES: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+        ));
+
+    static DRIVER_LEAVE: RefCell<StableBTreeMap<u64, DriverLeave, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))
+        ));
+
+    static SHIFTS: RefCell<StableBTreeMap<u64, Shift, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+        ));
+
+    static HOURS_POLICIES: RefCell<StableBTreeMap<u64, HoursOfServicePolicy, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))
+        ));
+
+    static TRAFFIC_STATS: RefCell<StableBTreeMap<TrafficKey, TrafficStats, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+        ));
+
+    static DEMAND_STATS: RefCell<StableBTreeMap<DemandKey, DemandStats, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45)))
+        ));
+
+    static DEMAND_ADJUSTMENTS: RefCell<StableBTreeMap<u64, DemandAdjustment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
+        ));
+
+    static FARE_RULES: RefCell<StableBTreeMap<u64, FareRule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47)))
+        ));
+
+    static AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))
+        ));
+
+    static AUDIT_SEQUENCE: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49))), 0)
+            .expect("Cannot create the audit sequence")
+    );
+
+    static AUDIT_RETENTION: RefCell<Cell<AuditRetention, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50))),
+            AuditRetention::default(),
+        )
+        .expect("Cannot create the audit retention policy")
+    );
+
+    static SACCO_MEMBERS: RefCell<StableBTreeMap<MemberKey, SaccoMember, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51)))
+        ));
+
+    // Last id handed out by each sequence
+    static ID_SEQUENCES: RefCell<StableBTreeMap<u8, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52)))
+        ));
+
+    static API_TOKENS: RefCell<StableBTreeMap<u64, ApiToken, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53)))
+        ));
+
+    static UPGRADE_MIGRATION: RefCell<Cell<UpgradeMigration, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54))),
+            UpgradeMigration::default(),
+        )
+        .expect("Cannot create the upgrade migration state")
+    );
+
+    // Holder of the API token on the HTTP request being served
+    static HTTP_CALLER: RefCell<Option<Principal>> = const { RefCell::new(None) };
+
+}
+
+// Functions
+
+// Create SACCO
+#[ic_cdk::update]
+fn create_sacco(payload: CreateSACCOPayload) -> Result<SACCO, Message> {
+    let (contact, code) = check_new_sacco(&payload)?;
+    // Whoever creates the SACCO becomes its first admin
+    let caller = caller();
+    let code = code.unwrap_or_else(|| derive_sacco_code(&payload.name));
+
+    let sacco_id = next_id(Sequence::Sacco);
+
+    let sacco = SACCO {
+        id: sacco_id,
+        code,
+        name: payload.name,
+        location: payload.location,
+        contact,
+        email: payload.email,
+        created_at: time(),
+        status: "active".to_string(),
+        archived_at: None,
+        version: 1,
+    };
+
+    SACCOS.with(|saccos| {
+        saccos.borrow_mut().insert(sacco_id, sacco.clone());
+    });
+    let member = SaccoMember {
+        sacco_id,
+        principal: caller,
+        role: "admin".to_string(),
+        added_at: time(),
+    };
+    SACCO_MEMBERS.with(|members| {
+        members.borrow_mut().insert(
+            MemberKey {
+                sacco_id,
+                principal: caller,
+            },
+            member,
+        )
+    });
+    audit("create_sacco", "sacco", sacco_id, None, Some(&sacco));
+
+    Ok(sacco)
+}
+
+// Helper function to check a new SACCO without creating it, giving its
+// normalized contact and code when one was chosen
+fn check_new_sacco(payload: &CreateSACCOPayload) -> Result<(String, Option<String>), Message> {
+    if payload.name.is_empty() || payload.contact.is_empty() || payload.email.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Missing required fields".to_string(),
+        ));
+    }
+
+    check_text("Name", &payload.name, MAX_TEXT)?;
+    check_text("Location", &payload.location, MAX_TEXT)?;
+    check_text("Email", &payload.email, MAX_TEXT)?;
+    let contact = normalize_phone(&payload.contact)?;
+    validate_email(&payload.email)?;
+
+    // Whoever creates the SACCO becomes its first admin, so it needs a caller
+    if caller() == Principal::anonymous() {
+        return Err(Message::Error(
+            "Anonymous callers cannot create a SACCO".to_string(),
+        ));
+    }
+
+    let code = match &payload.code {
+        Some(code) => {
+            let code = code.trim().to_uppercase();
+            if code.len() < 2
+                || code.len() > MAX_SACCO_CODE
+                || !code.chars().all(|c| c.is_ascii_alphanumeric())
+            {
+                return Err(Message::InvalidPayload(format!(
+                    "Code must be 2 to {} letters or digits",
+                    MAX_SACCO_CODE
+                )));
+            }
+            if sacco_code_taken(&code) {
+                return Err(Message::Error("SACCO code is already taken".to_string()));
+            }
+            Some(code)
+        }
+        None => None,
+    };
+
+    Ok((contact, code))
+}
+
+// SACCO Members
+#[ic_cdk::update]
+fn add_sacco_member(
+    sacco_id: u64,
+    principal: Principal,
+    role: String,
+) -> Result<SaccoMember, Message> {
+    authorize_sacco_admin(sacco_id)?;
+    add_member(sacco_id, principal, role)
+}
+
+// Gives a SACCO without an admin, such as one created before SACCOs had
+// members, its first admin. Controllers only.
+#[ic_cdk::update]
+fn assign_sacco_admin(sacco_id: u64, principal: Principal) -> Result<SaccoMember, Message> {
+    require_controller()?;
+    if !SACCOS.with(|saccos| saccos.borrow().contains_key(&sacco_id)) {
+        return Err(Message::NotFound("SACCO not found".to_string()));
+    }
+    if sacco_members(sacco_id).iter().any(|m| m.role == "admin") {
+        return Err(Message::Error(
+            "SACCO already has an admin, who can add others".to_string(),
+        ));
+    }
+
+    add_member(sacco_id, principal, "admin".to_string())
+}
+
+// Helper function to add or change a SACCO member once the caller is allowed to
+fn add_member(sacco_id: u64, principal: Principal, role: String) -> Result<SaccoMember, Message> {
+    if !["admin", "staff"].contains(&role.as_str()) {
+        return Err(Message::InvalidPayload(
+            "Role must be admin or staff".to_string(),
+        ));
+    }
+
+    if principal == Principal::anonymous() {
+        return Err(Message::InvalidPayload(
+            "The anonymous principal cannot be a member".to_string(),
+        ));
+    }
+
+    let member = SaccoMember {
+        sacco_id,
+        principal,
+        role,
+        added_at: time(),
+    };
+
+    let before = SACCO_MEMBERS.with(|members| {
+        members.borrow_mut().insert(
+            MemberKey {
+                sacco_id,
+                principal,
+            },
+            member.clone(),
+        )
+    });
+    audit(
+        "add_sacco_member",
+        "sacco_member",
+        sacco_id,
+        before.as_ref(),
+        Some(&member),
+    );
+
+    Ok(member)
+}
+
+#[ic_cdk::update]
+fn remove_sacco_member(sacco_id: u64, principal: Principal) -> Result<SaccoMember, Message> {
+    authorize_sacco_admin(sacco_id)?;
+
+    let key = MemberKey {
+        sacco_id,
+        principal,
+    };
+    let member = SACCO_MEMBERS
+        .with(|members| members.borrow().get(&key))
+        .ok_or(Message::NotFound("Member not found".to_string()))?;
+
+    // Keep at least one admin so the SACCO can still be managed
+    let admins = sacco_members(sacco_id)
+        .iter()
+        .filter(|m| m.role == "admin")
+        .count();
+    if member.role == "admin" && admins == 1 {
+        return Err(Message::Error(
+            "Cannot remove the SACCO's last admin".to_string(),
+        ));
+    }
+
+    SACCO_MEMBERS.with(|members| members.borrow_mut().remove(&key));
+    audit(
+        "remove_sacco_member",
+        "sacco_member",
+        sacco_id,
+        Some(&member),
+        None,
+    );
+
+    Ok(member)
+}
+
+#[ic_cdk::query]
+fn get_sacco_members(sacco_id: u64) -> Result<Vec<SaccoMember>, Message> {
+    authorize_sacco(sacco_id)?;
+
+    Ok(sacco_members(sacco_id))
+}
+
+// Register Matatu
+#[ic_cdk::update]
+fn register_matatu(payload: RegisterMatatuPayload) -> Result<Matatu, Message> {
+    let (sacco, plate_number) = check_new_matatu(&payload)?;
+
+    let matatu_id = next_id(Sequence::Matatu);
+    // Matatus are never deleted, so numbering after the whole fleet keeps numbers unique
+    let fleet_size = MATATUS.with(|matatus| {
+        matatus
+            .borrow()
+            .iter()
+            .filter(|(_, m)| m.sacco_id == sacco.id)
+            .count()
+    });
+
+    let matatu = Matatu {
+        id: matatu_id,
+        sacco_id: payload.sacco_id,
+        fleet_number: format!("{}-{:03}", sacco.code, fleet_size + 1),
+        plate_number,
+        capacity: payload.capacity,
+        route: payload.route,
+        status: "active".to_string(),
+        archived_at: None,
+        version: 1,
+    };
+
+    MATATUS.with(|matatus| {
+        matatus.borrow_mut().insert(matatu_id, matatu.clone());
+    });
+    audit("register_matatu", "matatu", matatu_id, None, Some(&matatu));
+
+    Ok(matatu)
+}
+
+// Helper function to check a new matatu without registering it, giving its
+// SACCO and normalized plate number
+fn check_new_matatu(payload: &RegisterMatatuPayload) -> Result<(SACCO, String), Message> {
+    if payload.plate_number.is_empty() || payload.route.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Missing required fields".to_string(),
+        ));
+    }
+
+    let plate_number = normalize_plate(&payload.plate_number)?;
+    check_plate_available(&plate_number, None)?;
+
+    let sacco = open_sacco(payload.sacco_id)?;
+    check_sacco_route(sacco.id, &payload.route)?;
+
+    Ok((sacco, plate_number))
+}
+
+// Helper function to get a SACCO the caller can add records to
+fn open_sacco(sacco_id: u64) -> Result<SACCO, Message> {
+    let sacco = SACCOS
+        .with(|saccos| saccos.borrow().get(&sacco_id))
+        .ok_or(Message::NotFound("SACCO not found".to_string()))?;
+    authorize_sacco(sacco.id)?;
+    if sacco.status == "archived" {
+        return Err(Message::Error("SACCO is archived".to_string()));
+    }
+
+    Ok(sacco)
+}
+
+// Register Driver
+#[ic_cdk::update]
+fn register_driver(payload: RegisterDriverPayload) -> Result<Driver, Message> {
+    let (sacco, contact) = check_new_driver(&payload)?;
+
+    let driver_id = next_id(Sequence::Driver);
+    let staff_size = DRIVERS.with(|drivers| {
+        drivers
+            .borrow()
+            .iter()
+            .filter(|(_, d)| d.sacco_id == sacco.id)
+            .count()
+    });
+
+    let driver = Driver {
+        id: driver_id,
+        sacco_id: payload.sacco_id,
+        staff_number: format!("{}-D{:03}", sacco.code, staff_size + 1),
+        name: payload.name,
+        license_number: payload.license_number,
+        contact,
+        assigned_matatu: None,
+        principal: payload.principal,
+        working_days: Vec::new(),
+        status: "active".to_string(),
+        archived_at: None,
+        version: 1,
+    };
+
+    DRIVERS.with(|drivers| {
+        drivers.borrow_mut().insert(driver_id, driver.clone());
+    });
+    audit("register_driver", "driver", driver_id, None, Some(&driver));
+
+    Ok(driver)
+}
+
+// Helper function to check a new driver without registering them, giving their
+// SACCO and normalized contact
+fn check_new_driver(payload: &RegisterDriverPayload) -> Result<(SACCO, String), Message> {
+    if payload.name.is_empty() || payload.license_number.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Missing required fields".to_string(),
+        ));
+    }
+
+    check_text("Name", &payload.name, MAX_TEXT)?;
+    check_text("License number", &payload.license_number, MAX_TEXT)?;
+    let contact = normalize_phone(&payload.contact)?;
+    let sacco = open_sacco(payload.sacco_id)?;
+
+    Ok((sacco, contact))
+}
+
+// Profile Updates
+#[ic_cdk::update]
+fn update_sacco(payload: UpdateSACCOPayload) -> Result<SACCO, Message> {
+    let before = SACCOS
+        .with(|saccos| saccos.borrow().get(&payload.sacco_id))
+        .ok_or(Message::NotFound("SACCO not found".to_string()))?;
+    authorize_sacco_admin(before.id)?;
+    check_version("SACCO", before.version, payload.version)?;
+    if before.status == "archived" {
+        return Err(Message::Error("SACCO is archived".to_string()));
+    }
+
+    let mut sacco = before.clone();
+    if let Some(name) = payload.name {
+        if name.is_empty() {
+            return Err(Message::InvalidPayload("Name cannot be empty".to_string()));
+        }
+        check_text("Name", &name, MAX_TEXT)?;
+        sacco.name = name;
+    }
+    if let Some(location) = payload.location {
+        check_text("Location", &location, MAX_TEXT)?;
+        sacco.location = location;
+    }
+    if let Some(contact) = payload.contact {
+        sacco.contact = normalize_phone(&contact)?;
+    }
+    if let Some(email) = payload.email {
+        check_text("Email", &email, MAX_TEXT)?;
+        validate_email(&email)?;
+        sacco.email = email;
+    }
+    sacco.version += 1;
+
+    SACCOS.with(|saccos| saccos.borrow_mut().insert(sacco.id, sacco.clone()));
+    audit(
+        "update_sacco",
+        "sacco",
+        sacco.id,
+        Some(&before),
+        Some(&sacco),
+    );
+
+    Ok(sacco)
+}
+
+#[ic_cdk::update]
+fn update_matatu(payload: UpdateMatatuPayload) -> Result<Matatu, Message> {
+    let before = tenant_matatu(payload.matatu_id)?;
+    check_version("Matatu", before.version, payload.version)?;
+    if before.status == "archived" {
+        return Err(Message::Error("Matatu is archived".to_string()));
+    }
+
+    let mut matatu = before.clone();
+    if let Some(plate_number) = payload.plate_number {
+        let plate_number = normalize_plate(&plate_number)?;
+        check_plate_available(&plate_number, Some(matatu.id))?;
+        matatu.plate_number = plate_number;
+    }
+    if let Some(capacity) = payload.capacity {
+        if capacity == 0 {
+            return Err(Message::InvalidPayload(
+                "Capacity must be at least one seat".to_string(),
+            ));
+        }
+        matatu.capacity = capacity;
+    }
+    if let Some(route) = payload.route {
+        check_sacco_route(matatu.sacco_id, &route)?;
+        // Trips and departures already planned keep the route they were planned on
+        if route != matatu.route {
+            if let Some(reason) = matatu_in_use(matatu.id, time()) {
+                return Err(Message::Error(reason));
+            }
+        }
+        matatu.route = route;
+    }
+    matatu.version += 1;
+
+    MATATUS.with(|matatus| matatus.borrow_mut().insert(matatu.id, matatu.clone()));
+    audit(
+        "update_matatu",
+        "matatu",
+        matatu.id,
+        Some(&before),
+        Some(&matatu),
+    );
+
+    Ok(matatu)
+}
+
+#[ic_cdk::update]
+fn update_driver(payload: UpdateDriverPayload) -> Result<Driver, Message> {
+    let before = tenant_driver(payload.driver_id)?;
+    check_version("Driver", before.version, payload.version)?;
+    if before.status == "archived" {
+        return Err(Message::Error("Driver is archived".to_string()));
+    }
+
+    let mut driver = before.clone();
+    if let Some(name) = payload.name {
+        if name.is_empty() {
+            return Err(Message::InvalidPayload("Name cannot be empty".to_string()));
+        }
+        check_text("Name", &name, MAX_TEXT)?;
+        driver.name = name;
+    }
+    if let Some(license_number) = payload.license_number {
+        if license_number.is_empty() {
+            return Err(Message::InvalidPayload(
+                "License number cannot be empty".to_string(),
+            ));
+        }
+        check_text("License number", &license_number, MAX_TEXT)?;
+        driver.license_number = license_number;
+    }
+    if let Some(contact) = payload.contact {
+        driver.contact = normalize_phone(&contact)?;
+    }
+    if let Some(principal) = payload.principal {
+        let taken = DRIVERS.with(|drivers| {
+            drivers
+                .borrow()
+                .iter()
+                .any(|(id, d)| id != driver.id && d.principal == Some(principal))
+        });
+        if taken {
+            return Err(Message::Error(
+                "Principal is already used by another driver".to_string(),
+            ));
+        }
+        driver.principal = Some(principal);
+    }
+    driver.version += 1;
+
+    DRIVERS.with(|drivers| drivers.borrow_mut().insert(driver.id, driver.clone()));
+    audit(
+        "update_driver",
+        "driver",
+        driver.id,
+        Some(&before),
+        Some(&driver),
+    );
+
+    Ok(driver)
+}
+
+// Assign Driver to Matatu
+#[ic_cdk::update]
+fn assign_driver_to_matatu(driver_id: u64, matatu_id: u64) -> Result<Driver, Message> {
+    let matatu = tenant_matatu(matatu_id)?;
+    if matatu.status == "archived" {
+        return Err(Message::Error("Matatu is archived".to_string()));
+    }
+
+    // Audited once the map is released, the audit looks the driver up
+    let (driver, updated_driver) = DRIVERS.with(|drivers| {
+        let mut drivers_map = drivers.borrow_mut();
+
+        if let Some(driver) = drivers_map.get(&driver_id) {
+            if driver.status == "archived" {
+                return Err(Message::Error("Driver is archived".to_string()));
+            }
+            if driver.sacco_id != matatu.sacco_id {
+                return Err(Message::InvalidPayload(
+                    "Driver and matatu belong to different SACCOs".to_string(),
+                ));
+            }
+
+            let mut updated_driver = driver.clone();
+            updated_driver.assigned_matatu = Some(matatu_id);
+            updated_driver.version += 1;
+            drivers_map.insert(driver_id, updated_driver.clone());
+            Ok((driver, updated_driver))
+        } else {
+            Err(Message::NotFound("Driver not found".to_string()))
+        }
+    })?;
+    audit(
+        "assign_driver_to_matatu",
+        "driver",
+        driver_id,
+        Some(&driver),
+        Some(&updated_driver),
+    );
+
+    Ok(updated_driver)
+}
+
+// Record Driver Leave
+#[ic_cdk::update]
+fn record_driver_leave(payload: DriverLeavePayload) -> Result<DriverLeave, Message> {
+    if payload.end_date <= payload.start_date {
+        return Err(Message::InvalidPayload(
+            "Leave must end after it starts".to_string(),
+        ));
+    }
+
+    if !["leave", "sick", "suspension"].contains(&payload.leave_type.as_str()) {
+        return Err(Message::InvalidPayload(
+            "Leave type must be leave, sick or suspension".to_string(),
+        ));
+    }
+
+    tenant_driver(payload.driver_id)?;
+
+    // Leave needs approval, sick days and suspensions take effect straight away
+    let (status, reviewed_at) = if payload.leave_type == "leave" {
+        ("pending", None)
+    } else {
+        ("approved", Some(time()))
+    };
+
+    let leave = DriverLeave {
+        id: next_id(Sequence::DriverLeave),
+        driver_id: payload.driver_id,
+        start_date: payload.start_date,
+        end_date: payload.end_date,
+        leave_type: payload.leave_type,
+        status: status.to_string(),
+        reason: payload.reason,
+        created_at: time(),
+        reviewed_at,
+    };
+
+    DRIVER_LEAVE.with(|leave_map| leave_map.borrow_mut().insert(leave.id, leave.clone()));
+    audit(
+        "record_driver_leave",
+        "driver_leave",
+        leave.id,
+        None,
+        Some(&leave),
+    );
+
+    Ok(leave)
+}
+
+#[ic_cdk::update]
+fn review_driver_leave(leave_id: u64, approved: bool) -> Result<DriverLeave, Message> {
+    let (before, leave) = DRIVER_LEAVE.with(|leave_map| {
+        let mut leave_map = leave_map.borrow_mut();
+        if let Some(mut leave) = leave_map.get(&leave_id) {
+            // Approval is an admin's call, so drivers can't approve their own leave
+            authorize_sacco_admin(tenant_driver(leave.driver_id)?.sacco_id)?;
+            if leave.status != "pending" {
+                return Err(Message::Error(
+                    "Leave has already been reviewed".to_string(),
+                ));
+            }
+
+            leave.status = if approved { "approved" } else { "rejected" }.to_string();
+            leave.reviewed_at = Some(time());
+            let before = leave_map.insert(leave_id, leave.clone());
+            Ok((before, leave))
+        } else {
+            Err(Message::NotFound("Leave not found".to_string()))
+        }
+    })?;
+    audit(
+        "review_driver_leave",
+        "driver_leave",
+        leave_id,
+        before.as_ref(),
+        Some(&leave),
+    );
+
+    Ok(leave)
+}
+
+#[ic_cdk::update]
+fn set_driver_working_days(driver_id: u64, working_days: Vec<u8>) -> Result<Driver, Message> {
+    if working_days.iter().any(|day| *day > 6) {
+        return Err(Message::InvalidPayload(
+            "Working days must be between 0 (Sunday) and 6 (Saturday)".to_string(),
+        ));
+    }
+
+    authorize_sacco_admin(tenant_driver(driver_id)?.sacco_id)?;
+
+    let (before, driver) = DRIVERS.with(|drivers| {
+        let mut drivers_map = drivers.borrow_mut();
+        if let Some(mut driver) = drivers_map.get(&driver_id) {
+            driver.working_days = working_days;
+            driver.version += 1;
+            let before = drivers_map.insert(driver_id, driver.clone());
+            Ok((before, driver))
+        } else {
+            Err(Message::NotFound("Driver not found".to_string()))
+        }
+    })?;
+    audit(
+        "set_driver_working_days",
+        "driver",
+        driver_id,
+        before.as_ref(),
+        Some(&driver),
+    );
+
+    Ok(driver)
+}
+
+// Deactivation and Archival
+// Inactive records are kept out of service but still listed, archived records
+// are only listed on request and can't be reactivated. Both stay in historic
+// reports and analytics. Only SACCO admins change a record's status.
+#[ic_cdk::update]
+fn set_sacco_active(sacco_id: u64, active: bool) -> Result<SACCO, Message> {
+    let mut sacco = SACCOS
+        .with(|saccos| saccos.borrow().get(&sacco_id))
+        .ok_or(Message::NotFound("SACCO not found".to_string()))?;
+    authorize_sacco_admin(sacco_id)?;
+    if sacco.status == "archived" {
+        return Err(Message::Error("SACCO is archived".to_string()));
+    }
+
+    if !active {
+        let fleet: Vec<u64> = MATATUS.with(|matatus| {
+            matatus
+                .borrow()
+                .iter()
+                .filter(|(_, m)| m.sacco_id == sacco_id)
+                .map(|(id, _)| id)
+                .collect()
+        });
+        if fleet.iter().any(|id| find_ongoing_trip(*id).is_some()) {
+            return Err(Message::Error("SACCO has matatus on the road".to_string()));
+        }
+    }
+
+    sacco.status = if active { "active" } else { "inactive" }.to_string();
+    sacco.archived_at = None;
+    sacco.version += 1;
+    let before = SACCOS.with(|saccos| saccos.borrow_mut().insert(sacco_id, sacco.clone()));
+    audit(
+        "set_sacco_active",
+        "sacco",
+        sacco_id,
+        before.as_ref(),
+        Some(&sacco),
+    );
+
+    Ok(sacco)
+}
+
+// A SACCO can only be archived once its matatus and drivers are archived
+#[ic_cdk::update]
+fn archive_sacco(sacco_id: u64) -> Result<SACCO, Message> {
+    let mut sacco = SACCOS
+        .with(|saccos| saccos.borrow().get(&sacco_id))
+        .ok_or(Message::NotFound("SACCO not found".to_string()))?;
+    authorize_sacco_admin(sacco_id)?;
+    // Archiving again would lose the original archive time
+    if sacco.status == "archived" {
+        return Err(Message::Error("SACCO is already archived".to_string()));
+    }
+
+    let matatus = MATATUS.with(|matatus| {
+        matatus
+            .borrow()
+            .iter()
+            .filter(|(_, m)| m.sacco_id == sacco_id && m.status != "archived")
+            .count()
+    });
+    let drivers = DRIVERS.with(|drivers| {
+        drivers
+            .borrow()
+            .iter()
+            .filter(|(_, d)| d.sacco_id == sacco_id && d.status != "archived")
+            .count()
+    });
+    if matatus + drivers > 0 {
+        return Err(Message::Error(format!(
+            "Archive the SACCO's {} matatus and {} drivers first",
+            matatus, drivers
+        )));
+    }
+
+    sacco.status = "archived".to_string();
+    sacco.archived_at = Some(time());
+    sacco.version += 1;
+    let before = SACCOS.with(|saccos| saccos.borrow_mut().insert(sacco_id, sacco.clone()));
+    audit(
+        "archive_sacco",
+        "sacco",
+        sacco_id,
+        before.as_ref(),
+        Some(&sacco),
+    );
+
+    Ok(sacco)
+}
+
+#[ic_cdk::update]
+fn set_matatu_active(matatu_id: u64, active: bool) -> Result<Matatu, Message> {
+    let mut matatu = MATATUS
+        .with(|matatus| matatus.borrow().get(&matatu_id))
+        .ok_or(Message::NotFound("Matatu not found".to_string()))?;
+    authorize_sacco_admin(matatu.sacco_id)?;
+    if matatu.status == "archived" {
+        return Err(Message::Error("Matatu is archived".to_string()));
+    }
+
+    if !active {
+        if let Some(reason) = matatu_in_use(matatu_id, time()) {
+            return Err(Message::Error(reason));
+        }
+    }
+
+    matatu.status = if active { "active" } else { "inactive" }.to_string();
+    matatu.archived_at = None;
+    matatu.version += 1;
+    let before = MATATUS.with(|matatus| matatus.borrow_mut().insert(matatu_id, matatu.clone()));
+    audit(
+        "set_matatu_active",
+        "matatu",
+        matatu_id,
+        before.as_ref(),
+        Some(&matatu),
+    );
+
+    Ok(matatu)
+}
+
+// Archiving a matatu also unassigns its drivers and revokes its tracker
+#[ic_cdk::update]
+fn archive_matatu(matatu_id: u64) -> Result<Matatu, Message> {
+    let mut matatu = MATATUS
+        .with(|matatus| matatus.borrow().get(&matatu_id))
+        .ok_or(Message::NotFound("Matatu not found".to_string()))?;
+    authorize_sacco_admin(matatu.sacco_id)?;
+    if matatu.status == "archived" {
+        return Err(Message::Error("Matatu is already archived".to_string()));
+    }
+
+    let now = time();
+    if let Some(reason) = matatu_in_use(matatu_id, now) {
+        return Err(Message::Error(reason));
+    }
+
+    let assigned: Vec<Driver> = DRIVERS.with(|drivers| {
+        drivers
+            .borrow()
+            .iter()
+            .filter(|(_, d)| d.assigned_matatu == Some(matatu_id))
+            .map(|(_, d)| d.clone())
+            .collect()
+    });
+    for before in assigned {
+        let mut driver = before.clone();
+        driver.assigned_matatu = None;
+        driver.version += 1;
+        DRIVERS.with(|drivers| drivers.borrow_mut().insert(driver.id, driver.clone()));
+        audit(
+            "archive_matatu",
+            "driver",
+            driver.id,
+            Some(&before),
+            Some(&driver),
+        );
+    }
+    let bound: Vec<Device> = DEVICES.with(|devices| {
+        devices
+            .borrow()
+            .iter()
+            .filter(|(_, d)| d.matatu_id == matatu_id && d.status == "active")
+            .map(|(_, d)| d.clone())
+            .collect()
+    });
+    for before in bound {
+        let mut device = before.clone();
+        device.status = "revoked".to_string();
+        DEVICES.with(|devices| devices.borrow_mut().insert(device.id, device.clone()));
+        audit(
+            "archive_matatu",
+            "device",
+            device.id,
+            Some(&before),
+            Some(&device),
+        );
+    }
+
+    matatu.status = "archived".to_string();
+    matatu.archived_at = Some(now);
+    matatu.version += 1;
+    let before = MATATUS.with(|matatus| matatus.borrow_mut().insert(matatu_id, matatu.clone()));
+    audit(
+        "archive_matatu",
+        "matatu",
+        matatu_id,
+        before.as_ref(),
+        Some(&matatu),
+    );
+
+    Ok(matatu)
+}
+
+#[ic_cdk::update]
+fn set_driver_active(driver_id: u64, active: bool) -> Result<Driver, Message> {
+    let mut driver = DRIVERS
+        .with(|drivers| drivers.borrow().get(&driver_id))
+        .ok_or(Message::NotFound("Driver not found".to_string()))?;
+    authorize_sacco_admin(driver.sacco_id)?;
+    if driver.status == "archived" {
+        return Err(Message::Error("Driver is archived".to_string()));
+    }
+
+    if !active {
+        if let Some(reason) = driver_in_use(driver_id, time()) {
+            return Err(Message::Error(reason));
+        }
+    }
+
+    driver.status = if active { "active" } else { "inactive" }.to_string();
+    driver.archived_at = None;
+    driver.version += 1;
+    let before = DRIVERS.with(|drivers| drivers.borrow_mut().insert(driver_id, driver.clone()));
+    audit(
+        "set_driver_active",
+        "driver",
+        driver_id,
+        before.as_ref(),
+        Some(&driver),
+    );
+
+    Ok(driver)
+}
+
+// Archiving a driver also takes them off their matatu
+#[ic_cdk::update]
+fn archive_driver(driver_id: u64) -> Result<Driver, Message> {
+    let mut driver = DRIVERS
+        .with(|drivers| drivers.borrow().get(&driver_id))
+        .ok_or(Message::NotFound("Driver not found".to_string()))?;
+    authorize_sacco_admin(driver.sacco_id)?;
+    if driver.status == "archived" {
+        return Err(Message::Error("Driver is already archived".to_string()));
+    }
+
+    let now = time();
+    if let Some(reason) = driver_in_use(driver_id, now) {
+        return Err(Message::Error(reason));
+    }
+
+    driver.status = "archived".to_string();
+    driver.archived_at = Some(now);
+    driver.assigned_matatu = None;
+    driver.version += 1;
+    let before = DRIVERS.with(|drivers| drivers.borrow_mut().insert(driver_id, driver.clone()));
+    audit(
+        "archive_driver",
+        "driver",
+        driver_id,
+        before.as_ref(),
+        Some(&driver),
+    );
+
+    Ok(driver)
+}
+
+#[ic_cdk::update]
+fn set_route_active(route_id: u64, active: bool) -> Result<Route, Message> {
+    let mut route = ROUTES
+        .with(|routes| routes.borrow().get(&route_id))
+        .ok_or(Message::NotFound("Route not found".to_string()))?;
+    authorize_sacco_admin(route.sacco_id)?;
+    if route.status == "archived" {
+        return Err(Message::Error("Route is archived".to_string()));
+    }
+
+    if !active {
+        if let Some(reason) = route_in_use(&route, time()) {
+            return Err(Message::Error(reason));
+        }
+    }
+
+    route.status = if active { "active" } else { "inactive" }.to_string();
+    route.archived_at = None;
+    let before = ROUTES.with(|routes| routes.borrow_mut().insert(route_id, route.clone()));
+    audit(
+        "set_route_active",
+        "route",
+        route_id,
+        before.as_ref(),
+        Some(&route),
+    );
+
+    Ok(route)
+}
+
+// A route can only be archived once no matatu is licensed to run it
+#[ic_cdk::update]
+fn archive_route(route_id: u64) -> Result<Route, Message> {
+    let mut route = ROUTES
+        .with(|routes| routes.borrow().get(&route_id))
+        .ok_or(Message::NotFound("Route not found".to_string()))?;
+    authorize_sacco_admin(route.sacco_id)?;
+    if route.status == "archived" {
+        return Err(Message::Error("Route is already archived".to_string()));
+    }
+
+    let now = time();
+    if let Some(reason) = route_in_use(&route, now) {
+        return Err(Message::Error(reason));
+    }
+
+    let licensed = MATATUS.with(|matatus| {
+        matatus
+            .borrow()
+            .iter()
+            .filter(|(_, m)| {
+                m.sacco_id == route.sacco_id && m.route == route.name && m.status != "archived"
+            })
+            .count()
+    });
+    if licensed > 0 {
+        return Err(Message::Error(format!(
+            "Route is still licensed to {} matatus",
+            licensed
+        )));
+    }
+
+    route.status = "archived".to_string();
+    route.archived_at = Some(now);
+    let before = ROUTES.with(|routes| routes.borrow_mut().insert(route_id, route.clone()));
+    audit(
+        "archive_route",
+        "route",
+        route_id,
+        before.as_ref(),
+        Some(&route),
+    );
+
+    Ok(route)
+}
+
+#[ic_cdk::query]
+fn get_saccos(include_archived: Option<bool>) -> Vec<SACCO> {
+    let include_archived = include_archived.unwrap_or(false);
+    // Controllers see every SACCO, everyone else only the ones they belong to
+    let caller = caller();
+    let everything = ic_cdk::api::is_controller(&caller);
+    SACCOS.with(|saccos| {
+        saccos
+            .borrow()
+            .iter()
+            .filter(|(id, _)| everything || is_sacco_member(*id, caller))
+            .filter(|(_, s)| include_archived || s.status != "archived")
+            .map(|(_, s)| s.clone())
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_sacco_matatus(
+    sacco_id: u64,
+    include_archived: Option<bool>,
+) -> Result<Vec<Matatu>, Message> {
+    authorize_sacco(sacco_id)?;
+
+    let include_archived = include_archived.unwrap_or(false);
+    Ok(MATATUS.with(|matatus| {
+        matatus
+            .borrow()
+            .iter()
+            .filter(|(_, m)| m.sacco_id == sacco_id && (include_archived || m.status != "archived"))
+            .map(|(_, m)| m.clone())
+            .collect()
+    }))
+}
+
+// Look a matatu up by the fleet number painted on it
+#[ic_cdk::query]
+fn get_matatu_by_fleet_number(fleet_number: String) -> Result<Matatu, Message> {
+    let fleet_number = fleet_number.trim().to_uppercase();
+    let matatu = MATATUS
+        .with(|matatus| {
+            matatus
+                .borrow()
+                .iter()
+                .find(|(_, m)| m.fleet_number == fleet_number)
+                .map(|(_, m)| m.clone())
+        })
+        .ok_or(Message::NotFound("Matatu not found".to_string()))?;
+    authorize_sacco(matatu.sacco_id)?;
+
+    Ok(matatu)
+}
+
+#[ic_cdk::query]
+fn get_sacco_drivers(
+    sacco_id: u64,
+    include_archived: Option<bool>,
+) -> Result<Vec<Driver>, Message> {
+    authorize_sacco(sacco_id)?;
+
+    let include_archived = include_archived.unwrap_or(false);
+    Ok(DRIVERS.with(|drivers| {
+        drivers
+            .borrow()
+            .iter()
+            .filter(|(_, d)| d.sacco_id == sacco_id && (include_archived || d.status != "archived"))
+            .map(|(_, d)| d.clone())
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn get_routes(sacco_id: u64, include_archived: Option<bool>) -> Result<Vec<Route>, Message> {
+    authorize_sacco(sacco_id)?;
+
+    let include_archived = include_archived.unwrap_or(false);
+    Ok(ROUTES.with(|routes| {
+        routes
+            .borrow()
+            .iter()
+            .filter(|(_, r)| r.sacco_id == sacco_id && (include_archived || r.status != "archived"))
+            .map(|(_, r)| r.clone())
+            .collect()
+    }))
+}
+
+// Shift check-in, called by the driver
+#[ic_cdk::update]
+fn check_in() -> Result<Shift, Message> {
+    let driver = driver_for_caller()?;
+    let now = time();
+
+    if let Some(reason) = driver_unavailability(&driver, now, now + 1) {
+        return Err(Message::Error(reason));
+    }
+    if open_shift(driver.id).is_some() {
+        return Err(Message::Error("Driver is already checked in".to_string()));
+    }
+
+    let shift = Shift {
+        id: next_id(Sequence::Shift),
+        driver_id: driver.id,
+        check_in: now,
+        check_out: None,
+    };
+
+    SHIFTS.with(|shifts| shifts.borrow_mut().insert(shift.id, shift.clone()));
+    audit("check_in", "shift", shift.id, None, Some(&shift));
+
+    Ok(shift)
+}
+
+// Shift check-out, called by the driver
+#[ic_cdk::update]
+fn check_out() -> Result<Shift, Message> {
+    let driver = driver_for_caller()?;
+    let mut shift =
+        open_shift(driver.id).ok_or(Message::Error("Driver is not checked in".to_string()))?;
+
+    shift.check_out = Some(time());
+    let before = SHIFTS.with(|shifts| shifts.borrow_mut().insert(shift.id, shift.clone()));
+    audit(
+        "check_out",
+        "shift",
+        shift.id,
+        before.as_ref(),
+        Some(&shift),
+    );
+
+    Ok(shift)
+}
+
+// Monthly attendance, `month` is a calendar month as YYYYMM
+#[ic_cdk::query]
+fn get_attendance_report(driver_id: u64, month: u64) -> Result<AttendanceReport, Message> {
+    let driver = DRIVERS
+        .with(|drivers| drivers.borrow().get(&driver_id))
+        .ok_or(Message::NotFound("Driver not found".to_string()))?;
+    authorize_driver(&driver)?;
+
+    let (month_start, month_end) = calendar::month_bounds(month).ok_or(Message::InvalidPayload(
+        "Month must be given as YYYYMM".to_string(),
+    ))?;
+    let now = time();
+    let month_end = month_end.min(now);
+    let shifts: Vec<Shift> = SHIFTS.with(|shifts| {
+        shifts
+            .borrow()
+            .iter()
+            .filter(|(_, s)| {
+                s.driver_id == driver_id && s.check_in >= month_start && s.check_in < month_end
+            })
+            .map(|(_, s)| s.clone())
+            .collect()
+    });
+
+    let mut report = AttendanceReport {
+        driver_id,
+        month,
+        hours_on_shift: shifts
+            .iter()
+            .map(|s| s.check_out.unwrap_or(now).saturating_sub(s.check_in) as f64)
+            .sum::<f64>()
+            / HOUR_NANOS as f64,
+        ..Default::default()
+    };
+
+    let mut day_start = month_start;
+    while day_start < month_end {
+        let day_end = day_start + DAY_NANOS;
+        if driver.working_days.is_empty()
+            || driver
+                .working_days
+                .contains(&calendar::day_of_week(day_start))
+        {
+            report.working_days += 1;
+            match approved_leave(driver_id, day_start, day_end).map(|l| l.leave_type) {
+                Some(leave_type) if leave_type == "sick" => report.sick_days += 1,
+                Some(leave_type) if leave_type == "suspension" => report.suspended_days += 1,
+                Some(_) => report.leave_days += 1,
+                None if shifts
+                    .iter()
+                    .any(|s| s.check_in >= day_start && s.check_in < day_end) =>
+                {
+                    report.days_worked += 1
+                }
+                None => report.absent_days += 1,
+            }
+        }
+        day_start = day_end;
+    }
+
+    Ok(report)
+}
+
+#[ic_cdk::update]
+fn start_trip(payload: StartTripPayload) -> Result<Trip, Message> {
+    // Validate matatu and driver existence
+    let matatu = MATATUS.with(|matatus| matatus.borrow().get(&payload.matatu_id));
+    let driver = DRIVERS.with(|drivers| drivers.borrow().get(&payload.driver_id));
+    let (matatu, driver) = match (matatu, driver) {
+        (Some(matatu), Some(driver)) => (matatu, driver),
+        _ => return Err(Message::NotFound("Matatu or Driver not found".to_string())),
+    };
+
+    if matatu.sacco_id != driver.sacco_id {
+        return Err(Message::InvalidPayload(
+            "Driver and matatu belong to different SACCOs".to_string(),
+        ));
+    }
+    authorize_driver(&driver)?;
+
+    if let Some(reason) = matatu_unavailability(&matatu) {
+        return Err(Message::Error(reason));
+    }
+
+    // One trip at a time, or schedules and driving hours would count twice
+    if find_ongoing_trip(matatu.id).is_some() {
+        return Err(Message::Error("Matatu has an ongoing trip".to_string()));
+    }
+    if find_driver_ongoing_trip(driver.id).is_some() {
+        return Err(Message::Error("Driver has an ongoing trip".to_string()));
+    }
+
+    let now = time();
+    if let Some(reason) = driver_unavailability(&driver, now, now + 1) {
+        return Err(Message::Error(reason));
+    }
+
+    let mut route = payload.route;
+    let schedule = match payload.schedule_id {
+        Some(schedule_id) => {
+            let schedule = SCHEDULES
+                .with(|schedules| schedules.borrow().get(&schedule_id))
+                .ok_or(Message::NotFound("Schedule not found".to_string()))?;
+            if schedule.matatu_id != payload.matatu_id || schedule.driver_id != payload.driver_id {
+                return Err(Message::InvalidPayload(
+                    "Schedule is for a different matatu or driver".to_string(),
+                ));
+            }
+            if schedule.status != "scheduled" {
+                return Err(Message::Error(format!("Schedule is {}", schedule.status)));
+            }
+            // The periodic job marks it missed soon, until then it can't be started either
+            if now > schedule.start_time + SCHEDULE_GRACE_PERIOD {
+                return Err(Message::Error("Schedule is missed".to_string()));
+            }
+            if let Some(scheduled_route) =
+                ROUTES.with(|routes| routes.borrow().get(&schedule.route_id))
+            {
+                route = scheduled_route.name;
+            }
+            Some(schedule)
+        }
+        None => None,
+    };
+
+    // Block the trip if it would take the driver past the hours-of-service limits.
+    // Only a breach that actually happens, checked in end_trip, costs compliance score.
+    let planned = match &schedule {
+        Some(schedule) => schedule.end_time.saturating_sub(schedule.start_time),
+        None => find_route_by_name(matatu.sacco_id, &route)
+            .map_or(0, |r| r.estimated_time as u64 * 60 * 1_000_000_000),
+    };
+    let sacco_id = driver.sacco_id;
+    let duties = driver_trip_duties(
+        payload.driver_id,
+        now.saturating_sub(8 * DAY_NANOS),
+        now,
+        None,
+    );
+    if let Some(reason) = driving_limit_breach(
+        &duties,
+        now,
+        now + planned.max(1),
+        &hours_policy_for(sacco_id),
+    ) {
+        return Err(Message::Error(reason));
+    }
+
+    let trip_id = next_id(Sequence::Trip);
+
+    let trip = Trip {
+        id: trip_id,
+        matatu_id: payload.matatu_id,
+        driver_id: payload.driver_id,
+        start_time: now,
+        end_time: None,
+        passengers: 0,
+        route,
+        status: "ongoing".to_string(),
+        revenue: 0.0,
+        schedule_id: payload.schedule_id,
+    };
+
+    TRIPS.with(|trips| trips.borrow_mut().insert(trip_id, trip.clone()));
+
+    if let Some(mut schedule) = schedule {
+        schedule.status = "in_progress".to_string();
+        schedule.trip_id = Some(trip_id);
+        schedule.actual_start = Some(now);
+        SCHEDULES.with(|schedules| schedules.borrow_mut().insert(schedule.id, schedule));
+    }
+    audit("start_trip", "trip", trip_id, None, Some(&trip));
+
+    Ok(trip)
+}
+
+#[ic_cdk::update]
+fn end_trip(payload: EndTripPayload) -> Result<Trip, Message> {
+    let (before, trip) = TRIPS.with(|trips| {
+        let mut trips_map = trips.borrow_mut();
+        if let Some(mut trip) = trips_map.get(&payload.trip_id) {
+            authorize_trip(&trip)?;
+            if trip.status != "ongoing" {
+                return Err(Message::Error("Trip is not ongoing".to_string()));
+            }
+
+            trip.end_time = Some(time());
+            trip.passengers = payload.passengers;
+            trip.revenue = payload.revenue;
+            trip.status = "completed".to_string();
+
+            // Everyone still on board alights at the end of the trip
+            complete_trip_tickets(trip.id);
+
+            if let Some(schedule_id) = trip.schedule_id {
+                SCHEDULES.with(|schedules| {
+                    let mut schedules_map = schedules.borrow_mut();
+                    if let Some(mut schedule) = schedules_map.get(&schedule_id) {
+                        schedule.status = "completed".to_string();
+                        schedule.actual_end = trip.end_time;
+                        schedules_map.insert(schedule_id, schedule);
+                    }
+                });
+            }
+
+            // Update driver performance
+            update_driver_performance(trip.driver_id, payload.revenue);
+
+            let before = trips_map.insert(payload.trip_id, trip.clone());
+            Ok((before, trip))
+        } else {
+            Err(Message::NotFound("Trip not found".to_string()))
+        }
+    })?;
+    audit("end_trip", "trip", trip.id, before.as_ref(), Some(&trip));
+
+    // A trip that ran past the hours-of-service limits counts against the driver
+    let end_time = trip.end_time.unwrap_or_default();
+    let duties = driver_trip_duties(
+        trip.driver_id,
+        trip.start_time.saturating_sub(8 * DAY_NANOS),
+        end_time,
+        Some(trip.id),
+    );
+    let sacco_id = DRIVERS
+        .with(|drivers| drivers.borrow().get(&trip.driver_id))
+        .map_or(0, |d| d.sacco_id);
+    let policy = hours_policy_for(sacco_id);
+    if driving_limit_breach(&duties, trip.start_time, end_time, &policy).is_some() {
+        apply_compliance_penalty(trip.driver_id, FATIGUE_PENALTY);
+    }
+
+    // Learn how long the route takes at this time of the week
+    if let Some(route) = trip_route(&trip) {
+        let minutes = end_time.saturating_sub(trip.start_time) as f64 / 60_000_000_000.0;
+        record_traffic(route.id, trip.start_time, |stats| {
+            let samples = (stats.trips + 1).min(MAX_SEGMENT_SAMPLES);
+            stats.average_duration += (minutes - stats.average_duration) / samples as f64;
+            stats.trips = samples;
+        });
+
+        // Ticketed passengers count in the hour they boarded, the rest when the trip started
+        let boardings: Vec<u64> = TICKETS.with(|tickets| {
+            tickets
+                .borrow()
+                .iter()
+                .filter(|(_, t)| t.trip_id == trip.id && t.status != "cancelled")
+                .map(|(_, t)| t.issued_at)
+                .collect()
+        });
+        let mut by_hour: BTreeMap<u64, u32> = BTreeMap::new();
+        for issued_at in &boardings {
+            *by_hour
+                .entry(issued_at - issued_at % HOUR_NANOS)
+                .or_default() += 1;
+        }
+        let untracked = trip.passengers.saturating_sub(boardings.len() as u32);
+        if untracked > 0 {
+            *by_hour.entry(trip.start_time).or_default() += untracked;
+        }
+        for (hour, passengers) in by_hour {
+            record_demand(route.id, hour, passengers);
+        }
+    }
+
+    Ok(trip)
+}
+
+// Hours of Service
+#[ic_cdk::update]
+fn set_hours_of_service_policy(
+    payload: HoursOfServicePayload,
+) -> Result<HoursOfServicePolicy, Message> {
+    if payload.max_continuous_driving == 0
+        || payload.min_break == 0
+        || payload.max_continuous_driving > payload.max_daily_driving
+        || payload.max_daily_driving > payload.max_weekly_driving
+        || payload.min_daily_rest >= 24 * 60
+    {
+        return Err(Message::InvalidPayload(
+            "Limits must satisfy 0 < continuous <= daily <= weekly, with a break and less than a day of rest".to_string(),
+        ));
+    }
+
+    let sacco_exists = SACCOS.with(|saccos| saccos.borrow().contains_key(&payload.sacco_id));
+    if !sacco_exists {
+        return Err(Message::NotFound("SACCO not found".to_string()));
+    }
+    authorize_sacco_admin(payload.sacco_id)?;
+
+    let policy = HoursOfServicePolicy {
+        sacco_id: payload.sacco_id,
+        max_continuous_driving: payload.max_continuous_driving,
+        min_break: payload.min_break,
+        max_daily_driving: payload.max_daily_driving,
+        max_weekly_driving: payload.max_weekly_driving,
+        min_daily_rest: payload.min_daily_rest,
+        updated_at: time(),
+    };
+
+    let before = HOURS_POLICIES.with(|policies| {
+        policies
+            .borrow_mut()
+            .insert(policy.sacco_id, policy.clone())
+    });
+    audit(
+        "set_hours_of_service_policy",
+        "hours_of_service_policy",
+        policy.sacco_id,
+        before.as_ref(),
+        Some(&policy),
+    );
+
+    Ok(policy)
+}
+
+#[ic_cdk::query]
+fn get_hours_of_service_policy(sacco_id: u64) -> Result<HoursOfServicePolicy, Message> {
+    authorize_sacco(sacco_id)?;
+
+    Ok(hours_policy_for(sacco_id))
+}
+
+#[ic_cdk::query]
+fn get_driving_hours(driver_id: u64) -> Result<DrivingHours, Message> {
+    let driver = DRIVERS
+        .with(|drivers| drivers.borrow().get(&driver_id))
+        .ok_or(Message::NotFound("Driver not found".to_string()))?;
+    authorize_driver(&driver)?;
+
+    let now = time();
+    let policy = hours_policy_for(driver.sacco_id);
+    let duties = driver_trip_duties(driver_id, now.saturating_sub(7 * DAY_NANOS), now, None);
+    let to_minutes = |nanos: u64| (nanos / 60_000_000_000) as u32;
+
+    let mut hours = DrivingHours {
+        driver_id,
+        last_24_hours: to_minutes(driven_within(&duties, now, DAY_NANOS)),
+        last_7_days: to_minutes(driven_within(&duties, now, 7 * DAY_NANOS)),
+        ..Default::default()
+    };
+
+    if let Some(last) = duties.len().checked_sub(1) {
+        let break_gap = minutes_to_nanos(policy.min_break);
+        if duties[last].1 + break_gap > now {
+            hours.continuous_driving = to_minutes(duty_block_span(&duties, last, break_gap));
+        }
+        if duties[last].1 < now {
+            hours.resting_for = Some(to_minutes(now - duties[last].1));
+        }
+    } else {
+        hours.resting_for = Some(to_minutes(7 * DAY_NANOS));
+    }
+
+    Ok(hours)
+}
+
+// Issue Ticket
+#[ic_cdk::update]
+fn issue_ticket(payload: IssueTicketPayload) -> Result<Ticket, Message> {
+    if payload.fare < 0.0 {
+        return Err(Message::InvalidPayload(
+            "Fare cannot be negative".to_string(),
+        ));
+    }
+
+    let trip = TRIPS
+        .with(|trips| trips.borrow().get(&payload.trip_id))
+        .ok_or(Message::NotFound("Trip not found".to_string()))?;
+    authorize_trip(&trip)?;
+    if trip.status != "ongoing" {
+        return Err(Message::Error("Trip is not ongoing".to_string()));
+    }
+
+    let route = trip_route(&trip);
+    let route_id = route.as_ref().map(|r| r.id);
+    for stop_id in [payload.boarding_stop_id, payload.alighting_stop_id]
+        .into_iter()
+        .flatten()
+    {
+        let on_route = ROUTE_STOPS
+            .with(|stops| stops.borrow().get(&stop_id))
+            .is_some_and(|stop| Some(stop.route_id) == route_id);
+        if !on_route {
+            return Err(Message::InvalidPayload(
+                "Stop is not on the trip's route".to_string(),
+            ));
+        }
+    }
+
+    let capacity = MATATUS
+        .with(|matatus| matatus.borrow().get(&trip.matatu_id))
+        .map(|m| m.capacity)
+        .unwrap_or_default();
+    if onboard_tickets(trip.id).len() as u32 >= capacity {
+        return Err(Message::Error("Matatu is full".to_string()));
+    }
+
+    let now = time();
+    let fare_quote = match &route {
+        Some(route) => Some(quote_route_fare(
+            route,
+            payload.boarding_stop_id,
+            payload.alighting_stop_id,
+            payload.category.as_deref(),
+            now,
+        )?),
+        None => None,
+    };
+
+    let ticket = Ticket {
+        id: next_id(Sequence::Ticket),
+        trip_id: trip.id,
+        matatu_id: trip.matatu_id,
+        route_id,
+        boarding_stop_id: payload.boarding_stop_id,
+        alighting_stop_id: payload.alighting_stop_id,
+        fare: fare_quote.as_ref().map_or(payload.fare, |q| q.fare),
+        status: "onboard".to_string(),
+        issued_at: now,
+        fare_quote,
+    };
+
+    TICKETS.with(|tickets| tickets.borrow_mut().insert(ticket.id, ticket.clone()));
+    audit("issue_ticket", "ticket", ticket.id, None, Some(&ticket));
+
+    Ok(ticket)
+}
+
+// Fare Rules
+#[ic_cdk::update]
+fn set_fare_rule(payload: FareRulePayload) -> Result<FareRule, Message> {
+    if payload.stage_fares.len() > MAX_STAGE_FARES
+        || payload.overrides.len() > MAX_FARE_OVERRIDES
+        || payload.discounts.len() > MAX_FARE_DISCOUNTS
+    {
+        return Err(Message::InvalidPayload(format!(
+            "At most {} stage fares, {} overrides and {} discounts are allowed",
+            MAX_STAGE_FARES, MAX_FARE_OVERRIDES, MAX_FARE_DISCOUNTS
+        )));
+    }
+
+    let mut fares = [payload.base_fare, payload.peak_surcharge]
+        .into_iter()
+        .chain(payload.stage_fares.iter().map(|s| s.fare));
+    if fares.any(|fare| fare.is_nan() || fare < 0.0) {
+        return Err(Message::InvalidPayload(
+            "Fares cannot be negative".to_string(),
+        ));
+    }
+
+    for fare_override in &payload.overrides {
+        if fare_override.name.len() > MAX_FARE_LABEL
+            || fare_override.end_time <= fare_override.start_time
+            || fare_override.multiplier.is_nan()
+            || fare_override.multiplier <= 0.0
+            || fare_override.surcharge.is_nan()
+        {
+            return Err(Message::InvalidPayload(format!(
+                "Overrides need a name of at most {} characters, a period and a positive multiplier",
+                MAX_FARE_LABEL
+            )));
+        }
+    }
+
+    for (index, discount) in payload.discounts.iter().enumerate() {
+        if discount.category.is_empty()
+            || discount.category.len() > MAX_FARE_LABEL
+            || !(0.0..=100.0).contains(&discount.percent)
+            || payload.discounts[..index]
+                .iter()
+                .any(|d| d.category == discount.category)
+        {
+            return Err(Message::InvalidPayload(format!(
+                "Discounts need a unique category of at most {} characters and a percentage from 0 to 100",
+                MAX_FARE_LABEL
+            )));
+        }
+    }
+
+    tenant_route(payload.route_id)?;
+
+    for stage in &payload.stage_fares {
+        for stop_id in [stage.from_stop_id, stage.to_stop_id] {
+            let on_route = ROUTE_STOPS
+                .with(|stops| stops.borrow().get(&stop_id))
+                .is_some_and(|stop| stop.route_id == payload.route_id);
+            if !on_route {
+                return Err(Message::InvalidPayload(
+                    "Stage fare stop is not on the route".to_string(),
+                ));
+            }
+        }
+    }
+
+    let rule = FareRule {
+        route_id: payload.route_id,
+        base_fare: payload.base_fare,
+        stage_fares: payload.stage_fares,
+        peak_surcharge: payload.peak_surcharge,
+        overrides: payload.overrides,
+        discounts: payload.discounts,
+        updated_at: time(),
+    };
+
+    let before = FARE_RULES.with(|rules| rules.borrow_mut().insert(rule.route_id, rule.clone()));
+    audit(
+        "set_fare_rule",
+        "fare_rule",
+        rule.route_id,
+        before.as_ref(),
+        Some(&rule),
+    );
+
+    Ok(rule)
+}
+
+// Public passenger query: the fares a route charges, as posted at its stages
+#[ic_cdk::query]
+fn get_fare_rule(route_id: u64) -> Result<FareRule, Message> {
+    let route = public_route(route_id)?;
+
+    Ok(fare_rule_for(&route))
+}
+
+// Public passenger query: the fare for one ride, with how it was worked out
+#[ic_cdk::query]
+fn quote_fare(payload: FareQuotePayload) -> Result<FareQuote, Message> {
+    let route = public_route(payload.route_id)?;
+
+    for stop_id in [payload.boarding_stop_id, payload.alighting_stop_id]
+        .into_iter()
+        .flatten()
+    {
+        let on_route = ROUTE_STOPS
+            .with(|stops| stops.borrow().get(&stop_id))
+            .is_some_and(|stop| stop.route_id == route.id);
+        if !on_route {
+            return Err(Message::InvalidPayload(
+                "Stop is not on the route".to_string(),
+            ));
+        }
+    }
+
+    quote_route_fare(
+        &route,
+        payload.boarding_stop_id,
+        payload.alighting_stop_id,
+        payload.category.as_deref(),
+        payload.time.unwrap_or_else(time),
+    )
+}
+
+#[ic_cdk::query]
+fn get_driver_performance(driver_id: u64, month: u64) -> Result<DriverPerformance, Message> {
+    tenant_driver(driver_id)?;
+    if month < FIRST_CALENDAR_MONTH || calendar::month_bounds(month).is_none() {
+        return Err(Message::InvalidPayload(
+            "Month must be given as YYYYMM".to_string(),
+        ));
+    }
+
+    DRIVER_PERFORMANCE.with(|performances| {
+        performances
+            .borrow()
+            .iter()
+            .find(|(_, p)| p.driver_id == driver_id && p.month == month)
+            .map(|(_, p)| p.clone())
+            .ok_or(Message::NotFound(
+                "Performance record not found".to_string(),
+            ))
+    })
+}
+
+#[ic_cdk::query]
+fn get_matatu_analytics(matatu_id: u64) -> Result<MatatuAnalytics, Message> {
+    tenant_matatu(matatu_id)?;
+
+    let total_trips = TRIPS.with(|trips| {
+        trips
+            .borrow()
+            .iter()
+            .filter(|(_, t)| t.matatu_id == matatu_id)
+            .count()
+    });
+
+    let total_revenue = TRIPS.with(|trips| {
+        trips
+            .borrow()
+            .iter()
+            .filter(|(_, t)| t.matatu_id == matatu_id)
+            .map(|(_, t)| t.revenue)
+            .sum()
+    });
+
+    let maintenance_costs = MAINTENANCE_RECORDS.with(|records| {
+        records
+            .borrow()
+            .iter()
+            .filter(|(_, r)| r.matatu_id == matatu_id)
+            .map(|(_, r)| r.cost)
+            .sum()
+    });
+
+    let fuel_costs = FUEL_RECORDS.with(|records| {
+        records
+            .borrow()
+            .iter()
+            .filter(|(_, r)| r.matatu_id == matatu_id)
+            .map(|(_, r)| r.cost)
+            .sum()
+    });
+
+    Ok(MatatuAnalytics {
+        total_trips,
+        total_revenue,
+        maintenance_costs,
+        fuel_costs,
+        net_profit: total_revenue - maintenance_costs - fuel_costs,
+    })
+}
+
+// Record Maintenance
+#[ic_cdk::update]
+fn record_maintenance(payload: RecordMaintenancePayload) -> Result<Maintenance, Message> {
+    if payload.description.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Missing required fields".to_string(),
+        ));
+    }
+
+    tenant_matatu(payload.matatu_id)?;
+
+    let maintenance = Maintenance {
+        id: next_id(Sequence::Maintenance),
+        matatu_id: payload.matatu_id,
+        date: payload.date,
+        downtime_hours: payload.downtime_hours,
+        description: payload.description,
+        cost: payload.cost,
+        status: "scheduled".to_string(),
+    };
+
+    MAINTENANCE_RECORDS.with(|records| {
+        records
+            .borrow_mut()
+            .insert(maintenance.id, maintenance.clone())
+    });
+    audit(
+        "record_maintenance",
+        "maintenance",
+        maintenance.id,
+        None,
+        Some(&maintenance),
+    );
+
+    Ok(maintenance)
+}
+
+// Create Route
+#[ic_cdk::update]
+fn create_route(payload: CreateRoutePayload) -> Result<Route, Message> {
+    let sacco = check_new_route(&payload)?;
+
+    let route_id = next_id(Sequence::Route);
+    let line: Vec<(f64, f64)> = payload
+        .stops
+        .iter()
+        .map(|stop| (stop.latitude, stop.longitude))
+        .collect();
+
+    let route = Route {
+        id: route_id,
+        sacco_id: sacco.id,
+        name: payload.name,
+        start_point: payload.start_point,
+        end_point: payload.end_point,
+        distance: geo::polyline_length(&line) / 1000.0,
+        estimated_time: payload.estimated_time,
+        peak_hours: payload.peak_hours,
+        traffic_patterns: vec![],
+        average_passengers: payload.average_passengers,
+        price: payload.price,
+        corridor_buffer: if payload.corridor_buffer > 0.0 {
+            payload.corridor_buffer
+        } else {
+            DEFAULT_CORRIDOR_BUFFER
+        },
+        status: "active".to_string(),
+        archived_at: None,
+    };
+
+    ROUTE_STOPS.with(|stops| {
+        let mut stops_map = stops.borrow_mut();
+        for (sequence, stop) in payload.stops.into_iter().enumerate() {
+            let stop_id = next_id(Sequence::RouteStop);
+            stops_map.insert(
+                stop_id,
+                RouteStop {
+                    id: stop_id,
+                    route_id,
+                    sequence: sequence as u32,
+                    name: stop.name,
+                    latitude: stop.latitude,
+                    longitude: stop.longitude,
+                    radius: if stop.radius > 0.0 {
+                        stop.radius
+                    } else {
+                        DEFAULT_STAGE_RADIUS
+                    },
+                },
+            );
+        }
+    });
+
+    ROUTES.with(|routes| routes.borrow_mut().insert(route_id, route.clone()));
+    audit("create_route", "route", route_id, None, Some(&route));
+
+    Ok(route)
+}
+
+// Helper function to check a new route without creating it, giving its SACCO
+fn check_new_route(payload: &CreateRoutePayload) -> Result<SACCO, Message> {
+    if payload.name.is_empty() || payload.start_point.is_empty() || payload.end_point.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Missing required fields".to_string(),
+        ));
+    }
+
+    check_text("Name", &payload.name, MAX_ROUTE_NAME)?;
+    check_text("Start point", &payload.start_point, MAX_ROUTE_PLACE)?;
+    check_text("End point", &payload.end_point, MAX_ROUTE_PLACE)?;
+    if payload.peak_hours.len() > MAX_PEAK_WINDOWS {
+        return Err(Message::InvalidPayload(format!(
+            "A route can have at most {} peak windows",
+            MAX_PEAK_WINDOWS
+        )));
+    }
+
+    if payload.stops.len() < 2 || payload.stops.len() > MAX_ROUTE_STOPS {
+        return Err(Message::InvalidPayload(format!(
+            "A route needs between two and {} stops",
+            MAX_ROUTE_STOPS
+        )));
+    }
+    for stop in &payload.stops {
+        check_text("Stop name", &stop.name, MAX_TEXT)?;
+    }
+
+    if payload
+        .stops
+        .iter()
+        .any(|stop| !geo::is_valid_coordinate(stop.latitude, stop.longitude))
+    {
+        return Err(Message::InvalidPayload(
+            "Invalid stop coordinates".to_string(),
+        ));
+    }
+
+    let sacco = open_sacco(payload.sacco_id)?;
+
+    // Matatus and trips refer to routes by name, so names are unique within a SACCO
+    if find_route_by_name(sacco.id, &payload.name).is_some() {
+        return Err(Message::Error(
+            "SACCO already has a route with this name".to_string(),
+        ));
+    }
+
+    Ok(sacco)
+}
+
+// Public passenger query: the stages of a route, as printed on a route map.
+// Archived routes have none.
+#[ic_cdk::query]
+fn get_route_stops(route_id: u64) -> Vec<RouteStop> {
+    match public_route(route_id) {
+        Ok(route) => get_ordered_stops(route.id),
+        Err(_) => Vec::new(),
+    }
+}
+
+// Public passenger query: predicted arrival at each remaining stop of a matatu's
+// ongoing trip. Gives stop times only, never the matatu's position.
+#[ic_cdk::query]
+fn get_stop_predictions(matatu_id: u64) -> Result<Vec<StopPrediction>, Message> {
+    let state = TRACKING_STATES
+        .with(|states| states.borrow().get(&matatu_id))
+        .ok_or(Message::NotFound("No location data for matatu".to_string()))?;
+    let route = state
+        .route_id
+        .ok_or(Message::NotFound("Matatu is not on a trip".to_string()))
+        .and_then(public_route)?;
+
+    let stops = get_ordered_stops(route.id);
+    let line: Vec<(f64, f64)> = stops.iter().map(|s| (s.latitude, s.longitude)).collect();
+    let distances = geo::cumulative_distances(&line);
+    let projection = tracked_projection(&state);
+
+    Ok(stops
+        .into_iter()
+        .zip(distances)
+        .filter(|(_, distance)| *distance > state.route_progress)
+        .map(|(stop, distance)| StopPrediction {
+            stop_id: stop.id,
+            stop_name: stop.name,
+            sequence: stop.sequence,
+            predicted_arrival: predict_arrival(
+                &route,
+                &line,
+                &projection,
+                state.average_speed,
+                distance,
+                state.timestamp,
+            ),
+        })
+        .collect())
+}
+
+// Public passenger query: upcoming matatus for passengers waiting at a stop
+#[ic_cdk::query]
+fn get_next_matatus(route_id: u64, stop_id: u64) -> Result<Vec<NextMatatu>, Message> {
+    let route = public_route(route_id)?;
+
+    let stops = get_ordered_stops(route_id);
+    let line: Vec<(f64, f64)> = stops.iter().map(|s| (s.latitude, s.longitude)).collect();
+    let distances = geo::cumulative_distances(&line);
+    let (stop, stop_distance) = stops
+        .iter()
+        .zip(distances)
+        .find(|(s, _)| s.id == stop_id)
+        .ok_or(Message::NotFound("Stop not found on route".to_string()))?;
+
+    let now = time();
+    let mut upcoming = Vec::new();
+
+    // Matatus already on the road that haven't passed the stop yet
+    let tracked: Vec<TrackingState> = TRACKING_STATES.with(|states| {
+        states
+            .borrow()
+            .iter()
+            .filter(|(_, s)| {
+                s.route_id == Some(route_id)
+                    && s.route_progress < stop_distance
+                    && now.saturating_sub(s.timestamp) <= STALE_TRACKING_NANOS
+            })
+            .map(|(_, s)| s.clone())
+            .collect()
+    });
+
+    for state in tracked {
+        let (matatu, trip) = match (
+            MATATUS.with(|matatus| matatus.borrow().get(&state.matatu_id)),
+            find_ongoing_trip(state.matatu_id),
+        ) {
+            (Some(matatu), Some(trip)) => (matatu, trip),
+            _ => continue,
+        };
+
+        let onboard = onboard_tickets(trip.id)
+            .iter()
+            .filter(|t| stays_on_past(t, stop.sequence))
+            .count() as u32;
+
+        upcoming.push(NextMatatu {
+            matatu_id: matatu.id,
+            plate_number: matatu.plate_number,
+            trip_id: Some(trip.id),
+            schedule_id: None,
+            predicted_arrival: predict_arrival(
+                &route,
+                &line,
+                &tracked_projection(&state),
+                state.average_speed,
+                stop_distance,
+                state.timestamp,
+            ),
+            free_seats: matatu.capacity.saturating_sub(onboard),
+            distance_away: stop_distance - state.route_progress,
+        });
+    }
+
+    // Scheduled departures that haven't started yet
+    let scheduled: Vec<Schedule> = SCHEDULES.with(|schedules| {
+        schedules
+            .borrow()
+            .iter()
+            .filter(|(_, s)| {
+                s.route_id == route_id && s.status == "scheduled" && s.start_time >= now
+            })
+            .map(|(_, s)| s.clone())
+            .collect()
+    });
+
+    let departure = geo::Projection::default();
+    for schedule in scheduled {
+        let matatu = match MATATUS.with(|matatus| matatus.borrow().get(&schedule.matatu_id)) {
+            Some(matatu) => matatu,
+            None => continue,
+        };
+
+        upcoming.push(NextMatatu {
+            matatu_id: matatu.id,
+            plate_number: matatu.plate_number,
+            trip_id: None,
+            schedule_id: Some(schedule.id),
+            predicted_arrival: predict_arrival(
+                &route,
+                &line,
+                &departure,
+                route_average_speed(&route),
+                stop_distance,
+                schedule.start_time,
+            ),
+            free_seats: matatu.capacity,
+            distance_away: stop_distance,
+        });
+    }
+
+    upcoming.sort_by_key(|m| m.predicted_arrival);
+    upcoming.truncate(NEXT_MATATU_LIMIT);
+
+    Ok(upcoming)
+}
+
+// Traffic Learning
+// Rebuilds the traffic patterns of every route with enough observations, returns how
+// many routes were updated
+#[ic_cdk::update]
+fn learn_traffic_patterns() -> u32 {
+    let updated = relearn_traffic_patterns();
+    audit("learn_traffic_patterns", "route", 0, None, Some(&updated));
+
+    updated
+}
+
+// Helper function to count the patterns a route has room for. Routes created
+// before text was capped can hold fewer than MAX_ROUTE_PATTERNS.
+fn route_pattern_budget(route: &Route) -> usize {
+    let bare = Route {
+        traffic_patterns: Vec::new(),
+        ..route.clone()
+    };
+    let used = Encode!(&bare)
+        .map(|bytes| bytes.len())
+        .unwrap_or(usize::MAX);
+    // one spare byte for the longer pattern count
+    ((ROUTE_MAX_SIZE as usize).saturating_sub(used.saturating_add(1)) / TRAFFIC_PATTERN_SIZE)
+        .min(MAX_ROUTE_PATTERNS)
+}
+
+// Helper function to rebuild traffic patterns, shared with the hourly job
+fn relearn_traffic_patterns() -> u32 {
+    let routes: Vec<Route> =
+        ROUTES.with(|routes| routes.borrow().iter().map(|(_, r)| r.clone()).collect());
+
+    let mut updated = 0;
+    for mut route in routes {
+        if let Some(patterns) = learned_patterns(&route, route_pattern_budget(&route)) {
+            route.traffic_patterns = patterns;
+            ROUTES.with(|routes| routes.borrow_mut().insert(route.id, route));
+            updated += 1;
+        }
+    }
+
+    updated
+}
+
+#[ic_cdk::query]
+fn get_traffic_stats(route_id: u64) -> Result<Vec<TrafficStats>, Message> {
+    tenant_route(route_id)?;
+
+    Ok(route_traffic_stats(route_id))
+}
+
+// Route Optimization Functions
+#[ic_cdk::update]
+fn optimize_route(route_id: u64, current_time: u64) -> Result<RouteOptimization, Message> {
+    let route = tenant_route(route_id)?;
+
+    // Find current traffic pattern
+    let traffic_pattern = current_traffic_pattern(&route, current_time).unwrap_or_default();
+
+    // Calculate optimized route details
+    let base_time = route.estimated_time as f64;
+    let delay_factor = 1.0 + (traffic_pattern.congestion_level as f64 / 5.0);
+    let optimized_time = (base_time * delay_factor) as u32;
+
+    let alternatives = find_alternate_routes(&route, current_time);
+    let mut alternate_routes: Vec<Route> = Vec::new();
+    for id in alternatives.iter().flat_map(|a| a.route_ids.iter()) {
+        if !alternate_routes.iter().any(|r| r.id == *id) {
+            if let Some(alternate) = ROUTES.with(|routes| routes.borrow().get(id)) {
+                alternate_routes.push(alternate);
+            }
+        }
+    }
+
+    Ok(RouteOptimization {
+        route_id,
+        optimal_start_time: current_time + 10 * 60 * 1_000_000_000, // 10 minutes buffer
+        estimated_duration: optimized_time,
+        congestion_level: traffic_pattern.congestion_level,
+        alternate_routes,
+        alternatives,
+    })
+}
+
+// Customer Feedback System
+#[ic_cdk::update]
+fn submit_feedback(payload: CustomerFeedbackPayload) -> Result<CustomerFeedback, Message> {
+    let feedback_id = next_id(Sequence::CustomerFeedback);
+
+    let feedback = CustomerFeedback {
+        id: feedback_id,
+        trip_id: payload.trip_id,
+        rating: payload.rating,
+        cleanliness: payload.cleanliness,
+        punctuality: payload.punctuality,
+        safety: payload.safety,
+        comment: payload.comment,
+        timestamp: time(),
+    };
+
+    CUSTOMER_FEEDBACK
+        .with(|feedbacks| feedbacks.borrow_mut().insert(feedback_id, feedback.clone()));
+    audit(
+        "submit_feedback",
+        "customer_feedback",
+        feedback_id,
+        None,
+        Some(&feedback),
+    );
+
+    // Update driver performance based on feedback
+    update_driver_performance(feedback.trip_id, feedback.rating as f64);
+
+    Ok(feedback)
+}
+
+// Demand Forecasting
+#[ic_cdk::update]
+fn add_demand_adjustment(payload: DemandAdjustmentPayload) -> Result<DemandAdjustment, Message> {
+    if payload.end_date <= payload.start_date {
+        return Err(Message::InvalidPayload(
+            "Adjustment must end after it starts".to_string(),
+        ));
+    }
+
+    if !["holiday", "school_term", "school_holiday"].contains(&payload.kind.as_str()) {
+        return Err(Message::InvalidPayload(
+            "Kind must be holiday, school_term or school_holiday".to_string(),
+        ));
+    }
+
+    if payload.factor.is_nan() || payload.factor <= 0.0 {
+        return Err(Message::InvalidPayload(
+            "Factor must be greater than zero".to_string(),
+        ));
+    }
+
+    // Adjustments for every route, such as public holidays, are set by controllers
+    match payload.route_id {
+        Some(route_id) => tenant_route(route_id).map(|_| ())?,
+        None => require_controller()?,
+    }
+
+    let adjustment = DemandAdjustment {
+        id: next_id(Sequence::DemandAdjustment),
+        name: payload.name,
+        kind: payload.kind,
+        route_id: payload.route_id,
+        start_date: payload.start_date,
+        end_date: payload.end_date,
+        factor: payload.factor,
+        created_at: time(),
+    };
+
+    DEMAND_ADJUSTMENTS.with(|adjustments| {
+        adjustments
+            .borrow_mut()
+            .insert(adjustment.id, adjustment.clone())
+    });
+    audit(
+        "add_demand_adjustment",
+        "demand_adjustment",
+        adjustment.id,
+        None,
+        Some(&adjustment),
+    );
+
+    Ok(adjustment)
+}
+
+#[ic_cdk::update]
+fn remove_demand_adjustment(adjustment_id: u64) -> Result<DemandAdjustment, Message> {
+    let adjustment = DEMAND_ADJUSTMENTS
+        .with(|adjustments| adjustments.borrow().get(&adjustment_id))
+        .ok_or(Message::NotFound("Adjustment not found".to_string()))?;
+    match adjustment.route_id {
+        Some(route_id) => tenant_route(route_id).map(|_| ())?,
+        None => require_controller()?,
+    }
+
+    // Audited first so the entry can still find the adjustment's SACCO
+    audit(
+        "remove_demand_adjustment",
+        "demand_adjustment",
+        adjustment_id,
+        Some(&adjustment),
+        None,
+    );
+    DEMAND_ADJUSTMENTS.with(|adjustments| adjustments.borrow_mut().remove(&adjustment_id));
+
+    Ok(adjustment)
+}
+
+#[ic_cdk::query]
+fn get_demand_adjustments() -> Vec<DemandAdjustment> {
+    // Everyone sees the adjustments for every route, and those of the routes they can manage
+    DEMAND_ADJUSTMENTS.with(|adjustments| {
+        adjustments
+            .borrow()
+            .iter()
+            .filter(|(_, a)| match a.route_id {
+                Some(route_id) => tenant_route(route_id).is_ok(),
+                None => true,
+            })
+            .map(|(_, a)| a.clone())
+            .collect()
+    })
+}
+
+// Hourly passenger forecast of a route for the day `date` falls on
+#[ic_cdk::query]
+fn forecast_demand(route_id: u64, date: u64) -> Result<Vec<DemandForecast>, Message> {
+    tenant_route(route_id)?;
+
+    Ok(route_demand_forecast(
+        route_id,
+        calendar::start_of_day(date),
+    ))
+}
+
+// Departures and matatus a SACCO should run on each of its routes for the day,
+// ready to be passed to `create_automated_schedule`
+#[ic_cdk::query]
+fn recommend_deployment(sacco_id: u64, date: u64) -> Result<Vec<DemandWindow>, Message> {
+    let sacco_exists = SACCOS.with(|saccos| saccos.borrow().contains_key(&sacco_id));
+    if !sacco_exists {
+        return Err(Message::NotFound("SACCO not found".to_string()));
+    }
+    authorize_sacco(sacco_id)?;
+
+    Ok(demand_windows(sacco_id, calendar::start_of_day(date)))
+}
+
+// Automated Scheduling System
+#[ic_cdk::query]
+fn preview_automated_schedule(
+    sacco_id: u64,
+    date: u64,
+    demand: Option<Vec<DemandWindow>>,
+) -> Result<RosterPlan, Message> {
+    let sacco = SACCOS
+        .with(|saccos| saccos.borrow().get(&sacco_id))
+        .ok_or(Message::NotFound("SACCO not found".to_string()))?;
+    authorize_sacco(sacco_id)?;
+    if sacco.status != "active" {
+        return Err(Message::Error(format!("SACCO is {}", sacco.status)));
+    }
+
+    let demand = roster_demand(sacco_id, date, demand)?;
+    let existing = replaceable_schedules(sacco_id, date, time());
+    Ok(diff_roster(existing, plan_roster(sacco_id, date, demand)))
+}
+
+// Replaces the SACCO's unstarted schedules for the day with a fresh roster.
+// Demand defaults to the deployment recommendation for the day.
+// Departures that keep their slot keep their schedule id, so running it
+// again without changes in between leaves the schedules untouched.
+#[ic_cdk::update]
+fn create_automated_schedule(
+    sacco_id: u64,
+    date: u64,
+    demand: Option<Vec<DemandWindow>>,
+) -> Result<RosterPlan, Message> {
+    let sacco = SACCOS
+        .with(|saccos| saccos.borrow().get(&sacco_id))
+        .ok_or(Message::NotFound("SACCO not found".to_string()))?;
+    authorize_sacco(sacco_id)?;
+    if sacco.status != "active" {
+        return Err(Message::Error(format!("SACCO is {}", sacco.status)));
+    }
+
+    let demand = roster_demand(sacco_id, date, demand)?;
+    let existing = replaceable_schedules(sacco_id, date, time());
+    let before = existing.clone();
+    let mut plan = diff_roster(existing, plan_roster(sacco_id, date, demand));
+
+    // New departures get their ids only once they're saved
+    plan.added.clear();
+    for schedule in plan.roster.schedules.iter_mut() {
+        if schedule.id == 0 {
+            schedule.id = next_id(Sequence::Schedule);
+            plan.added.push(schedule.clone());
+        }
+    }
+
+    SCHEDULES.with(|schedules| {
+        let mut schedules_map = schedules.borrow_mut();
+        for schedule in &plan.removed {
+            schedules_map.remove(&schedule.id);
+        }
+        for schedule in &plan.roster.schedules {
+            schedules_map.insert(schedule.id, schedule.clone());
+        }
+    });
+    audit(
+        "create_automated_schedule",
+        "roster",
+        sacco_id,
+        Some(&before),
+        Some(&plan.roster.schedules),
+    );
+
+    Ok(plan)
+}
+
+// Punctuality of a driver's schedules planned to start within a period
+#[ic_cdk::query]
+fn get_driver_punctuality(
+    driver_id: u64,
+    start_time: u64,
+    end_time: u64,
+) -> Result<DriverPunctuality, Message> {
+    tenant_driver(driver_id)?;
+
+    let now = time();
+    let mut punctuality = DriverPunctuality {
+        driver_id,
+        ..Default::default()
+    };
+    let mut total_delay = 0;
+
+    SCHEDULES.with(|schedules| {
+        for (_, schedule) in schedules.borrow().iter() {
+            if schedule.driver_id != driver_id
+                || schedule.start_time < start_time
+                || schedule.start_time >= end_time
+            {
+                continue;
+            }
+
+            // Overdue schedules count as missed even before the next sweep
+            let started = match schedule.actual_start {
+                Some(started) => started,
+                None if schedule.status == "missed"
+                    || (schedule.status == "scheduled"
+                        && now > schedule.start_time + SCHEDULE_GRACE_PERIOD) =>
+                {
+                    punctuality.scheduled += 1;
+                    punctuality.missed += 1;
+                    continue;
+                }
+                None => continue,
+            };
+
+            let delay = started.saturating_sub(schedule.start_time);
+            punctuality.scheduled += 1;
+            total_delay += delay;
+            if delay <= ON_TIME_TOLERANCE {
+                punctuality.on_time += 1;
+            } else {
+                punctuality.late += 1;
+            }
+        }
+    });
+
+    let ran = punctuality.on_time + punctuality.late;
+    if ran > 0 {
+        punctuality.average_delay = total_delay as f64 / ran as f64 / 60_000_000_000.0;
+    }
+    if punctuality.scheduled > 0 {
+        punctuality.on_time_rate = punctuality.on_time as f64 / punctuality.scheduled as f64;
+    }
+
+    Ok(punctuality)
+}
+
+#[ic_cdk::query]
+fn get_schedule(schedule_id: u64) -> Result<Schedule, Message> {
+    let schedule = SCHEDULES
+        .with(|schedules| schedules.borrow().get(&schedule_id))
+        .ok_or(Message::NotFound("Schedule not found".to_string()))?;
+    tenant_matatu(schedule.matatu_id)?;
+
+    Ok(schedule)
+}
+
+// Real-time Tracking System
+#[ic_cdk::update]
+fn update_location(payload: LocationUpdatePayload) -> Result<LocationUpdate, Message> {
+    if !geo::is_valid_coordinate(payload.latitude, payload.longitude) {
+        return Err(Message::InvalidPayload("Invalid coordinates".to_string()));
+    }
+
+    let matatu_exists = MATATUS.with(|matatus| matatus.borrow().contains_key(&payload.matatu_id));
+    if !matatu_exists {
+        return Err(Message::NotFound("Matatu not found".to_string()));
+    }
+
+    authorize_ping(caller(), payload.matatu_id).map_err(Message::Error)?;
+
+    let (mut result, mut stored) = ingest_locations(vec![payload]);
+    if let Some(rejected) = result.rejected.pop() {
+        return Err(Message::InvalidPayload(rejected.reason));
+    }
+
+    let location = stored
+        .pop()
+        .ok_or(Message::Error("Duplicate location update".to_string()))?;
+    audit(
+        "update_location",
+        "location",
+        location.matatu_id,
+        None,
+        Some(&location),
+    );
+
+    Ok(location)
+}
+
+// Batch of pings from a telematics gateway
+#[ic_cdk::update]
+fn update_locations_batch(
+    payloads: Vec<LocationUpdatePayload>,
+) -> Result<LocationBatchResult, Message> {
+    if payloads.is_empty() || payloads.len() > MAX_LOCATION_BATCH {
+        return Err(Message::InvalidPayload(format!(
+            "Batch must contain between 1 and {} locations",
+            MAX_LOCATION_BATCH
+        )));
+    }
+
+    let result = ingest_locations(payloads).0;
+    audit("update_locations_batch", "location", 0, None, Some(&result));
+
+    Ok(result)
+}
+
+// Telematics Device Registry
+#[ic_cdk::update]
+fn register_device(payload: RegisterDevicePayload) -> Result<Device, Message> {
+    if payload.label.is_empty() {
+        return Err(Message::InvalidPayload(
+            "Missing required fields".to_string(),
+        ));
+    }
+
+    // Only SACCO admins bind trackers, a bound device can post the matatu's position
+    let matatu = MATATUS
+        .with(|matatus| matatus.borrow().get(&payload.matatu_id))
+        .ok_or(Message::NotFound("Matatu not found".to_string()))?;
+    authorize_sacco_admin(matatu.sacco_id)?;
+
+    let conflict = DEVICES.with(|devices| {
+        devices.borrow().iter().any(|(_, d)| {
+            d.status == "active"
+                && (d.matatu_id == payload.matatu_id || d.principal == payload.principal)
+        })
+    });
+    if conflict {
+        return Err(Message::Error(
+            "Matatu or device already has an active binding".to_string(),
+        ));
+    }
+
+    let device = Device {
+        id: next_id(Sequence::Device),
+        sacco_id: matatu.sacco_id,
+        matatu_id: matatu.id,
+        principal: payload.principal,
+        label: payload.label,
+        status: "active".to_string(),
+        last_nonce: 0,
+        last_seen: None,
+        registered_at: time(),
+    };
+
+    DEVICES.with(|devices| devices.borrow_mut().insert(device.id, device.clone()));
+    audit("register_device", "device", device.id, None, Some(&device));
+
+    Ok(device)
+}
+
+#[ic_cdk::update]
+fn revoke_device(device_id: u64) -> Result<Device, Message> {
+    let (before, device) = DEVICES.with(|devices| {
+        let mut devices_map = devices.borrow_mut();
+        if let Some(mut device) = devices_map.get(&device_id) {
+            authorize_sacco_admin(device.sacco_id)?;
+            device.status = "revoked".to_string();
+            let before = devices_map.insert(device_id, device.clone());
+            Ok((before, device))
+        } else {
+            Err(Message::NotFound("Device not found".to_string()))
+        }
+    })?;
+    audit(
+        "revoke_device",
+        "device",
+        device_id,
+        before.as_ref(),
+        Some(&device),
+    );
+
+    Ok(device)
+}
+
+// Last contact of every active device of a SACCO, to spot dead trackers
+#[ic_cdk::query]
+fn get_device_heartbeats(
+    sacco_id: u64,
+    offline_after: Option<u64>,
+) -> Result<Vec<DeviceHeartbeat>, Message> {
+    authorize_sacco(sacco_id)?;
+
+    let now = time();
+    let offline_after = offline_after.unwrap_or(DEFAULT_DEVICE_OFFLINE_AFTER);
+    let devices: Vec<Device> = DEVICES.with(|devices| {
+        devices
+            .borrow()
+            .iter()
+            .filter(|(_, d)| d.sacco_id == sacco_id && d.status == "active")
+            .map(|(_, d)| d.clone())
+            .collect()
+    });
+
+    Ok(devices
+        .into_iter()
+        .map(|device| DeviceHeartbeat {
+            device_id: device.id,
+            matatu_id: device.matatu_id,
+            plate_number: MATATUS
+                .with(|matatus| matatus.borrow().get(&device.matatu_id))
+                .map(|m| m.plate_number)
+                .unwrap_or_default(),
+            label: device.label,
+            last_seen: device.last_seen,
+            offline: match device.last_seen {
+                Some(seen) => now.saturating_sub(seen) > offline_after,
+                None => true,
+            },
+        })
+        .collect())
+}
+
+// Location History
+#[ic_cdk::update]
+fn set_location_retention(payload: LocationRetentionPayload) -> Result<LocationRetention, Message> {
+    // The retention policy applies to every SACCO's history
+    require_controller()?;
+
+    if payload.raw_window < MIN_RAW_WINDOW {
+        return Err(Message::InvalidPayload(
+            "Raw window must be at least one hour".to_string(),
+        ));
+    }
+
+    if payload.tolerance <= 0.0 {
+        return Err(Message::InvalidPayload(
+            "Tolerance must be positive".to_string(),
+        ));
+    }
+
+    let retention = LocationRetention {
+        raw_window: payload.raw_window,
+        tolerance: payload.tolerance,
+        updated_at: time(),
+    };
+
+    let before = LOCATION_RETENTION
+        .with(|cell| cell.borrow_mut().set(retention.clone()))
+        .map_err(|_| Message::Error("Failed to save retention policy".to_string()))?;
+    audit(
+        "set_location_retention",
+        "location_retention",
+        0,
+        Some(&before),
+        Some(&retention),
+    );
+
+    Ok(retention)
+}
+
+#[ic_cdk::query]
+fn get_location_retention() -> LocationRetention {
+    LOCATION_RETENTION.with(|cell| cell.borrow().get().clone())
+}
+
+// Compress aged history for every matatu, returns the number of points removed
+#[ic_cdk::update]
+fn compact_location_history() -> u64 {
+    let now = time();
+    let matatu_ids: Vec<u64> =
+        MATATUS.with(|matatus| matatus.borrow().iter().map(|(id, _)| id).collect());
+
+    let removed = matatu_ids
+        .into_iter()
+        .map(|matatu_id| compact_matatu_history(matatu_id, now))
+        .sum();
+    audit(
+        "compact_location_history",
+        "location",
+        0,
+        None,
+        Some(&removed),
+    );
+
+    removed
+}
+
+#[ic_cdk::query]
+fn get_location_history(
+    matatu_id: u64,
+    start_time: u64,
+    end_time: u64,
+) -> Result<Vec<LocationUpdate>, Message> {
+    tenant_matatu(matatu_id)?;
+
+    Ok(location_history(matatu_id, start_time, end_time))
+}
+
+// Trip trajectory as "geojson" or "polyline"
+#[ic_cdk::query]
+fn get_trip_trajectory(trip_id: u64, format: String) -> Result<String, Message> {
+    let trip = TRIPS
+        .with(|trips| trips.borrow().get(&trip_id))
+        .ok_or(Message::NotFound("Trip not found".to_string()))?;
+    tenant_matatu(trip.matatu_id)?;
+    let points = location_history(
+        trip.matatu_id,
+        trip.start_time,
+        trip.end_time.unwrap_or_else(time),
+    );
+
+    match format.as_str() {
+        "polyline" => {
+            let line: Vec<(f64, f64)> = points.iter().map(|p| (p.latitude, p.longitude)).collect();
+            Ok(geo::encode_polyline(&line))
+        }
+        "geojson" => {
+            let feature = serde_json::json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "LineString",
+                    "coordinates": points
+                        .iter()
+                        .map(|p| vec![p.longitude, p.latitude])
+                        .collect::<Vec<_>>(),
+                },
+                "properties": {
+                    "trip_id": trip.id,
+                    "matatu_id": trip.matatu_id,
+                    "timestamps": points.iter().map(|p| p.timestamp).collect::<Vec<_>>(),
+                },
+            });
+            Ok(feature.to_string())
+        }
+        _ => Err(Message::InvalidPayload(
+            "Format must be geojson or polyline".to_string(),
+        )),
+    }
+}
+
+// Moves sequences that are behind the stored ids forward, then leaves the
+// data migrations to batches on a timer so no single call walks whole maps
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    for sequence in Sequence::ALL {
+        let highest = sequence_last_key(sequence);
+        ID_SEQUENCES.with(|sequences| {
+            let mut sequences = sequences.borrow_mut();
+            let last = sequences.get(&(sequence as u8));
+            if last.is_some_and(|last| last < highest) {
+                sequences.insert(sequence as u8, highest);
+            }
+        });
+    }
+
+    UPGRADE_MIGRATION
+        .with(|cell| {
+            cell.borrow_mut().set(UpgradeMigration {
+                started_at: time(),
+                ..Default::default()
+            })
+        })
+        .expect("Cannot reset the upgrade migration");
+    start_timers();
+}
+
+#[ic_cdk::init]
+fn init() {
+    start_timers();
+}
+
+// Marks overdue schedules as missed and refreshes route traffic patterns on
+// interval timers, which don't survive upgrades and are set again after one,
+// and resumes the upgrade migration when it hasn't finished
+fn start_timers() {
+    if UPGRADE_MIGRATION.with(|cell| cell.borrow().get().finished_at.is_none()) {
+        ic_cdk_timers::set_timer(Duration::ZERO, run_upgrade_migration);
+    }
+    ic_cdk_timers::set_timer_interval(Duration::from_nanos(SCHEDULE_SWEEP_INTERVAL), || {
+        mark_missed_schedules(time());
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_nanos(TRAFFIC_LEARNING_INTERVAL), || {
+        relearn_traffic_patterns();
+    });
+}
+
+// Audit Trail
+// Entries matching the query, newest first. SACCO admins see their SACCO's
+// entries, controllers can query the whole trail.
+#[ic_cdk::query]
+fn get_audit_log(query: AuditQuery) -> Result<AuditPage, Message> {
+    match query.sacco_id {
+        Some(sacco_id) => authorize_sacco_admin(sacco_id)?,
+        None => require_controller()?,
+    }
+
+    let limit = query
+        .limit
+        .unwrap_or(AUDIT_PAGE_SIZE)
+        .clamp(1, AUDIT_PAGE_SIZE) as usize;
+    let start_time = query.start_time.unwrap_or(0);
+    let end_time = query.end_time.unwrap_or(u64::MAX);
+    let oldest = AUDIT_LOG.with(|log| log.borrow().iter().next().map(|(id, _)| id));
+    let oldest = match oldest {
+        Some(oldest) => oldest,
+        None => return Ok(AuditPage::default()),
+    };
+    let newest = AUDIT_SEQUENCE.with(|sequence| *sequence.borrow().get());
+
+    // Ids run without gaps from the oldest kept entry, and timestamps only grow,
+    // so the scan can walk back by id and stop once it is past the start time
+    let mut page = AuditPage::default();
+    let mut id = query.cursor.unwrap_or(newest + 1).min(newest + 1);
+    let mut scanned = 0;
+    while id > oldest {
+        if page.entries.len() == limit || scanned == MAX_AUDIT_SCAN {
+            page.next_cursor = Some(id);
+            break;
+        }
+        id -= 1;
+        scanned += 1;
+
+        let entry = match AUDIT_LOG.with(|log| log.borrow().get(&id)) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        if entry.timestamp < start_time {
+            break;
+        }
+        let matches = entry.timestamp <= end_time
+            && (query.sacco_id.is_none() || query.sacco_id == entry.sacco_id)
+            && (query.entity_type.is_none()
+                || query.entity_type.as_ref() == Some(&entry.entity_type))
+            && (query.entity_id.is_none() || query.entity_id == Some(entry.entity_id))
+            && (query.caller.is_none() || query.caller == entry.caller);
+        if matches {
+            page.entries.push(entry);
+        }
+    }
+
+    Ok(page)
+}
+
+#[ic_cdk::update]
+fn set_audit_retention(payload: AuditRetentionPayload) -> Result<AuditRetention, Message> {
+    require_controller()?;
+
+    if payload.max_entries == 0 || payload.max_age < DAY_NANOS {
+        return Err(Message::InvalidPayload(
+            "Keep at least one entry and at least a day of history".to_string(),
+        ));
+    }
+
+    let retention = AuditRetention {
+        max_entries: payload.max_entries,
+        max_age: payload.max_age,
+        updated_at: time(),
+    };
+
+    let before = AUDIT_RETENTION
+        .with(|cell| cell.borrow_mut().set(retention.clone()))
+        .map_err(|_| Message::Error("Failed to save retention policy".to_string()))?;
+    audit(
+        "set_audit_retention",
+        "audit_retention",
+        0,
+        Some(&before),
+        Some(&retention),
+    );
+
+    Ok(retention)
+}
+
+#[ic_cdk::query]
+fn get_audit_retention() -> AuditRetention {
+    AUDIT_RETENTION.with(|cell| cell.borrow().get().clone())
+}
+
+// How far the data migrations of the last upgrade have got
+#[ic_cdk::query]
+fn get_upgrade_migration() -> Result<UpgradeMigration, Message> {
+    require_controller()?;
+    Ok(UPGRADE_MIGRATION.with(|cell| cell.borrow().get().clone()))
+}
+
+// Geofencing System
+#[ic_cdk::update]
+fn create_geofence(payload: CreateGeofencePayload) -> Result<Geofence, Message> {
+    if payload.name.is_empty() || payload.radius <= 0.0 {
+        return Err(Message::InvalidPayload(
+            "Missing required fields".to_string(),
+        ));
+    }
+
+    if !["depot", "restricted", "speed_zone"].contains(&payload.zone_type.as_str()) {
+        return Err(Message::InvalidPayload(
+            "Zone type must be depot, restricted or speed_zone".to_string(),
+        ));
+    }
+
+    if payload.zone_type == "speed_zone" && payload.speed_limit.is_none() {
+        return Err(Message::InvalidPayload(
+            "Speed zones need a speed limit".to_string(),
+        ));
+    }
+
+    if payload.speed_limit.is_some_and(|limit| limit <= 0.0) {
+        return Err(Message::InvalidPayload(
+            "Speed limit must be positive".to_string(),
+        ));
+    }
+
+    if !geo::is_valid_coordinate(payload.latitude, payload.longitude) {
+        return Err(Message::InvalidPayload("Invalid coordinates".to_string()));
+    }
+
+    let sacco_exists = SACCOS.with(|saccos| saccos.borrow().contains_key(&payload.sacco_id));
+    if !sacco_exists {
+        return Err(Message::NotFound("SACCO not found".to_string()));
+    }
+    authorize_sacco(payload.sacco_id)?;
+
+    let geofence = Geofence {
+        id: next_id(Sequence::Geofence),
+        sacco_id: payload.sacco_id,
+        name: payload.name,
+        zone_type: payload.zone_type,
+        latitude: payload.latitude,
+        longitude: payload.longitude,
+        radius: payload.radius,
+        speed_limit: payload.speed_limit,
+        created_at: time(),
+    };
+
+    GEOFENCES.with(|geofences| geofences.borrow_mut().insert(geofence.id, geofence.clone()));
+    audit(
+        "create_geofence",
+        "geofence",
+        geofence.id,
+        None,
+        Some(&geofence),
+    );
+
+    Ok(geofence)
+}
+
+#[ic_cdk::query]
+fn get_geofence_events(
+    matatu_id: u64,
+    start_time: u64,
+    end_time: u64,
+) -> Result<Vec<GeofenceEvent>, Message> {
+    tenant_matatu(matatu_id)?;
+
+    Ok(GEOFENCE_EVENTS.with(|events| {
+        events
+            .borrow()
+            .iter()
+            .filter(|(_, e)| {
+                e.matatu_id == matatu_id && e.timestamp >= start_time && e.timestamp <= end_time
+            })
+            .map(|(_, e)| e.clone())
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn get_incidents(sacco_id: u64, status: Option<String>) -> Result<Vec<Incident>, Message> {
+    authorize_sacco(sacco_id)?;
+
+    Ok(INCIDENTS.with(|incidents| {
+        incidents
+            .borrow()
+            .iter()
+            .filter(|(_, i)| {
+                i.sacco_id == sacco_id && (status.is_none() || status.as_ref() == Some(&i.status))
+            })
+            .map(|(_, i)| i.clone())
+            .collect()
+    }))
+}
+
+#[ic_cdk::update]
+fn resolve_incident(incident_id: u64) -> Result<Incident, Message> {
+    let (before, incident) = INCIDENTS.with(|incidents| {
+        let mut incidents_map = incidents.borrow_mut();
+        if let Some(mut incident) = incidents_map.get(&incident_id) {
+            authorize_sacco(incident.sacco_id)?;
+            if incident.status == "resolved" {
+                return Err(Message::Error("Incident already resolved".to_string()));
+            }
+
+            incident.status = "resolved".to_string();
+            incident.resolved_at = Some(time());
+            let before = incidents_map.insert(incident_id, incident.clone());
+            Ok((before, incident))
+        } else {
+            Err(Message::NotFound("Incident not found".to_string()))
+        }
+    })?;
+    audit(
+        "resolve_incident",
+        "incident",
+        incident_id,
+        before.as_ref(),
+        Some(&incident),
+    );
+
+    Ok(incident)
+}
+
+// Speed Monitoring System
+#[ic_cdk::update]
+fn set_speed_policy(payload: SpeedPolicyPayload) -> Result<SpeedPolicy, Message> {
+    if payload.speed_limit <= 0.0 || payload.speed_limit > PSV_SPEED_LIMIT {
+        return Err(Message::InvalidPayload(format!(
+            "Speed limit must be between 0 and {} km/h",
+            PSV_SPEED_LIMIT
+        )));
+    }
+
+    let sacco_exists = SACCOS.with(|saccos| saccos.borrow().contains_key(&payload.sacco_id));
+    if !sacco_exists {
+        return Err(Message::NotFound("SACCO not found".to_string()));
+    }
+    authorize_sacco_admin(payload.sacco_id)?;
+
+    let policy = SpeedPolicy {
+        sacco_id: payload.sacco_id,
+        speed_limit: payload.speed_limit,
+        sustained_pings: payload.sustained_pings.max(1),
+        updated_at: time(),
+    };
+
+    let before = SPEED_POLICIES.with(|policies| {
+        policies
+            .borrow_mut()
+            .insert(policy.sacco_id, policy.clone())
+    });
+    audit(
+        "set_speed_policy",
+        "speed_policy",
+        policy.sacco_id,
+        before.as_ref(),
+        Some(&policy),
+    );
+
+    Ok(policy)
+}
+
+#[ic_cdk::query]
+fn get_speed_policy(sacco_id: u64) -> Result<SpeedPolicy, Message> {
+    authorize_sacco(sacco_id)?;
+
+    Ok(speed_policy_for(sacco_id))
+}
+
+#[ic_cdk::query]
+fn get_speed_violations(
+    sacco_id: u64,
+    driver_id: Option<u64>,
+) -> Result<Vec<SpeedViolation>, Message> {
+    authorize_sacco(sacco_id)?;
+
+    Ok(SPEED_VIOLATIONS.with(|violations| {
+        violations
+            .borrow()
+            .iter()
+            .filter(|(_, v)| {
+                v.sacco_id == sacco_id && (driver_id.is_none() || v.driver_id == driver_id)
+            })
+            .map(|(_, v)| v.clone())
+            .collect()
+    }))
+}
+
+// Financial Reporting System
+#[ic_cdk::query]
+fn generate_financial_report(
+    sacco_id: u64,
+    start_time: u64,
+    end_time: u64,
+) -> Result<FinancialReport, Message> {
+    authorize_sacco(sacco_id)?;
+
+    let revenues = calculate_total_revenues(sacco_id, start_time, end_time);
+    let expenses = calculate_total_expenses(sacco_id, start_time, end_time);
+
+    let revenue_breakdown = analyze_revenue_sources(sacco_id, start_time, end_time);
+    let expense_breakdown = analyze_expense_categories(sacco_id, start_time, end_time);
+
+    let profit_margin = if revenues > 0.0 {
+        ((revenues - expenses) / revenues) * 100.0
+    } else {
+        0.0
+    };
+
+    let report = FinancialReport {
+        id: next_id(Sequence::FinancialReport),
+        sacco_id,
+        period_start: start_time,
+        period_end: end_time,
+        total_revenue: revenues,
+        total_expenses: expenses,
+        expense_breakdown,
+        revenue_breakdown,
+        profit_margin,
+        created_at: time(),
+    };
+
+    FINANCIAL_REPORTS.with(|reports| reports.borrow_mut().insert(report.id, report.clone()));
+
+    Ok(report)
+}
+
+// Data Export
+// Records of a SACCO dated within a period, a chunk at a time in id order
+#[ic_cdk::query]
+fn export_data(payload: ExportPayload) -> Result<ExportChunk, Message> {
+    authorize_sacco(payload.sacco_id)?;
+
+    if !["csv", "json"].contains(&payload.format.as_str()) {
+        return Err(Message::InvalidPayload(
+            "Format must be csv or json".to_string(),
+        ));
+    }
+
+    if payload.end_time < payload.start_time {
+        return Err(Message::InvalidPayload(
+            "Period must end after it starts".to_string(),
+        ));
+    }
+
+    // Tokens name the dataset and the id to carry on from
+    let from = match &payload.continuation {
+        Some(token) => token
+            .strip_prefix(&payload.dataset)
+            .and_then(|rest| rest.strip_prefix(':'))
+            .and_then(|id| id.parse().ok())
+            .ok_or(Message::InvalidPayload(
+                "Continuation token is not for this dataset".to_string(),
+            ))?,
+        None => 0,
+    };
+
+    let fleet: BTreeSet<u64> = MATATUS.with(|matatus| {
+        matatus
+            .borrow()
+            .iter()
+            .filter(|(_, m)| m.sacco_id == payload.sacco_id)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    let in_period = |date: u64| date >= payload.start_time && date <= payload.end_time;
+    let mut export = ExportWriter::new(&payload.format, payload.continuation.is_none());
+
+    let next = match payload.dataset.as_str() {
+        "trips" => export.rows(
+            &TRIPS,
+            from,
+            &[
+                "id",
+                "matatu_id",
+                "driver_id",
+                "route",
+                "start_time",
+                "end_time",
+                "status",
+                "passengers",
+                "revenue",
+            ],
+            |t| fleet.contains(&t.matatu_id) && in_period(t.start_time),
+            |t| {
+                Ok(vec![
+                    t.id.into(),
+                    t.matatu_id.into(),
+                    t.driver_id.into(),
+                    t.route.clone().into(),
+                    export_time(t.start_time).into(),
+                    t.end_time.map(export_time).into(),
+                    t.status.clone().into(),
+                    t.passengers.into(),
+                    export_number(t.revenue)?,
+                ])
+            },
+        ),
+        "revenues" => export.rows(
+            &REVENUES,
+            from,
+            &["id", "date", "matatu_id", "amount", "description"],
+            |r| r.sacco_id == payload.sacco_id && in_period(r.date),
+            |r| {
+                Ok(vec![
+                    r.id.into(),
+                    export_time(r.date).into(),
+                    r.matatu_id.into(),
+                    export_number(r.amount)?,
+                    r.description.clone().into(),
+                ])
+            },
+        ),
+        "expenses" => export.rows(
+            &EXPENSES,
+            from,
+            &["id", "date", "category", "amount", "description"],
+            |e| e.sacco_id == payload.sacco_id && in_period(e.date),
+            |e| {
+                Ok(vec![
+                    e.id.into(),
+                    export_time(e.date).into(),
+                    e.category.clone().into(),
+                    export_number(e.amount)?,
+                    e.description.clone().into(),
+                ])
+            },
+        ),
+        "fuel" => export.rows(
+            &FUEL_RECORDS,
+            from,
+            &[
+                "id",
+                "matatu_id",
+                "date",
+                "liters",
+                "cost",
+                "odometer_reading",
+            ],
+            |f| fleet.contains(&f.matatu_id) && in_period(f.date),
+            |f| {
+                Ok(vec![
+                    f.id.into(),
+                    f.matatu_id.into(),
+                    export_time(f.date).into(),
+                    export_number(f.liters)?,
+                    export_number(f.cost)?,
+                    f.odometer_reading.into(),
+                ])
+            },
+        ),
+        "maintenance" => export.rows(
+            &MAINTENANCE_RECORDS,
+            from,
+            &[
+                "id",
+                "matatu_id",
+                "date",
+                "description",
+                "cost",
+                "downtime_hours",
+                "status",
+            ],
+            |m| fleet.contains(&m.matatu_id) && in_period(m.date),
+            |m| {
+                Ok(vec![
+                    m.id.into(),
+                    m.matatu_id.into(),
+                    export_time(m.date).into(),
+                    m.description.clone().into(),
+                    export_number(m.cost)?,
+                    m.downtime_hours.into(),
+                    m.status.clone().into(),
+                ])
+            },
+        ),
+        _ => {
+            return Err(Message::InvalidPayload(
+                "Dataset must be trips, revenues, expenses, fuel or maintenance".to_string(),
+            ))
+        }
+    }?;
+
+    Ok(ExportChunk {
+        continuation: next.map(|id| format!("{}:{}", payload.dataset, id)),
+        dataset: payload.dataset,
+        format: payload.format,
+        rows: export.count,
+        data: export.finish(),
+    })
+}
+
+// Builds one export chunk, stopping before it outgrows the reply size limit
+struct ExportWriter {
+    csv: bool,
+    data: String,
+    count: u32,
+    header: bool, // only the first chunk of a CSV export has the header
+}
+
+impl ExportWriter {
+    fn new(format: &str, first_chunk: bool) -> Self {
+        let csv = format == "csv";
+        ExportWriter {
+            csv,
+            data: if csv { String::new() } else { "[".to_string() },
+            count: 0,
+            header: csv && first_chunk,
+        }
+    }
+
+    // Adds the matching records from id `from` on, returns the id to carry on from
+    // when the chunk is full. CSV rows and JSON objects hold the same columns.
+    fn rows<T: BoundedStorable + Clone>(
+        &mut self,
+        map: &'static std::thread::LocalKey<RefCell<StableBTreeMap<u64, T, Memory>>>,
+        from: u64,
+        headers: &[&str],
+        keep: impl Fn(&T) -> bool,
+        columns: impl Fn(&T) -> Result<Vec<serde_json::Value>, String>,
+    ) -> Result<Option<u64>, Message> {
+        if self.header {
+            self.data.push_str(&headers.join(","));
+            self.data.push('\n');
+        }
+
+        map.with(|map| {
+            for (scanned, (id, record)) in map.borrow().range(from..).enumerate() {
+                if scanned == MAX_EXPORT_SCAN {
+                    return Ok(Some(id));
+                }
+                if !keep(&record) {
+                    continue;
+                }
+
+                let unexportable = |reason: String| {
+                    Message::Error(format!("Record {} can't be exported: {}", id, reason))
+                };
+                let values = columns(&record).map_err(unexportable)?;
+                let row = if self.csv {
+                    let fields: Vec<String> = values
+                        .iter()
+                        .map(|value| match value {
+                            serde_json::Value::Null => String::new(),
+                            serde_json::Value::String(text) => csv_field(text),
+                            other => other.to_string(),
+                        })
+                        .collect();
+                    fields.join(",") + "\n"
+                } else {
+                    // Written field by field so keys keep the column order
+                    let mut fields = Vec::with_capacity(values.len());
+                    for (header, value) in headers.iter().zip(&values) {
+                        let key = serde_json::to_string(header)
+                            .map_err(|e| unexportable(e.to_string()))?;
+                        let value = serde_json::to_string(value)
+                            .map_err(|e| unexportable(e.to_string()))?;
+                        fields.push(format!("{}:{}", key, value));
+                    }
+                    let separator = if self.count > 0 { "," } else { "" };
+                    format!("{}{{{}}}", separator, fields.join(","))
+                };
+                if self.count > 0 && self.data.len() + row.len() > MAX_EXPORT_BYTES {
+                    return Ok(Some(id));
+                }
+
+                self.data.push_str(&row);
+                self.count += 1;
+            }
+            Ok(None)
+        })
+    }
+
+    fn finish(mut self) -> String {
+        if !self.csv {
+            self.data.push(']');
+        }
+        self.data
+    }
+}
+
+// Helper function to quote a CSV field when it needs it
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// Helper function to export an amount, which JSON can't hold unless it is finite
+fn export_number(value: f64) -> Result<serde_json::Value, String> {
+    serde_json::Number::from_f64(value)
+        .map(serde_json::Value::Number)
+        .ok_or(format!("{} is not a number", value))
+}
+
+// Helper function to write a timestamp the way spreadsheets read it, in local time
+fn export_time(timestamp: u64) -> String {
+    calendar::to_local(timestamp)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+// Bulk Import
+// Each row goes through the same checks as the endpoint that creates its record.
+// In best-effort mode the valid rows are kept and the others reported. In
+// all-or-nothing mode nothing is kept unless every row passes.
+#[ic_cdk::update]
+fn import_records(payload: ImportPayload) -> Result<ImportReport, Message> {
+    run_import(payload)
+}
+
+// Runs an import without keeping anything, to see which rows would fail
+#[ic_cdk::query]
+fn preview_import(payload: ImportPayload) -> Result<ImportReport, Message> {
+    run_import(payload)
+}
+
+// Helper function to import every row of a batch, collecting the errors.
+// In all_or_nothing mode every row is checked first and nothing is saved
+// unless they all pass.
+fn run_import(payload: ImportPayload) -> Result<ImportReport, Message> {
+    if !["all_or_nothing", "best_effort"].contains(&payload.mode.as_str()) {
+        return Err(Message::InvalidPayload(
+            "Mode must be all_or_nothing or best_effort".to_string(),
+        ));
+    }
+
+    let typed_columns = import_typed_columns(&payload.entity).ok_or(Message::InvalidPayload(
+        "Entity must be saccos, matatus, drivers, routes or trips".to_string(),
+    ))?;
+
+    let rows: Vec<Result<serde_json::Value, String>> = match payload.format.as_str() {
+        "csv" => parse_csv(&payload.data)
+            .map_err(Message::InvalidPayload)?
+            .into_iter()
+            .map(|row| row.map(|row| csv_row_to_json(row, typed_columns)))
+            .collect(),
+        "json" => serde_json::from_str::<Vec<serde_json::Value>>(&payload.data)
+            .map_err(|e| Message::InvalidPayload(format!("Invalid JSON: {}", e)))?
+            .into_iter()
+            .map(Ok)
+            .collect(),
+        _ => {
+            return Err(Message::InvalidPayload(
+                "Format must be csv or json".to_string(),
+            ))
+        }
+    };
+
+    if rows.is_empty() || rows.len() > MAX_IMPORT_ROWS {
+        return Err(Message::InvalidPayload(format!(
+            "Import must contain between 1 and {} rows",
+            MAX_IMPORT_ROWS
+        )));
+    }
+
+    let mut report = ImportReport {
+        entity: payload.entity,
+        mode: payload.mode,
+        rows: rows.len() as u32,
+        ..Default::default()
+    };
+
+    if report.mode == "all_or_nothing" {
+        // Rows must also not clash with each other, as they would once saved
+        let mut keys = BTreeSet::new();
+        let mut trips: Vec<HistoricalTripPayload> = Vec::new();
+        for (index, row) in rows.iter().enumerate() {
+            let checked = row
+                .clone()
+                .map_err(Message::InvalidPayload)
+                .and_then(|row| check_import_row(&report.entity, row));
+            let error = match checked {
+                Ok(Some(key)) if !keys.insert(key.clone()) => {
+                    Message::Error(format!("Same {} as an earlier row", key))
+                }
+                Ok(_) => {
+                    let trip = row
+                        .as_ref()
+                        .ok()
+                        .filter(|_| report.entity == "trips")
+                        .and_then(|row| import_payload::<HistoricalTripPayload>(row.clone()).ok());
+                    match trip {
+                        Some(trip)
+                            if trips.iter().any(|earlier| {
+                                overlaps_historical_trip(
+                                    &trip,
+                                    earlier.matatu_id,
+                                    earlier.driver_id,
+                                    earlier.start_time,
+                                    earlier.end_time,
+                                )
+                            }) =>
+                        {
+                            Message::Error(
+                                "Trip overlaps an earlier row's trip of the same matatu or driver"
+                                    .to_string(),
+                            )
+                        }
+                        Some(trip) => {
+                            trips.push(trip);
+                            continue;
+                        }
+                        None => continue,
+                    }
+                }
+                Err(message) => message,
+            };
+            report.errors.push(ImportError {
+                row: index as u32 + 1,
+                message: message_text(error),
+            });
+        }
+        if !report.errors.is_empty() {
+            return Ok(report);
+        }
+    }
+
+    for (index, row) in rows.into_iter().enumerate() {
+        let imported = row
+            .map_err(Message::InvalidPayload)
+            .and_then(|row| import_row(&report.entity, row));
+        match imported {
+            Ok(id) => {
+                report.imported += 1;
+                report.ids.push(id);
+            }
+            Err(message) => report.errors.push(ImportError {
+                row: index as u32 + 1,
+                message: message_text(message),
+            }),
+        }
+    }
+
+    Ok(report)
+}
+
+// Helper function to read an import row as the payload record of its entity
+fn import_payload<T: serde::de::DeserializeOwned>(row: serde_json::Value) -> Result<T, Message> {
+    serde_json::from_value(row).map_err(|e| Message::InvalidPayload(e.to_string()))
+}
 
-// Implementing Storable for Driver
-impl Storable for Driver {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+// Helper function to check one import row without saving it, giving the
+// value no two rows may share, if the entity has one
+fn check_import_row(entity: &str, row: serde_json::Value) -> Result<Option<String>, Message> {
+    match entity {
+        "saccos" => check_new_sacco(&import_payload(row)?)
+            .map(|(_, code)| code.map(|code| format!("SACCO code {}", code))),
+        "matatus" => check_new_matatu(&import_payload(row)?)
+            .map(|(_, plate)| Some(format!("plate number {}", plate))),
+        "drivers" => check_new_driver(&import_payload(row)?).map(|_| None),
+        "routes" => {
+            let route: CreateRoutePayload = import_payload(row)?;
+            check_new_route(&route)
+                .map(|sacco| Some(format!("route {} in SACCO {}", route.name, sacco.id)))
+        }
+        "trips" => check_historical_trip(&import_payload(row)?).map(|_| None),
+        _ => Err(Message::InvalidPayload("Unknown entity".to_string())),
+    }
+}
+
+// Helper function to create the record of one import row
+fn import_row(entity: &str, row: serde_json::Value) -> Result<u64, Message> {
+    match entity {
+        "saccos" => create_sacco(import_payload(row)?).map(|s| s.id),
+        "matatus" => register_matatu(import_payload(row)?).map(|m| m.id),
+        "drivers" => register_driver(import_payload(row)?).map(|d| d.id),
+        "routes" => create_route(import_payload(row)?).map(|r| r.id),
+        "trips" => import_trip(import_payload(row)?).map(|t| t.id),
+        _ => Err(Message::InvalidPayload("Unknown entity".to_string())),
+    }
+}
+
+// Helper function to record a completed trip from before onboarding
+fn import_trip(payload: HistoricalTripPayload) -> Result<Trip, Message> {
+    let (matatu, driver) = check_historical_trip(&payload)?;
+
+    let trip = Trip {
+        id: next_id(Sequence::Trip),
+        matatu_id: matatu.id,
+        driver_id: driver.id,
+        start_time: payload.start_time,
+        end_time: Some(payload.end_time),
+        passengers: payload.passengers,
+        route: payload.route,
+        status: "completed".to_string(),
+        revenue: payload.revenue,
+        schedule_id: None,
+    };
+
+    TRIPS.with(|trips| trips.borrow_mut().insert(trip.id, trip.clone()));
+    audit("import_records", "trip", trip.id, None, Some(&trip));
+
+    Ok(trip)
+}
+
+// Helper function to check a historical trip without recording it, giving its
+// matatu and driver. Only SACCO admins can record trips, and a matatu or driver
+// can't have been on two trips at once.
+fn check_historical_trip(payload: &HistoricalTripPayload) -> Result<(Matatu, Driver), Message> {
+    if payload.end_time <= payload.start_time || payload.end_time > time() {
+        return Err(Message::InvalidPayload(
+            "Trip must end after it starts and before now".to_string(),
+        ));
+    }
+
+    if payload.revenue.is_nan() || payload.revenue < 0.0 {
+        return Err(Message::InvalidPayload(
+            "Revenue cannot be negative".to_string(),
+        ));
+    }
+
+    let matatu = tenant_matatu(payload.matatu_id)?;
+    let driver = tenant_driver(payload.driver_id)?;
+    if matatu.sacco_id != driver.sacco_id {
+        return Err(Message::InvalidPayload(
+            "Driver and matatu belong to different SACCOs".to_string(),
+        ));
+    }
+    authorize_sacco_admin(matatu.sacco_id)?;
+
+    if find_route_by_name(matatu.sacco_id, &payload.route).is_none() {
+        return Err(Message::InvalidPayload(
+            "Route is not one of the SACCO's routes".to_string(),
+        ));
+    }
+
+    // Ongoing trips run until now, cancelled trips never ran
+    let now = time();
+    let overlapping = TRIPS.with(|trips| {
+        trips.borrow().iter().any(|(_, t)| {
+            t.status != "cancelled"
+                && overlaps_historical_trip(
+                    payload,
+                    t.matatu_id,
+                    t.driver_id,
+                    t.start_time,
+                    t.end_time.unwrap_or(now),
+                )
+        })
+    });
+    if overlapping {
+        return Err(Message::Error(
+            "Trip overlaps another trip of the same matatu or driver".to_string(),
+        ));
+    }
+
+    Ok((matatu, driver))
+}
+
+// Helper function to check whether a trip of the given matatu or driver runs
+// during a historical trip
+fn overlaps_historical_trip(
+    payload: &HistoricalTripPayload,
+    matatu_id: u64,
+    driver_id: u64,
+    start_time: u64,
+    end_time: u64,
+) -> bool {
+    (matatu_id == payload.matatu_id || driver_id == payload.driver_id)
+        && start_time < payload.end_time
+        && payload.start_time < end_time
+}
+
+// Helper function to get the CSV columns of an entity that hold numbers or JSON
+// lists rather than text, None for entities that can't be imported
+fn import_typed_columns(entity: &str) -> Option<&'static [&'static str]> {
+    match entity {
+        "saccos" => Some(&[]),
+        "matatus" => Some(&["sacco_id", "capacity"]),
+        "drivers" => Some(&["sacco_id"]),
+        "routes" => Some(&[
+            "sacco_id",
+            "estimated_time",
+            "average_passengers",
+            "price",
+            "corridor_buffer",
+            "stops",
+            "peak_hours",
+        ]),
+        "trips" => Some(&[
+            "matatu_id",
+            "driver_id",
+            "start_time",
+            "end_time",
+            "passengers",
+            "revenue",
+        ]),
+        _ => None,
+    }
+}
+
+// Helper function to turn a CSV row into the JSON form of its payload. Empty
+// cells are left out, so optional fields become None.
+fn csv_row_to_json(row: Vec<(String, String)>, typed_columns: &[&str]) -> serde_json::Value {
+    let fields = row
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(column, value)| {
+            let value = if typed_columns.contains(&column.as_str()) {
+                serde_json::from_str(&value).unwrap_or(serde_json::Value::String(value))
+            } else {
+                serde_json::Value::String(value)
+            };
+            (column, value)
+        })
+        .collect();
+
+    serde_json::Value::Object(fields)
+}
+
+// Helper function to read CSV with a header row into (column, value) pairs per row.
+// A row with the wrong number of fields is an error for that row alone.
+fn parse_csv(data: &str) -> Result<Vec<Result<CsvRow, String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = data.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted => {}
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("Unterminated quoted field".to_string());
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    // Blank lines are skipped
+    records.retain(|r| !(r.len() == 1 && r[0].trim().is_empty()));
+    let mut records = records.into_iter();
+    let header: Vec<String> = records
+        .next()
+        .ok_or("CSV needs a header row")?
+        .into_iter()
+        .map(|h| h.trim().to_string())
+        .collect();
+
+    Ok(records
+        .map(|values| {
+            if values.len() != header.len() {
+                return Err(format!(
+                    "Row has {} fields, the header has {}",
+                    values.len(),
+                    header.len()
+                ));
+            }
+            Ok(header.iter().cloned().zip(values).collect())
+        })
+        .collect())
+}
+
+// Helper function to get the text of an endpoint's message
+fn message_text(message: Message) -> String {
+    match message {
+        Message::Success(text)
+        | Message::Error(text)
+        | Message::NotFound(text)
+        | Message::InvalidPayload(text) => text,
+    }
+}
+
+// API Tokens
+// The HTTP gateway forwards requests anonymously, so clients of the JSON API
+// sign in by sending a token issued here to the principal that asked for it.
+#[ic_cdk::update]
+async fn create_api_token(ttl: Option<u64>) -> Result<ApiTokenGrant, Message> {
+    let principal = caller();
+    if principal == Principal::anonymous() {
+        return Err(Message::Error(
+            "The anonymous principal cannot hold a token".to_string(),
+        ));
+    }
+    let ttl = ttl.unwrap_or(DEFAULT_API_TOKEN_TTL);
+    if ttl == 0 || ttl > MAX_API_TOKEN_TTL {
+        return Err(Message::InvalidPayload(format!(
+            "A token can last at most {} days",
+            MAX_API_TOKEN_TTL / DAY_NANOS
+        )));
+    }
+
+    let (random,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, reason)| {
+            Message::Error(format!("No randomness for the token: {}", reason))
+        })?;
+    let secret: String = random.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let now = time();
+    let token = ApiToken {
+        id: next_id(Sequence::ApiToken),
+        principal,
+        secret_hash: secret_hash(&secret),
+        created_at: now,
+        expires_at: now + ttl,
+        status: "active".to_string(),
+    };
+
+    API_TOKENS.with(|tokens| tokens.borrow_mut().insert(token.id, token.clone()));
+    let info = api_token_info(&token);
+    audit("create_api_token", "api_token", token.id, None, Some(&info));
+
+    Ok(ApiTokenGrant {
+        token: format!("{}.{}", token.id, secret),
+        info,
+    })
+}
+
+// Tokens issued to the caller
+#[ic_cdk::query]
+fn get_api_tokens() -> Vec<ApiTokenInfo> {
+    let principal = caller();
+    API_TOKENS.with(|tokens| {
+        tokens
+            .borrow()
+            .iter()
+            .filter(|(_, t)| t.principal == principal)
+            .map(|(_, t)| api_token_info(&t))
+            .collect()
+    })
+}
+
+// Revoke one of the caller's tokens, or any token for a controller
+#[ic_cdk::update]
+fn revoke_api_token(token_id: u64) -> Result<ApiTokenInfo, Message> {
+    let mut token = API_TOKENS
+        .with(|tokens| tokens.borrow().get(&token_id))
+        .ok_or(Message::NotFound("Token not found".to_string()))?;
+    if token.principal != caller() {
+        require_controller()?;
+    }
+
+    let before = api_token_info(&token);
+    token.status = "revoked".to_string();
+    API_TOKENS.with(|tokens| tokens.borrow_mut().insert(token_id, token.clone()));
+    let info = api_token_info(&token);
+    audit(
+        "revoke_api_token",
+        "api_token",
+        token_id,
+        Some(&before),
+        Some(&info),
+    );
+
+    Ok(info)
+}
+
+// Helper function to describe a token without its secret
+fn api_token_info(token: &ApiToken) -> ApiTokenInfo {
+    ApiTokenInfo {
+        id: token.id,
+        principal: token.principal,
+        created_at: token.created_at,
+        expires_at: token.expires_at,
+        status: token.status.clone(),
+    }
+}
+
+// HTTP Gateway
+// A JSON API over the same endpoints, for clients that don't speak Candid.
+// Every route calls the Candid endpoint it mirrors, so auth and validation are shared.
+// Requests carrying an API token act as its holder, others are anonymous.
+struct HttpRoute {
+    method: &'static str,
+    path: &'static str,
+    query: &'static [(&'static str, &'static str, bool)], // name, JSON schema type, required
+    body: Option<fn() -> candid::types::Type>,            // Candid record the JSON body follows
+    response: Option<fn() -> candid::types::Type>,        // Candid type of a successful reply
+    summary: &'static str,
+}
+
+const HTTP_ROUTES: &[HttpRoute] = &[
+    HttpRoute {
+        method: "GET",
+        path: "/openapi.json",
+        query: &[],
+        body: None,
+        response: None,
+        summary: "This document",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/saccos",
+        query: &[("include_archived", "boolean", false)],
+        body: None,
+        response: Some(<Vec<SACCO>>::ty),
+        summary: "SACCOs the caller belongs to",
+    },
+    HttpRoute {
+        method: "POST",
+        path: "/saccos",
+        query: &[],
+        body: Some(CreateSACCOPayload::ty),
+        response: Some(SACCO::ty),
+        summary: "Create a SACCO with the caller as its first admin",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/saccos/{sacco_id}/members",
+        query: &[],
+        body: None,
+        response: Some(<Vec<SaccoMember>>::ty),
+        summary: "Members of a SACCO",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/saccos/{sacco_id}/matatus",
+        query: &[("include_archived", "boolean", false)],
+        body: None,
+        response: Some(<Vec<Matatu>>::ty),
+        summary: "Matatus of a SACCO",
+    },
+    HttpRoute {
+        method: "POST",
+        path: "/saccos/{sacco_id}/matatus",
+        query: &[],
+        body: Some(RegisterMatatuPayload::ty),
+        response: Some(Matatu::ty),
+        summary: "Register a matatu",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/saccos/{sacco_id}/drivers",
+        query: &[("include_archived", "boolean", false)],
+        body: None,
+        response: Some(<Vec<Driver>>::ty),
+        summary: "Drivers of a SACCO",
+    },
+    HttpRoute {
+        method: "POST",
+        path: "/saccos/{sacco_id}/drivers",
+        query: &[],
+        body: Some(RegisterDriverPayload::ty),
+        response: Some(Driver::ty),
+        summary: "Register a driver",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/saccos/{sacco_id}/routes",
+        query: &[("include_archived", "boolean", false)],
+        body: None,
+        response: Some(<Vec<Route>>::ty),
+        summary: "Routes of a SACCO",
+    },
+    HttpRoute {
+        method: "POST",
+        path: "/saccos/{sacco_id}/routes",
+        query: &[],
+        body: Some(CreateRoutePayload::ty),
+        response: Some(Route::ty),
+        summary: "Create a route from its ordered stops",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/saccos/{sacco_id}/incidents",
+        query: &[("status", "string", false)],
+        body: None,
+        response: Some(<Vec<Incident>>::ty),
+        summary: "Route deviations and restricted zone entries",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/saccos/{sacco_id}/speed-violations",
+        query: &[("driver_id", "integer", false)],
+        body: None,
+        response: Some(<Vec<SpeedViolation>>::ty),
+        summary: "Speed violations, optionally for one driver",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/saccos/{sacco_id}/financial-report",
+        query: &[
+            ("start_time", "integer", true),
+            ("end_time", "integer", true),
+        ],
+        body: None,
+        response: Some(FinancialReport::ty),
+        summary: "Revenue and expenses over a period",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/saccos/{sacco_id}/exports/{dataset}",
+        query: &[
+            ("format", "string", true),
+            ("start_time", "integer", true),
+            ("end_time", "integer", true),
+            ("continuation", "string", false),
+        ],
+        body: None,
+        response: Some(ExportChunk::ty),
+        summary: "A chunk of trips, revenues, expenses, fuel or maintenance records",
+    },
+    HttpRoute {
+        method: "POST",
+        path: "/imports",
+        query: &[],
+        body: Some(ImportPayload::ty),
+        response: Some(ImportReport::ty),
+        summary: "Import a batch of SACCOs, matatus, drivers, routes or trips",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/matatus/{matatu_id}/analytics",
+        query: &[],
+        body: None,
+        response: Some(MatatuAnalytics::ty),
+        summary: "Trips, revenue and costs of a matatu",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/matatus/{matatu_id}/stop-predictions",
+        query: &[],
+        body: None,
+        response: Some(<Vec<StopPrediction>>::ty),
+        summary: "Public: predicted arrival at each remaining stop of a matatu's trip",
+    },
+    HttpRoute {
+        method: "POST",
+        path: "/drivers/{driver_id}/assign/{matatu_id}",
+        query: &[],
+        body: None,
+        response: Some(Driver::ty),
+        summary: "Assign a driver to a matatu",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/drivers/{driver_id}/driving-hours",
+        query: &[],
+        body: None,
+        response: Some(DrivingHours::ty),
+        summary: "A driver's driving time and current rest",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/drivers/{driver_id}/attendance",
+        query: &[("month", "integer", true)],
+        body: None,
+        response: Some(AttendanceReport::ty),
+        summary: "A driver's attendance for a month given as YYYYMM",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/routes/{route_id}/stops",
+        query: &[],
+        body: None,
+        response: Some(<Vec<RouteStop>>::ty),
+        summary: "Public: ordered stops of a route",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/routes/{route_id}/stops/{stop_id}/next-matatus",
+        query: &[],
+        body: None,
+        response: Some(<Vec<NextMatatu>>::ty),
+        summary: "Public: upcoming matatus for a stop",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/routes/{route_id}/fare-rule",
+        query: &[],
+        body: None,
+        response: Some(FareRule::ty),
+        summary: "Public: fare rule of a route",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/routes/{route_id}/fare-quote",
+        query: &[
+            ("boarding_stop_id", "integer", false),
+            ("alighting_stop_id", "integer", false),
+            ("category", "string", false),
+            ("time", "integer", false),
+        ],
+        body: None,
+        response: Some(FareQuote::ty),
+        summary: "Public: fare for a ride on a route",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/routes/{route_id}/demand-forecast",
+        query: &[("date", "integer", true)],
+        body: None,
+        response: Some(<Vec<DemandForecast>>::ty),
+        summary: "Hourly passenger forecast of a route for a day",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/schedules/{schedule_id}",
+        query: &[],
+        body: None,
+        response: Some(Schedule::ty),
+        summary: "A scheduled departure",
+    },
+    HttpRoute {
+        method: "POST",
+        path: "/trips",
+        query: &[],
+        body: Some(StartTripPayload::ty),
+        response: Some(Trip::ty),
+        summary: "Start a trip",
+    },
+    HttpRoute {
+        method: "POST",
+        path: "/trips/{trip_id}/end",
+        query: &[],
+        body: Some(EndTripPayload::ty),
+        response: Some(Trip::ty),
+        summary: "End an ongoing trip",
+    },
+    HttpRoute {
+        method: "POST",
+        path: "/trips/{trip_id}/tickets",
+        query: &[],
+        body: Some(IssueTicketPayload::ty),
+        response: Some(Ticket::ty),
+        summary: "Issue a ticket on an ongoing trip",
+    },
+    HttpRoute {
+        method: "GET",
+        path: "/trips/{trip_id}/trajectory",
+        query: &[("format", "string", true)],
+        body: None,
+        response: Some(String::ty),
+        summary: "A trip's path as geojson or polyline",
+    },
+    HttpRoute {
+        method: "POST",
+        path: "/feedback",
+        query: &[],
+        body: Some(CustomerFeedbackPayload::ty),
+        response: Some(CustomerFeedback::ty),
+        summary: "Rate a trip",
+    },
+];
+
+#[ic_cdk::query]
+fn http_request(request: http::HttpRequest) -> http::HttpResponse {
+    serve_http(request, false)
+}
+
+#[ic_cdk::update]
+fn http_request_update(request: http::HttpRequest) -> http::HttpResponse {
+    serve_http(request, true)
+}
+
+// Helper function to route a gateway request. Reads are answered as queries,
+// anything else is upgraded so the gateway repeats it as an update call.
+fn serve_http(request: http::HttpRequest, update: bool) -> http::HttpResponse {
+    let method = request.method.to_uppercase();
+    let (segments, query) = http::split_url(&request.url);
+
+    let mut path_known = false;
+    for route in HTTP_ROUTES {
+        let params = match http::match_path(route.path, &segments) {
+            Some(params) => params,
+            None => continue,
+        };
+        if route.method != method {
+            path_known = true;
+            continue;
+        }
+        if method != "GET" && !update {
+            return http::upgrade();
+        }
+
+        let holder = match http::bearer_token(&request.headers) {
+            Some(token) => match api_token_holder(token) {
+                Some(principal) => Some(principal),
+                None => {
+                    return http::json(
+                        401,
+                        &Message::Error("Invalid or expired API token".to_string()),
+                    )
+                }
+            },
+            None => None,
+        };
+
+        HTTP_CALLER.with(|caller| *caller.borrow_mut() = holder);
+        let result = dispatch_http(route, &params, &query, &request.body);
+        HTTP_CALLER.with(|caller| *caller.borrow_mut() = None);
+
+        return match result {
+            Ok(response) => response,
+            Err(message) => http::json(http_status(&message), &message),
+        };
+    }
+
+    if path_known {
+        http::json(
+            405,
+            &Message::InvalidPayload(format!("{} is not allowed here", method)),
+        )
+    } else {
+        http::json(404, &Message::NotFound("No such endpoint".to_string()))
+    }
+}
+
+// Helper function to call the endpoint behind a route
+fn dispatch_http(
+    route: &HttpRoute,
+    params: &[String],
+    query: &HashMap<String, String>,
+    body: &[u8],
+) -> Result<http::HttpResponse, Message> {
+    let id = |index: usize| -> Result<u64, Message> {
+        params[index].parse().map_err(|_| {
+            Message::InvalidPayload(format!(
+                "{} must be a number",
+                http::path_parameters(route.path)[index]
+            ))
+        })
+    };
+
+    match (route.method, route.path) {
+        ("GET", "/openapi.json") => Ok(http::json(200, &openapi_document())),
+        ("GET", "/saccos") => http_reply(Ok(get_saccos(query_param(query, "include_archived")?))),
+        ("POST", "/saccos") => http_reply(create_sacco(json_body(body, &[])?)),
+        ("GET", "/saccos/{sacco_id}/members") => http_reply(get_sacco_members(id(0)?)),
+        ("GET", "/saccos/{sacco_id}/matatus") => http_reply(get_sacco_matatus(
+            id(0)?,
+            query_param(query, "include_archived")?,
+        )),
+        ("POST", "/saccos/{sacco_id}/matatus") => {
+            http_reply(register_matatu(json_body(body, &[("sacco_id", id(0)?)])?))
+        }
+        ("GET", "/saccos/{sacco_id}/drivers") => http_reply(get_sacco_drivers(
+            id(0)?,
+            query_param(query, "include_archived")?,
+        )),
+        ("POST", "/saccos/{sacco_id}/drivers") => {
+            http_reply(register_driver(json_body(body, &[("sacco_id", id(0)?)])?))
+        }
+        ("GET", "/saccos/{sacco_id}/routes") => {
+            http_reply(get_routes(id(0)?, query_param(query, "include_archived")?))
+        }
+        ("POST", "/saccos/{sacco_id}/routes") => {
+            http_reply(create_route(json_body(body, &[("sacco_id", id(0)?)])?))
+        }
+        ("GET", "/saccos/{sacco_id}/incidents") => {
+            http_reply(get_incidents(id(0)?, query_param(query, "status")?))
+        }
+        ("GET", "/saccos/{sacco_id}/speed-violations") => http_reply(get_speed_violations(
+            id(0)?,
+            query_param(query, "driver_id")?,
+        )),
+        ("GET", "/saccos/{sacco_id}/financial-report") => http_reply(generate_financial_report(
+            id(0)?,
+            required_param(query, "start_time")?,
+            required_param(query, "end_time")?,
+        )),
+        ("GET", "/saccos/{sacco_id}/exports/{dataset}") => http_reply(export_data(ExportPayload {
+            sacco_id: id(0)?,
+            dataset: params[1].clone(),
+            format: required_param(query, "format")?,
+            start_time: required_param(query, "start_time")?,
+            end_time: required_param(query, "end_time")?,
+            continuation: query_param(query, "continuation")?,
+        })),
+        ("POST", "/imports") => http_reply(import_records(json_body(body, &[])?)),
+        ("GET", "/matatus/{matatu_id}/analytics") => http_reply(get_matatu_analytics(id(0)?)),
+        ("GET", "/matatus/{matatu_id}/stop-predictions") => {
+            http_reply(get_stop_predictions(id(0)?))
+        }
+        ("POST", "/drivers/{driver_id}/assign/{matatu_id}") => {
+            http_reply(assign_driver_to_matatu(id(0)?, id(1)?))
+        }
+        ("GET", "/drivers/{driver_id}/driving-hours") => http_reply(get_driving_hours(id(0)?)),
+        ("GET", "/drivers/{driver_id}/attendance") => http_reply(get_attendance_report(
+            id(0)?,
+            required_param(query, "month")?,
+        )),
+        ("GET", "/routes/{route_id}/stops") => http_reply(Ok(get_route_stops(id(0)?))),
+        ("GET", "/routes/{route_id}/stops/{stop_id}/next-matatus") => {
+            http_reply(get_next_matatus(id(0)?, id(1)?))
+        }
+        ("GET", "/routes/{route_id}/fare-rule") => http_reply(get_fare_rule(id(0)?)),
+        ("GET", "/routes/{route_id}/fare-quote") => http_reply(quote_fare(FareQuotePayload {
+            route_id: id(0)?,
+            boarding_stop_id: query_param(query, "boarding_stop_id")?,
+            alighting_stop_id: query_param(query, "alighting_stop_id")?,
+            category: query_param(query, "category")?,
+            time: query_param(query, "time")?,
+        })),
+        ("GET", "/routes/{route_id}/demand-forecast") => {
+            http_reply(forecast_demand(id(0)?, required_param(query, "date")?))
+        }
+        ("GET", "/schedules/{schedule_id}") => http_reply(get_schedule(id(0)?)),
+        ("POST", "/trips") => http_reply(start_trip(json_body(body, &[])?)),
+        ("POST", "/trips/{trip_id}/end") => {
+            http_reply(end_trip(json_body(body, &[("trip_id", id(0)?)])?))
+        }
+        ("POST", "/trips/{trip_id}/tickets") => {
+            http_reply(issue_ticket(json_body(body, &[("trip_id", id(0)?)])?))
+        }
+        ("GET", "/trips/{trip_id}/trajectory") => http_reply(get_trip_trajectory(
+            id(0)?,
+            required_param(query, "format")?,
+        )),
+        ("POST", "/feedback") => http_reply(submit_feedback(json_body(body, &[])?)),
+        _ => Err(Message::NotFound("No such endpoint".to_string())),
+    }
+}
+
+// Helper function to send an endpoint's result back as JSON
+fn http_reply<T: serde::Serialize>(
+    result: Result<T, Message>,
+) -> Result<http::HttpResponse, Message> {
+    result.map(|value| http::json(200, &value))
+}
+
+// Helper function to pick the status code for an endpoint's error
+fn http_status(message: &Message) -> u16 {
+    match message {
+        Message::Success(_) => 200,
+        Message::InvalidPayload(_) => 400,
+        Message::NotFound(_) => 404,
+        Message::Error(_) => 409,
+    }
+}
+
+// Helper function to read an optional query parameter
+fn query_param<T: std::str::FromStr>(
+    query: &HashMap<String, String>,
+    name: &str,
+) -> Result<Option<T>, Message> {
+    query
+        .get(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| Message::InvalidPayload(format!("Invalid {}", name)))
+        })
+        .transpose()
+}
+
+// Helper function to read a query parameter the endpoint can't do without
+fn required_param<T: std::str::FromStr>(
+    query: &HashMap<String, String>,
+    name: &str,
+) -> Result<T, Message> {
+    query_param(query, name)?.ok_or(Message::InvalidPayload(format!("{} is required", name)))
+}
+
+// Helper function to read a JSON request body, with ids taken from the path
+// filling in or overriding the matching fields
+fn json_body<T: serde::de::DeserializeOwned>(
+    body: &[u8],
+    path_ids: &[(&str, u64)],
+) -> Result<T, Message> {
+    let mut value: serde_json::Value = if body.is_empty() {
+        serde_json::json!({})
+    } else {
+        serde_json::from_slice(body)
+            .map_err(|e| Message::InvalidPayload(format!("Invalid JSON: {}", e)))?
+    };
+
+    let fields = value.as_object_mut().ok_or(Message::InvalidPayload(
+        "Body must be a JSON object".to_string(),
+    ))?;
+    for (name, id) in path_ids {
+        fields.insert(name.to_string(), serde_json::Value::from(*id));
+    }
+
+    serde_json::from_value(value).map_err(|e| Message::InvalidPayload(e.to_string()))
+}
+
+// Helper function to describe the JSON API as an OpenAPI 3 document
+fn openapi_document() -> serde_json::Value {
+    let mut paths = serde_json::Map::new();
+
+    for route in HTTP_ROUTES {
+        let mut parameters: Vec<serde_json::Value> = http::path_parameters(route.path)
+            .into_iter()
+            .map(|name| {
+                let schema = if name.ends_with("_id") {
+                    serde_json::json!({ "type": "integer", "format": "int64" })
+                } else {
+                    serde_json::json!({ "type": "string" })
+                };
+                serde_json::json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "schema": schema,
+                })
+            })
+            .collect();
+        parameters.extend(route.query.iter().map(|(name, schema, required)| {
+            serde_json::json!({
+                "name": name,
+                "in": "query",
+                "required": required,
+                "schema": { "type": schema },
+            })
+        }));
+
+        let mut ok = serde_json::json!({ "description": "The endpoint's result, as JSON" });
+        if let Some(response) = route.response {
+            ok["content"] = serde_json::json!({
+                "application/json": { "schema": json_schema(&response()) }
+            });
+        }
+
+        let mut operation = serde_json::json!({
+            "summary": route.summary,
+            "parameters": parameters,
+            "security": [{}, { "bearerAuth": [] }],
+            "responses": {
+                "200": ok,
+                "401": {
+                    "description": "The API token is invalid, revoked or expired",
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/Message" }
+                        }
+                    },
+                },
+                "default": {
+                    "description": "Error: 400 invalid input, 404 not found, 409 refused",
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/Message" }
+                        }
+                    },
+                },
+            },
+        });
+        if let Some(record) = route.body {
+            let mut schema = json_schema(&record());
+            schema["description"] =
+                serde_json::Value::from("Ids in the path can be left out of the body");
+            operation["requestBody"] = serde_json::json!({
+                "required": true,
+                "content": { "application/json": { "schema": schema } },
+            });
+        }
+
+        let item = paths
+            .entry(route.path)
+            .or_insert_with(|| serde_json::json!({}));
+        item[route.method.to_lowercase()] = operation;
+    }
+
+    serde_json::json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "SACCO Management API",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": { "Message": json_schema(&Message::ty()) },
+            "securitySchemes": {
+                "bearerAuth": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "A token from create_api_token, calls without one are anonymous",
+                }
+            },
+        },
+    })
+}
+
+// Helper function to describe the JSON form of a Candid type as a JSON schema
+fn json_schema(ty: &candid::types::Type) -> serde_json::Value {
+    use candid::types::{Label, TypeInner};
+
+    let name = |label: &Label| match label {
+        Label::Named(name) => name.clone(),
+        Label::Id(id) | Label::Unnamed(id) => id.to_string(),
+    };
+
+    match ty.as_ref() {
+        TypeInner::Bool => serde_json::json!({ "type": "boolean" }),
+        TypeInner::Nat64 | TypeInner::Int64 => {
+            serde_json::json!({ "type": "integer", "format": "int64" })
+        }
+        TypeInner::Nat
+        | TypeInner::Int
+        | TypeInner::Nat8
+        | TypeInner::Nat16
+        | TypeInner::Nat32
+        | TypeInner::Int8
+        | TypeInner::Int16
+        | TypeInner::Int32 => serde_json::json!({ "type": "integer" }),
+        TypeInner::Float32 | TypeInner::Float64 => serde_json::json!({ "type": "number" }),
+        TypeInner::Text | TypeInner::Principal => serde_json::json!({ "type": "string" }),
+        TypeInner::Opt(inner) => {
+            let mut schema = json_schema(inner);
+            schema["nullable"] = serde_json::Value::from(true);
+            schema
+        }
+        TypeInner::Vec(inner) => {
+            serde_json::json!({ "type": "array", "items": json_schema(inner) })
+        }
+        TypeInner::Record(fields)
+            if !fields.is_empty()
+                && fields
+                    .iter()
+                    .all(|f| matches!(f.id.as_ref(), Label::Unnamed(_))) =>
+        {
+            // tuples are sent as arrays
+            let items: Vec<serde_json::Value> = fields.iter().map(|f| json_schema(&f.ty)).collect();
+            serde_json::json!({
+                "type": "array",
+                "items": { "oneOf": items },
+                "minItems": fields.len(),
+                "maxItems": fields.len(),
+            })
+        }
+        TypeInner::Record(fields) => {
+            let properties: serde_json::Map<String, serde_json::Value> = fields
+                .iter()
+                .map(|f| (name(&f.id), json_schema(&f.ty)))
+                .collect();
+            let required: Vec<String> = fields
+                .iter()
+                .filter(|f| !matches!(f.ty.as_ref(), TypeInner::Opt(_)))
+                .map(|f| name(&f.id))
+                .collect();
+            serde_json::json!({ "type": "object", "properties": properties, "required": required })
+        }
+        TypeInner::Variant(cases) => {
+            // unit cases are sent as their name, the rest as an object keyed by it
+            let units: Vec<String> = cases
+                .iter()
+                .filter(|c| matches!(c.ty.as_ref(), TypeInner::Null))
+                .map(|c| name(&c.id))
+                .collect();
+            let mut schemas: Vec<serde_json::Value> = cases
+                .iter()
+                .filter(|c| !matches!(c.ty.as_ref(), TypeInner::Null))
+                .map(|c| {
+                    let case = name(&c.id);
+                    serde_json::json!({
+                        "type": "object",
+                        "required": [case.clone()],
+                        "properties": { case: json_schema(&c.ty) },
+                    })
+                })
+                .collect();
+            if !units.is_empty() {
+                schemas.push(serde_json::json!({ "type": "string", "enum": units }));
+            }
+            serde_json::json!({ "oneOf": schemas })
+        }
+        _ => serde_json::json!({}),
+    }
+}
+
+// Helper Functions
+
+fn calculate_optimal_times(route: &Route, date: u64) -> Vec<(u64, u64)> {
+    let day_start = calendar::start_of_day(date);
+    let day = calendar::day_of_week(day_start);
+
+    // Peak windows of the route that fall on this day of the week
+    let mut windows: Vec<(u64, u64)> = route
+        .peak_hours
+        .iter()
+        .filter(|w| w.day_of_week == day && w.end_hour > w.start_hour)
+        .map(|w| {
+            (
+                day_start + w.start_hour as u64 * HOUR_NANOS,
+                day_start + w.end_hour.min(24) as u64 * HOUR_NANOS,
+            )
+        })
+        .collect();
+    windows.sort();
+
+    windows
+}
+
+// Helper function to build a day's roster without saving it.
+// Demand windows are filled in time order with departures spaced evenly across
+// the window, each one taking a matatu licensed for the route and a driver
+// who stays within the driving and rest limits.
+fn plan_roster(sacco_id: u64, date: u64, demand: Vec<DemandWindow>) -> Roster {
+    let day_start = calendar::start_of_day(date);
+    let now = time();
+
+    let matatus = get_available_matatus(sacco_id, day_start);
+    let drivers = get_available_drivers(sacco_id, day_start);
+    let policy = hours_policy_for(sacco_id);
+    let routes = sacco_routes(sacco_id);
+
+    // Time already taken by existing schedules, unscheduled trips and maintenance,
+    // looking back a week so weekly limits and rest across midnight are respected
+    // Unstarted schedules for the day are about to be replaced, so they don't block anything
+    let replaced: Vec<u64> = replaceable_schedules(sacco_id, day_start, now)
+        .iter()
+        .map(|s| s.id)
+        .collect();
+
+    let mut matatu_duties: BTreeMap<u64, Vec<(u64, u64)>> = BTreeMap::new();
+    let mut driver_duties: BTreeMap<u64, Vec<(u64, u64)>> = BTreeMap::new();
+    let (from, to) = (
+        day_start.saturating_sub(7 * DAY_NANOS),
+        day_start + 2 * DAY_NANOS,
+    );
+    SCHEDULES.with(|schedules| {
+        for (id, schedule) in schedules.borrow().iter() {
+            if !replaced.contains(&id)
+                && schedule.status != "cancelled"
+                && schedule.status != "missed"
+                && schedule.start_time < to
+                && schedule.end_time > from
+            {
+                let duty = (schedule.start_time, schedule.end_time);
+                matatu_duties
+                    .entry(schedule.matatu_id)
+                    .or_default()
+                    .push(duty);
+                driver_duties
+                    .entry(schedule.driver_id)
+                    .or_default()
+                    .push(duty);
+            }
+        }
+    });
+    TRIPS.with(|trips| {
+        for (_, trip) in trips.borrow().iter() {
+            let end = trip.end_time.unwrap_or(now.max(trip.start_time));
+            if trip.schedule_id.is_none() && trip.start_time < to && end > from {
+                driver_duties
+                    .entry(trip.driver_id)
+                    .or_default()
+                    .push((trip.start_time, end));
+            }
+        }
+    });
+    for matatu in &matatus {
+        matatu_duties
+            .entry(matatu.id)
+            .or_default()
+            .extend(maintenance_downtime(matatu.id, from, to));
+    }
+
+    // Demand windows across all routes, served earliest first
+    let mut demand = demand;
+    demand.sort_by_key(|w| (w.start_time, w.end_time, w.route_id));
+
+    let mut roster = Roster {
+        sacco_id,
+        date: day_start,
+        ..Default::default()
+    };
+
+    for window in demand {
+        let route = match routes.iter().find(|r| r.id == window.route_id) {
+            Some(route) => route,
+            None => continue,
+        };
+        let (window_start, window_end) = (window.start_time, window.end_time);
+        let uncovered = |passengers: u32, reason: &str| UncoveredDemand {
+            route_id: route.id,
+            start_time: window_start,
+            end_time: window_end,
+            passengers,
+            reason: reason.to_string(),
+        };
+
+        let fleet: Vec<&Matatu> = matatus
+            .iter()
+            .filter(|m| m.route == route.name && m.capacity > 0)
+            .collect();
+        if fleet.is_empty() {
+            roster
+                .uncovered
+                .push(uncovered(window.passengers, "No licensed matatu available"));
+            continue;
+        }
+        if route.estimated_time == 0 {
+            roster.uncovered.push(uncovered(
+                window.passengers,
+                "Route has no estimated travel time",
+            ));
+            continue;
+        }
+
+        let duration = route.estimated_time as u64 * 60 * 1_000_000_000;
+        let average_capacity = fleet.iter().map(|m| m.capacity).sum::<u32>() / fleet.len() as u32;
+        let departures = window
+            .passengers
+            .div_ceil(average_capacity.max(1))
+            .max(window.departures);
+        let headway = (window_end - window_start) / departures.max(1) as u64;
+
+        let mut remaining = window.passengers;
+        let mut reason = "";
+        for slot in 0..departures {
+            // Requested departures run even once the expected passengers are seated
+            if remaining == 0 && slot >= window.departures {
+                break;
+            }
+
+            let start = window_start + slot as u64 * headway;
+            let end = start + duration;
+            if start < now {
+                reason = "Window has already started";
+                continue;
+            }
+
+            // Least used free matatu
+            let matatu = fleet
+                .iter()
+                .filter(|m| {
+                    !matatu_duties
+                        .get(&m.id)
+                        .is_some_and(|duties| duties.iter().any(|&(s, e)| s < end && start < e))
+                })
+                .min_by_key(|m| driven_between(matatu_duties.get(&m.id), day_start));
+            let matatu = match matatu {
+                Some(matatu) => *matatu,
+                None => {
+                    reason = "No licensed matatu free";
+                    continue;
+                }
+            };
+
+            // Prefer the matatu's own driver, then whoever has driven least today
+            let driver = drivers
+                .iter()
+                .filter(|d| {
+                    let duties = driver_duties.get(&d.id).map_or(&[][..], |v| v.as_slice());
+                    driving_limit_breach(duties, start, end, &policy).is_none()
+                })
+                .min_by_key(|d| {
+                    (
+                        d.assigned_matatu != Some(matatu.id),
+                        driven_between(driver_duties.get(&d.id), day_start),
+                    )
+                });
+            let driver = match driver {
+                Some(driver) => driver,
+                None => {
+                    reason = "No driver within hours of service";
+                    continue;
+                }
+            };
+
+            matatu_duties
+                .entry(matatu.id)
+                .or_default()
+                .push((start, end));
+            driver_duties
+                .entry(driver.id)
+                .or_default()
+                .push((start, end));
+            remaining = remaining.saturating_sub(matatu.capacity);
+
+            roster.schedules.push(Schedule {
+                id: 0,
+                matatu_id: matatu.id,
+                driver_id: driver.id,
+                route_id: route.id,
+                start_time: start,
+                end_time: end,
+                status: "scheduled".to_string(),
+                created_at: now,
+                estimated_arrival: None,
+                trip_id: None,
+                actual_start: None,
+                actual_end: None,
+            });
+        }
+
+        if remaining > 0 {
+            if reason.is_empty() {
+                reason = "Not enough seats in the licensed fleet";
+            }
+            roster.uncovered.push(uncovered(remaining, reason));
+        }
+    }
+
+    roster
+}
+
+// Helper function to check demand passed to the roster, or recommend it when none is given
+fn roster_demand(
+    sacco_id: u64,
+    date: u64,
+    demand: Option<Vec<DemandWindow>>,
+) -> Result<Vec<DemandWindow>, Message> {
+    let day_start = calendar::start_of_day(date);
+    let demand = match demand {
+        Some(demand) => demand,
+        None => return Ok(demand_windows(sacco_id, day_start)),
+    };
+
+    let routes: Vec<u64> = sacco_routes(sacco_id).iter().map(|r| r.id).collect();
+    for window in &demand {
+        if !routes.contains(&window.route_id) {
+            return Err(Message::InvalidPayload(format!(
+                "Route {} is not run by the SACCO",
+                window.route_id
+            )));
+        }
+        if window.end_time <= window.start_time
+            || window.start_time < day_start
+            || window.start_time >= day_start + DAY_NANOS
+        {
+            return Err(Message::InvalidPayload(
+                "Demand windows must start on the scheduled day and end after they start"
+                    .to_string(),
+            ));
+        }
+    }
+
+    Ok(demand)
+}
+
+// Helper function to recommend service on a SACCO's routes for a day.
+// Routes with a demand history use the hourly forecast, the others fall
+// back to their peak hours and average passengers.
+fn demand_windows(sacco_id: u64, day_start: u64) -> Vec<DemandWindow> {
+    let matatus = get_available_matatus(sacco_id, day_start);
+    let mut windows = Vec::new();
+
+    for route in sacco_routes(sacco_id) {
+        let capacities: Vec<u32> = matatus
+            .iter()
+            .filter(|m| m.route == route.name && m.capacity > 0)
+            .map(|m| m.capacity)
+            .collect();
+        let capacity = if capacities.is_empty() {
+            TYPICAL_MATATU_CAPACITY
+        } else {
+            capacities.iter().sum::<u32>() / capacities.len() as u32
+        };
+        let duration = route.estimated_time as u64 * 60 * 1_000_000_000;
+
+        let forecast = route_demand_forecast(route.id, day_start);
+        let demand: Vec<(u64, u64, u32, &str)> = if forecast.is_empty() {
+            calculate_optimal_times(&route, day_start)
+                .into_iter()
+                .map(|(start, end)| (start, end, route.average_passengers, "peak_hours"))
+                .collect()
+        } else {
+            forecast
+                .iter()
+                .filter(|f| f.passengers > 0)
+                .map(|f| (f.start_time, f.end_time, f.passengers, "forecast"))
+                .collect()
+        };
+
+        for (start_time, end_time, passengers, basis) in demand {
+            let departures = passengers.div_ceil(capacity.max(1));
+            let vehicles = if duration == 0 {
+                departures
+            } else {
+                (departures as u64 * duration).div_ceil(end_time - start_time) as u32
+            };
+
+            windows.push(DemandWindow {
+                route_id: route.id,
+                start_time,
+                end_time,
+                passengers,
+                departures,
+                vehicles: vehicles.clamp(departures.min(1), departures),
+                basis: basis.to_string(),
+            });
+        }
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+    windows.sort_by_key(|w| (w.start_time, w.end_time, w.route_id));
+    windows
+}
+
+// Helper function to mark schedules that weren't started within the grace period as missed
+fn mark_missed_schedules(now: u64) -> u32 {
+    SCHEDULES.with(|schedules| {
+        let mut schedules_map = schedules.borrow_mut();
+        let overdue: Vec<u64> = schedules_map
+            .iter()
+            .filter(|(_, s)| s.status == "scheduled" && now > s.start_time + SCHEDULE_GRACE_PERIOD)
+            .map(|(id, _)| id)
+            .collect();
+
+        for id in &overdue {
+            if let Some(mut schedule) = schedules_map.get(id) {
+                schedule.status = "missed".to_string();
+                schedules_map.insert(*id, schedule);
+            }
+        }
+
+        overdue.len() as u32
+    })
+}
+
+// Helper function to get a SACCO's schedules for the day that haven't started yet
+fn replaceable_schedules(sacco_id: u64, date: u64, now: u64) -> Vec<Schedule> {
+    let day_start = calendar::start_of_day(date);
+    let fleet: Vec<u64> = MATATUS.with(|matatus| {
+        matatus
+            .borrow()
+            .iter()
+            .filter(|(_, m)| m.sacco_id == sacco_id)
+            .map(|(id, _)| id)
+            .collect()
+    });
+
+    SCHEDULES.with(|schedules| {
+        schedules
+            .borrow()
+            .iter()
+            .filter(|(_, s)| {
+                s.status == "scheduled"
+                    && s.start_time > now
+                    && s.start_time >= day_start
+                    && s.start_time < day_start + DAY_NANOS
+                    && fleet.contains(&s.matatu_id)
+            })
+            .map(|(_, s)| s.clone())
+            .collect()
+    })
+}
+
+// Helper function to compare a proposed roster with the schedules it replaces.
+// Departures are matched on route and start time; a matched departure keeps
+// the existing schedule's id and creation time.
+fn diff_roster(existing: Vec<Schedule>, roster: Roster) -> RosterPlan {
+    let mut remaining = existing;
+    let mut plan = RosterPlan {
+        roster,
+        ..Default::default()
+    };
+
+    for proposed in plan.roster.schedules.iter_mut() {
+        let same_slot =
+            |s: &Schedule| s.route_id == proposed.route_id && s.start_time == proposed.start_time;
+        let matched = remaining
+            .iter()
+            .position(|s| {
+                same_slot(s)
+                    && s.matatu_id == proposed.matatu_id
+                    && s.driver_id == proposed.driver_id
+            })
+            .or_else(|| remaining.iter().position(same_slot));
+
+        match matched {
+            Some(index) => {
+                let before = remaining.swap_remove(index);
+                proposed.id = before.id;
+                proposed.created_at = before.created_at;
+                if before.matatu_id == proposed.matatu_id && before.driver_id == proposed.driver_id
+                {
+                    plan.unchanged += 1;
+                } else {
+                    plan.reassigned.push(ScheduleChange {
+                        before,
+                        after: proposed.clone(),
+                    });
+                }
+            }
+            None => plan.added.push(proposed.clone()),
+        }
     }
+
+    plan.removed = remaining;
+    plan
 }
 
-impl BoundedStorable for Driver {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// Helper function to get the time a matatu or driver is busy on a given day
+fn driven_between(duties: Option<&Vec<(u64, u64)>>, day_start: u64) -> u64 {
+    duties.map_or(0, |duties| {
+        duties
+            .iter()
+            .filter(|(start, _)| *start >= day_start && *start < day_start + DAY_NANOS)
+            .map(|(start, end)| end.saturating_sub(*start))
+            .sum()
+    })
 }
 
-// Implementing Storable for Expense
-impl Storable for Expense {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+// Helper function to explain why a duty would break a driver's hours-of-service
+// limits, if it would
+fn driving_limit_breach(
+    duties: &[(u64, u64)],
+    start: u64,
+    end: u64,
+    policy: &HoursOfServicePolicy,
+) -> Option<String> {
+    if duties.iter().any(|&(s, e)| s < end && start < e) {
+        return Some("Driver is already on duty".to_string());
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+    let mut all = duties.to_vec();
+    all.push((start, end));
+    all.sort();
+
+    // Every 24 hour and 7 day window ending at or after this duty must stay within the limits
+    let driven_max = |window: u64| {
+        all.iter()
+            .filter(|&&(_, e)| e >= end && e < end + window)
+            .map(|&(_, e)| driven_within(&all, e, window))
+            .max()
+            .unwrap_or_default()
+    };
+    if driven_max(DAY_NANOS) > minutes_to_nanos(policy.max_daily_driving) {
+        return Some(format!(
+            "Driver would exceed {} minutes of driving in 24 hours",
+            policy.max_daily_driving
+        ));
+    }
+    if driven_max(7 * DAY_NANOS) > minutes_to_nanos(policy.max_weekly_driving) {
+        return Some(format!(
+            "Driver would exceed {} minutes of driving in 7 days",
+            policy.max_weekly_driving
+        ));
+    }
+
+    // Duties closer together than a break form one stretch of driving, and
+    // duties closer together than the daily rest form one shift
+    let index = all
+        .iter()
+        .position(|d| *d == (start, end))
+        .unwrap_or_default();
+    if duty_block_span(&all, index, minutes_to_nanos(policy.min_break))
+        > minutes_to_nanos(policy.max_continuous_driving)
+    {
+        return Some(format!("Driver needs a {} minute break", policy.min_break));
+    }
+    let rest = minutes_to_nanos(policy.min_daily_rest);
+    if duty_block_span(&all, index, rest) > DAY_NANOS.saturating_sub(rest) {
+        return Some(format!(
+            "Driver needs {} minutes of rest",
+            policy.min_daily_rest
+        ));
     }
+
+    None
 }
 
-impl BoundedStorable for Expense {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// Helper function to get the driving time in the `window` before `end`
+fn driven_within(duties: &[(u64, u64)], end: u64, window: u64) -> u64 {
+    let from = end.saturating_sub(window);
+    duties
+        .iter()
+        .map(|&(s, e)| e.min(end).saturating_sub(s.max(from)))
+        .sum()
 }
 
-// Implementing Storable for Revenue
-impl Storable for Revenue {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+fn minutes_to_nanos(minutes: u32) -> u64 {
+    minutes as u64 * 60 * 1_000_000_000
+}
+
+// Helper function to get a driver's trips between two times as (start, end) pairs,
+// ongoing trips running until `to`
+fn driver_trip_duties(driver_id: u64, from: u64, to: u64, skip: Option<u64>) -> Vec<(u64, u64)> {
+    let mut duties: Vec<(u64, u64)> = TRIPS.with(|trips| {
+        trips
+            .borrow()
+            .iter()
+            .filter(|(id, t)| {
+                t.driver_id == driver_id
+                    && Some(*id) != skip
+                    && t.status != "cancelled"
+                    && t.start_time < to
+                    && t.end_time.unwrap_or(to) > from
+            })
+            .map(|(_, t)| (t.start_time, t.end_time.unwrap_or(to)))
+            .collect()
+    });
+    duties.sort();
+
+    duties
+}
+
+// Helper function to get a SACCO's hours-of-service limits
+fn hours_policy_for(sacco_id: u64) -> HoursOfServicePolicy {
+    HOURS_POLICIES
+        .with(|policies| policies.borrow().get(&sacco_id))
+        .unwrap_or(HoursOfServicePolicy {
+            sacco_id,
+            max_continuous_driving: (MAX_CONTINUOUS_DRIVING / 60_000_000_000) as u32,
+            min_break: (MIN_DRIVING_BREAK / 60_000_000_000) as u32,
+            max_daily_driving: (MAX_DAILY_DRIVING / 60_000_000_000) as u32,
+            max_weekly_driving: (MAX_WEEKLY_DRIVING / 60_000_000_000) as u32,
+            min_daily_rest: (MIN_DAILY_REST / 60_000_000_000) as u32,
+            updated_at: 0,
+        })
+}
+
+// Helper function to get the span of the run of duties around `index` whose gaps are under `gap`
+fn duty_block_span(duties: &[(u64, u64)], index: usize, gap: u64) -> u64 {
+    let (mut first, mut last) = (index, index);
+    while first > 0 && duties[first - 1].1 + gap > duties[first].0 {
+        first -= 1;
+    }
+    while last + 1 < duties.len() && duties[last].1 + gap > duties[last + 1].0 {
+        last += 1;
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+    let end = duties[first..=last]
+        .iter()
+        .map(|d| d.1)
+        .max()
+        .unwrap_or_default();
+    end.saturating_sub(duties[first].0)
+}
+
+// Helper function to get the periods a matatu is off the road for maintenance
+fn maintenance_downtime(matatu_id: u64, from: u64, to: u64) -> Vec<(u64, u64)> {
+    MAINTENANCE_RECORDS.with(|records| {
+        records
+            .borrow()
+            .iter()
+            .filter(|(_, r)| r.matatu_id == matatu_id && r.status != "completed")
+            .map(|(_, r)| (r.date, r.date + r.downtime_hours as u64 * HOUR_NANOS))
+            .filter(|&(start, end)| start < to && end > from)
+            .collect()
+    })
+}
+
+fn update_arrival_estimates(matatu_id: u64, location: &LocationUpdate) {
+    SCHEDULES.with(|schedules| {
+        let mut schedules_map = schedules.borrow_mut();
+        let keys_to_update: Vec<u64> = schedules_map
+            .iter()
+            .filter_map(|(key, schedule)| {
+                if schedule.matatu_id == matatu_id && schedule.status == "in_progress" {
+                    Some(key)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        for key in keys_to_update {
+            if let Some(schedule) = schedules_map.get(&key) {
+                let mut updated_schedule = schedule.clone(); // Clone the schedule for modification
+                let new_arrival_time = calculate_new_arrival_time(&updated_schedule, location);
+                updated_schedule.estimated_arrival = Some(new_arrival_time);
+                schedules_map.insert(key, updated_schedule);
+            }
+        }
+    });
+}
+
+
+
+
+fn calculate_total_revenues(sacco_id: u64, start_time: u64, end_time: u64) -> f64 {
+    REVENUES.with(|revenues| {
+        revenues
+            .borrow()
+            .iter()
+            .filter(|(_, r)| r.sacco_id == sacco_id && r.date >= start_time && r.date <= end_time)
+            .map(|(_, r)| r.amount)
+            .sum()
+    })
+}
+
+fn analyze_revenue_sources(sacco_id: u64, start_time: u64, end_time: u64) -> Vec<RevenueSource> {
+    // Analyze revenue by different categories:
+    // 1. Regular trips
+    // 2. Special routes
+    // 3. Peak hour surcharges
+    // 4. Additional services
+    let total_revenue = calculate_total_revenues(sacco_id, start_time, end_time);
+
+    REVENUES.with(|revenues| {
+        let revenues_map = revenues.borrow();
+        let mut sources = HashMap::new();
+
+        for (_, revenue) in revenues_map.iter() {
+            if revenue.sacco_id == sacco_id
+                && revenue.date >= start_time
+                && revenue.date <= end_time
+            {
+                *sources.entry(revenue.description.clone()).or_insert(0.0) += revenue.amount;
+            }
+        }
+
+        sources
+            .into_iter()
+            .map(|(source, amount)| RevenueSource {
+                source,
+                amount,
+                percentage: if total_revenue > 0.0 {
+                    (amount / total_revenue) * 100.0
+                } else {
+                    0.0
+                },
+            })
+            .collect()
+    })
+}
+
+// Helper function to get get_available_matatus
+fn get_available_matatus(sacco_id: u64, date: u64) -> Vec<Matatu> {
+    let day_start = calendar::start_of_day(date);
+    let day_end = day_start + DAY_NANOS;
+
+    MATATUS.with(|matatus| {
+        matatus
+            .borrow()
+            .iter()
+            .filter(|(_, m)| m.sacco_id == sacco_id && m.status == "active")
+            // Skip matatus in the garage for the whole day
+            .filter(|(id, _)| {
+                !maintenance_downtime(*id, day_start, day_end)
+                    .iter()
+                    .any(|&(start, end)| start <= day_start && end >= day_end)
+            })
+            .map(|(_, m)| m.clone())
+            .collect()
+    })
+}
+
+// Helper function to get get_available_drivers
+fn get_available_drivers(sacco_id: u64, date: u64) -> Vec<Driver> {
+    let day_start = calendar::start_of_day(date);
+
+    DRIVERS.with(|drivers| {
+        drivers
+            .borrow()
+            .iter()
+            .filter(|(_, d)| {
+                d.sacco_id == sacco_id
+                    && driver_unavailability(d, day_start, day_start + DAY_NANOS).is_none()
+            })
+            .map(|(_, d)| d.clone())
+            .collect()
+    })
+}
+
+// Helper function to get why a driver can't work between `from` and `to`, if anything
+fn driver_unavailability(driver: &Driver, from: u64, to: u64) -> Option<String> {
+    if driver.status != "active" {
+        return Some(format!("Driver is {}", driver.status));
+    }
+
+    if !driver.working_days.is_empty()
+        && !driver.working_days.contains(&calendar::day_of_week(from))
+    {
+        return Some("Not one of the driver's working days".to_string());
     }
+
+    approved_leave(driver.id, from, to).map(|leave| match leave.leave_type.as_str() {
+        "sick" => "Driver is off sick".to_string(),
+        "suspension" => "Driver is suspended".to_string(),
+        _ => "Driver is on leave".to_string(),
+    })
 }
 
-impl BoundedStorable for Revenue {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// Helper function to find approved leave overlapping a period
+fn approved_leave(driver_id: u64, from: u64, to: u64) -> Option<DriverLeave> {
+    DRIVER_LEAVE.with(|leave| {
+        leave
+            .borrow()
+            .iter()
+            .find(|(_, l)| {
+                l.driver_id == driver_id
+                    && l.status == "approved"
+                    && l.start_date < to
+                    && l.end_date > from
+            })
+            .map(|(_, l)| l.clone())
+    })
 }
 
-// Implementing Storable for Trip
-impl Storable for Trip {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+// Helper function to find the driver signed in as the caller
+fn driver_for_caller() -> Result<Driver, Message> {
+    let caller = caller();
+    DRIVERS
+        .with(|drivers| {
+            drivers
+                .borrow()
+                .iter()
+                .find(|(_, d)| d.principal == Some(caller))
+                .map(|(_, d)| d.clone())
+        })
+        .ok_or(Message::NotFound(
+            "No driver registered for caller".to_string(),
+        ))
+}
+
+// Helper function to find a driver's shift that hasn't been checked out
+fn open_shift(driver_id: u64) -> Option<Shift> {
+    SHIFTS.with(|shifts| {
+        shifts
+            .borrow()
+            .iter()
+            .find(|(_, s)| s.driver_id == driver_id && s.check_out.is_none())
+            .map(|(_, s)| s.clone())
+    })
+}
+
+// Helper function to get a SACCO's active routes that its matatus are licensed for
+fn sacco_routes(sacco_id: u64) -> Vec<Route> {
+    let licensed: Vec<String> = MATATUS.with(|m| {
+        m.borrow()
+            .iter()
+            .filter(|(_, m)| m.sacco_id == sacco_id && m.status != "archived")
+            .map(|(_, m)| m.route.clone())
+            .collect()
+    });
+
+    ROUTES.with(|r| {
+        r.borrow()
+            .iter()
+            .filter(|(_, route)| {
+                route.sacco_id == sacco_id
+                    && route.status == "active"
+                    && licensed.contains(&route.name)
+            })
+            .map(|(_, route)| route.clone())
+            .collect()
+    })
+}
+
+// A ride between two neighbouring stops of a route in the stop graph
+struct StopEdge {
+    to: usize,
+    route: usize,       // index into the graph's routes
+    segment_index: u32, // segment of the route the ride covers
+    distance: f64,      // in meters
+}
+
+// Stops of a set of routes, where stops of different routes within each other's
+// radius are the same stage so passengers can change matatus there
+struct StopGraph {
+    routes: Vec<Route>,
+    stages: Vec<RouteStop>, // the first stop seen at each stage
+    edges: Vec<Vec<StopEdge>>,
+    route_ends: BTreeMap<u64, (usize, usize)>, // route id to its first and last stage
+}
+
+impl StopGraph {
+    fn build(routes: Vec<Route>) -> Self {
+        let mut graph = StopGraph {
+            routes: Vec::new(),
+            stages: Vec::new(),
+            edges: Vec::new(),
+            route_ends: BTreeMap::new(),
+        };
+
+        for route in routes {
+            let stops = get_ordered_stops(route.id);
+            if stops.len() < 2 {
+                continue;
+            }
+
+            let route_index = graph.routes.len();
+            let stages: Vec<usize> = stops.iter().map(|stop| graph.stage_of(stop)).collect();
+            for (index, pair) in stops.windows(2).enumerate() {
+                let distance = geo::haversine_distance(
+                    pair[0].latitude,
+                    pair[0].longitude,
+                    pair[1].latitude,
+                    pair[1].longitude,
+                );
+                // Matatus run the route both ways
+                for (from, to) in [
+                    (stages[index], stages[index + 1]),
+                    (stages[index + 1], stages[index]),
+                ] {
+                    graph.edges[from].push(StopEdge {
+                        to,
+                        route: route_index,
+                        segment_index: index as u32,
+                        distance,
+                    });
+                }
+            }
+
+            graph
+                .route_ends
+                .insert(route.id, (stages[0], stages[stages.len() - 1]));
+            graph.routes.push(route);
+        }
+
+        graph
+    }
+
+    fn stage_of(&mut self, stop: &RouteStop) -> usize {
+        let existing = self.stages.iter().position(|stage| {
+            geo::within_radius(
+                stop.latitude,
+                stop.longitude,
+                stage.latitude,
+                stage.longitude,
+                stage.radius.max(stop.radius),
+            )
+        });
+
+        existing.unwrap_or_else(|| {
+            self.stages.push(stop.clone());
+            self.edges.push(Vec::new());
+            self.stages.len() - 1
+        })
+    }
+
+    // Time-dependent shortest path, avoiding the excluded routes. Each ride is
+    // timed with the route's segment speeds and the traffic expected when the
+    // matatu gets there, and each change of matatu costs a transfer penalty.
+    fn fastest_path(
+        &self,
+        from: usize,
+        to: usize,
+        departure: u64,
+        excluded: &BTreeSet<u64>,
+    ) -> Option<AlternateRoute> {
+        // Search states are a stage and the route ridden into it
+        type State = (usize, Option<usize>);
+        let mut arrival: BTreeMap<State, u64> = BTreeMap::new();
+        let mut previous: BTreeMap<State, State> = BTreeMap::new();
+        let mut queue = std::collections::BinaryHeap::new();
+
+        arrival.insert((from, None), departure);
+        queue.push(std::cmp::Reverse((departure, from, None)));
+
+        while let Some(std::cmp::Reverse((at, stage, ridden))) = queue.pop() {
+            if arrival.get(&(stage, ridden)).is_some_and(|best| *best < at) {
+                continue;
+            }
+            if stage == to {
+                return Some(self.trace(previous, (stage, ridden), departure, at));
+            }
+
+            for edge in &self.edges[stage] {
+                let route = &self.routes[edge.route];
+                if excluded.contains(&route.id) {
+                    continue;
+                }
+
+                let transfer = match ridden {
+                    Some(current) if current != edge.route => TRANSFER_PENALTY,
+                    _ => 0,
+                };
+                let boarding = at + transfer;
+                let next = (edge.to, Some(edge.route));
+                let reached = boarding + ride_nanos(route, edge, boarding);
+
+                if reached < arrival.get(&next).copied().unwrap_or(u64::MAX) {
+                    arrival.insert(next, reached);
+                    previous.insert(next, (stage, ridden));
+                    queue.push(std::cmp::Reverse((reached, edge.to, Some(edge.route))));
+                }
+            }
+        }
+
+        None
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+    fn trace(
+        &self,
+        previous: BTreeMap<(usize, Option<usize>), (usize, Option<usize>)>,
+        end: (usize, Option<usize>),
+        departure: u64,
+        arrival: u64,
+    ) -> AlternateRoute {
+        let mut states = vec![end];
+        while let Some(state) = previous.get(states.last().unwrap()) {
+            states.push(*state);
+        }
+        states.reverse();
+
+        let mut alternate = AlternateRoute {
+            expected_duration: (arrival.saturating_sub(departure) / 60_000_000_000) as u32,
+            ..Default::default()
+        };
+        for (stage, ridden) in states {
+            alternate.stop_ids.push(self.stages[stage].id);
+            if let Some(route) = ridden.map(|r| self.routes[r].id) {
+                if alternate.route_ids.last() != Some(&route) {
+                    alternate.route_ids.push(route);
+                }
+            }
+        }
+        alternate.transfers = alternate.route_ids.len().saturating_sub(1) as u32;
+
+        alternate
     }
 }
 
-impl BoundedStorable for Trip {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// Helper function to get how long a ride between two stops takes when starting at `at`
+fn ride_nanos(route: &Route, edge: &StopEdge, at: u64) -> u64 {
+    let speed = segment_speed(route.id, edge.segment_index)
+        .unwrap_or_else(|| route_average_speed(route))
+        .max(MIN_ETA_SPEED);
+    let delay_factor = match current_traffic_pattern(route, at) {
+        Some(pattern) if route.estimated_time > 0 => {
+            1.0 + pattern.average_delay as f64 / route.estimated_time as f64
+        }
+        _ => 1.0,
+    };
+
+    (edge.distance / 1000.0 / speed * 3600.0 * delay_factor * 1_000_000_000.0) as u64
 }
 
-// Implementing Storable for Maintenance
-impl Storable for Maintenance {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+// Helper function to find other ways between a route's start and end points over the
+// routes of the SACCOs operating it, ranked by expected duration
+fn find_alternate_routes(route: &Route, departure: u64) -> Vec<AlternateRoute> {
+    let mut routes = sacco_routes(route.sacco_id);
+    if !routes.iter().any(|r| r.id == route.id) {
+        routes.push(route.clone());
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+    let graph = StopGraph::build(routes);
+    let (from, to) = match graph.route_ends.get(&route.id) {
+        Some(ends) => *ends,
+        None => return Vec::new(),
+    };
+
+    // Each alternative avoids the route itself and every route used by earlier ones
+    let mut excluded = BTreeSet::from([route.id]);
+    let mut alternatives = Vec::new();
+    while alternatives.len() < MAX_ALTERNATE_ROUTES {
+        let alternate = match graph.fastest_path(from, to, departure, &excluded) {
+            Some(alternate) => alternate,
+            None => break,
+        };
+        excluded.extend(alternate.route_ids.iter().copied());
+        alternatives.push(alternate);
     }
+    alternatives.sort_by_key(|a| a.expected_duration);
+
+    alternatives
 }
 
-impl BoundedStorable for Maintenance {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// Helper function to calculate_new_arrival_time
+fn calculate_new_arrival_time(schedule: &Schedule, location: &LocationUpdate) -> u64 {
+    // Calculate new estimated arrival time based on:
+    // 1. Current location projected onto the route line
+    // 2. Remaining distance along the route
+    // 3. Recent speed blended with historical segment speeds
+    // 4. Current traffic conditions
+    let route = match ROUTES.with(|routes| routes.borrow().get(&schedule.route_id)) {
+        Some(route) => route,
+        None => return schedule.end_time,
+    };
+    let line = route_polyline(route.id);
+    let projection = match geo::project_onto_polyline(&line, location.latitude, location.longitude)
+    {
+        Some(projection) if line.len() > 1 => projection,
+        _ => return schedule.end_time,
+    };
+
+    let recent_speed = TRACKING_STATES
+        .with(|states| states.borrow().get(&location.matatu_id))
+        .map(|state| state.average_speed)
+        .filter(|speed| *speed > 0.0)
+        .unwrap_or(location.speed);
+    predict_arrival(
+        &route,
+        &line,
+        &projection,
+        recent_speed,
+        geo::polyline_length(&line),
+        location.timestamp,
+    )
 }
 
-// Implementing Storable for DriverPerformance
-impl Storable for DriverPerformance {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// Helper function to estimate the travel time from a point on the route to a point further along
+fn travel_seconds(
+    route: &Route,
+    line: &[(f64, f64)],
+    projection: &geo::Projection,
+    recent_speed: f64,
+    target_distance: f64,
+) -> f64 {
+    let fallback_speed = route_average_speed(route);
+    let mut seconds = 0.0;
+    let mut segment_start = 0.0;
+
+    for (index, pair) in line.windows(2).enumerate() {
+        let length = geo::haversine_distance(pair[0].0, pair[0].1, pair[1].0, pair[1].1);
+        let segment_end = segment_start + length;
+        let from = segment_start.max(projection.distance_along);
+        let to = segment_end.min(target_distance);
+        segment_start = segment_end;
+
+        if to <= from {
+            continue;
+        }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        let current_segment = index == projection.segment_index;
+        let historical_speed = segment_speed(route.id, index as u32);
+        let speed = match (current_segment, historical_speed) {
+            (true, Some(historical)) => {
+                RECENT_SPEED_WEIGHT * recent_speed + (1.0 - RECENT_SPEED_WEIGHT) * historical
+            }
+            (true, None) => recent_speed,
+            (false, Some(historical)) => historical,
+            (false, None) => fallback_speed,
+        }
+        .max(MIN_ETA_SPEED);
+
+        seconds += (to - from) / (speed / 3.6);
     }
-}
 
-impl BoundedStorable for DriverPerformance {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+    seconds
 }
 
-// Implementing Storable for FuelConsumption
-impl Storable for FuelConsumption {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+// Helper function to spread the expected traffic delay over the distance still to cover
+fn traffic_delay_seconds(
+    route: &Route,
+    line: &[(f64, f64)],
+    projection: &geo::Projection,
+    target_distance: f64,
+    timestamp: u64,
+) -> f64 {
+    let total = geo::polyline_length(line);
+    if total <= 0.0 {
+        return 0.0;
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+    let remaining_fraction =
+        ((target_distance - projection.distance_along) / total).clamp(0.0, 1.0);
+    let delay_minutes = current_traffic_pattern(route, timestamp)
+        .map(|pattern| pattern.average_delay)
+        .unwrap_or(0);
+
+    delay_minutes as f64 * 60.0 * remaining_fraction
 }
 
-impl BoundedStorable for FuelConsumption {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// Helper function to predict when a tracked matatu reaches a point on its route
+fn predict_arrival(
+    route: &Route,
+    line: &[(f64, f64)],
+    projection: &geo::Projection,
+    recent_speed: f64,
+    target_distance: f64,
+    timestamp: u64,
+) -> u64 {
+    let seconds = travel_seconds(route, line, projection, recent_speed, target_distance)
+        + traffic_delay_seconds(route, line, projection, target_distance, timestamp);
+
+    timestamp + (seconds * 1_000_000_000.0) as u64
 }
 
-// Implementing Storable for Route
-impl Storable for Route {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// Helper function to find the traffic pattern in effect at a timestamp
+fn current_traffic_pattern(route: &Route, timestamp: u64) -> Option<TrafficPattern> {
+    let hour = calendar::hour_of_day(timestamp);
+    let day = calendar::day_of_week(timestamp);
+
+    route
+        .traffic_patterns
+        .iter()
+        .find(|tp| {
+            let window = &tp.time_window;
+            window.day_of_week == day && window.start_hour <= hour && window.end_hour > hour
+        })
+        .cloned()
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// Helper function to get a route's fare rule, falling back to its flat price
+fn fare_rule_for(route: &Route) -> FareRule {
+    FARE_RULES
+        .with(|rules| rules.borrow().get(&route.id))
+        .unwrap_or(FareRule {
+            route_id: route.id,
+            base_fare: route.price,
+            ..Default::default()
+        })
 }
 
-impl BoundedStorable for Route {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// Helper function to work out the fare of a ride on a route at a given time
+fn quote_route_fare(
+    route: &Route,
+    boarding_stop_id: Option<u64>,
+    alighting_stop_id: Option<u64>,
+    category: Option<&str>,
+    timestamp: u64,
+) -> Result<FareQuote, Message> {
+    let rule = fare_rule_for(route);
+
+    let discount_percent = match category {
+        Some(category) => {
+            rule.discounts
+                .iter()
+                .find(|d| d.category == category)
+                .ok_or(Message::InvalidPayload(format!(
+                    "No discount for category {}",
+                    category
+                )))?
+                .percent
+        }
+        None => 0.0,
+    };
+
+    let base_fare = match (boarding_stop_id, alighting_stop_id) {
+        (Some(from), Some(to)) => rule
+            .stage_fares
+            .iter()
+            .find(|s| {
+                (s.from_stop_id == from && s.to_stop_id == to)
+                    || (s.from_stop_id == to && s.to_stop_id == from)
+            })
+            .map_or(rule.base_fare, |s| s.fare),
+        _ => rule.base_fare,
+    };
+
+    let hour = calendar::hour_of_day(timestamp);
+    let day = calendar::day_of_week(timestamp);
+    let peak_surcharge = if route
+        .peak_hours
+        .iter()
+        .any(|w| w.day_of_week == day && w.start_hour <= hour && w.end_hour > hour)
+    {
+        rule.peak_surcharge
+    } else {
+        0.0
+    };
+
+    let mut fare = base_fare + peak_surcharge;
+    let fare_override = rule
+        .overrides
+        .iter()
+        .rev()
+        .find(|o| o.start_time <= timestamp && o.end_time > timestamp);
+    let override_adjustment =
+        fare_override.map_or(0.0, |o| (fare * o.multiplier + o.surcharge).max(0.0) - fare);
+    fare += override_adjustment;
+
+    let discount = fare * discount_percent / 100.0;
+    fare -= discount;
+
+    Ok(FareQuote {
+        route_id: route.id,
+        base_fare,
+        peak_surcharge,
+        override_name: fare_override.map(|o| o.name.clone()),
+        override_adjustment,
+        category: category.map(|c| c.to_string()),
+        discount,
+        fare,
+        rule_updated_at: rule.updated_at,
+    })
 }
 
-// Implementing Storable for TimeWindow
-impl Storable for TimeWindow {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+// Helper function to get the planned average speed of a route, in km/h
+fn route_average_speed(route: &Route) -> f64 {
+    if route.estimated_time == 0 {
+        return MIN_ETA_SPEED;
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+    route.distance / (route.estimated_time as f64 / 60.0)
 }
 
-impl BoundedStorable for TimeWindow {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// Helper function to get the historical speed over a route segment
+fn segment_speed(route_id: u64, segment_index: u32) -> Option<f64> {
+    SEGMENT_STATS
+        .with(|stats| {
+            stats.borrow().get(&SegmentKey {
+                route_id,
+                segment_index,
+            })
+        })
+        .filter(|s| s.samples > 0)
+        .map(|s| s.average_speed)
 }
 
-// Implementing Storable for TrafficPattern
-impl Storable for TrafficPattern {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// Helper function to calculate_total_expenses
+fn calculate_total_expenses(sacco_id: u64, start_time: u64, end_time: u64) -> f64 {
+    EXPENSES.with(|expenses| {
+        expenses
+            .borrow()
+            .iter()
+            .filter(|(_, e)| e.sacco_id == sacco_id && e.date >= start_time && e.date <= end_time)
+            .map(|(_, e)| e.amount)
+            .sum()
+    })
 }
 
-impl BoundedStorable for TrafficPattern {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// Helper function to analyze_expense_categories
+fn analyze_expense_categories(
+    sacco_id: u64,
+    start_time: u64,
+    end_time: u64,
+) -> Vec<ExpenseCategory> {
+    // Analyze expenses by different categories:
+    // 1. Fuel
+    // 2. Maintenance
+    // 3. Salaries
+    // 4. Insurance
+    let total_expenses = calculate_total_expenses(sacco_id, start_time, end_time);
+
+    EXPENSES.with(|expenses| {
+        let expenses_map = expenses.borrow();
+        let mut categories = HashMap::new();
+
+        for (_, expense) in expenses_map.iter() {
+            if expense.sacco_id == sacco_id
+                && expense.date >= start_time
+                && expense.date <= end_time
+            {
+                *categories.entry(expense.category.clone()).or_insert(0.0) += expense.amount;
+            }
+        }
+
+        categories
+            .into_iter()
+            .map(|(category, amount)| ExpenseCategory {
+                category,
+                amount,
+                percentage: if total_expenses > 0.0 {
+                    (amount / total_expenses) * 100.0
+                } else {
+                    0.0
+                },
+            })
+            .collect()
+    })
 }
 
-// Implementing Storable for CustomerFeedback
-impl Storable for CustomerFeedback {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// Helper function to update driver performance
+fn update_driver_performance(driver_id: u64, trip_revenue: f64) {
+    let mut updated_performance = current_driver_performance(driver_id);
+    updated_performance.trips_completed += 1;
+    updated_performance.total_revenue += trip_revenue;
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+    DRIVER_PERFORMANCE.with(|performances| {
+        performances
+            .borrow_mut()
+            .insert(updated_performance.id, updated_performance)
+    });
 }
 
-impl BoundedStorable for CustomerFeedback {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// Helper function to deduct compliance points from a driver's current month
+fn apply_compliance_penalty(driver_id: u64, penalty: f32) {
+    let mut updated_performance = current_driver_performance(driver_id);
+    updated_performance.compliance_score =
+        (updated_performance.compliance_score - penalty).max(0.0);
+
+    DRIVER_PERFORMANCE.with(|performances| {
+        performances
+            .borrow_mut()
+            .insert(updated_performance.id, updated_performance)
+    });
 }
 
-// Implementing Storable for Schedule
-impl Storable for Schedule {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// Helper function to get this month's performance record, or a fresh one
+fn current_driver_performance(driver_id: u64) -> DriverPerformance {
+    let current_month = calendar::month_of(time());
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+    DRIVER_PERFORMANCE
+        .with(|performances| {
+            performances
+                .borrow()
+                .iter()
+                .find(|(_, p)| p.driver_id == driver_id && p.month == current_month)
+                .map(|(_, p)| p.clone())
+        })
+        .unwrap_or_else(|| {
+            // Create new performance record if none exists
+            DriverPerformance {
+                id: next_id(Sequence::DriverPerformance),
+                driver_id,
+                month: current_month,
+                trips_completed: 0,
+                total_revenue: 0.0,
+                customer_rating: 0.0,
+                compliance_score: 100.0,
+            }
+        })
 }
 
-impl BoundedStorable for Schedule {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// A circular zone checked on every location update
+struct Zone {
+    zone_type: String,
+    zone_id: u64,
+    name: String,
+    latitude: f64,
+    longitude: f64,
+    radius: f64,
+    speed_limit: Option<f64>,
 }
 
-// Implementing Storable for LocationUpdate
-impl Storable for LocationUpdate {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+// What a matatu is doing at the time of a location update
+struct TrackingContext {
+    sacco_id: u64,
+    trip: Option<Trip>,
+    route: Option<Route>,
+    zones: Vec<Zone>,
+    route_line: Vec<(f64, f64)>,
+    projection: Option<geo::Projection>, // position of the current ping on the trip's route
+}
+
+// Helper function to run all tracking checks over a matatu's pings, sorted by time.
+// Pings older than the last tracked position are kept in history but not replayed.
+// Returns the latest ping that was processed.
+fn track_locations(matatu_id: u64, points: &[LocationUpdate]) -> Option<LocationUpdate> {
+    let mut state = TRACKING_STATES
+        .with(|states| states.borrow().get(&matatu_id))
+        .unwrap_or(TrackingState {
+            matatu_id,
+            ..Default::default()
+        });
+
+    let fresh: Vec<&LocationUpdate> = points
+        .iter()
+        .filter(|p| p.timestamp > state.timestamp)
+        .collect();
+    if fresh.is_empty() {
+        return None;
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+    let sacco_id = MATATUS
+        .with(|matatus| matatus.borrow().get(&matatu_id))
+        .map(|m| m.sacco_id)
+        .unwrap_or_default();
+    let trip = find_ongoing_trip(matatu_id);
+    let route = trip.as_ref().and_then(trip_route);
+    let zones = zones_for(sacco_id, route.as_ref());
+    let route_line = route
+        .as_ref()
+        .map(|r| route_polyline(r.id))
+        .unwrap_or_default();
+    let mut context = TrackingContext {
+        sacco_id,
+        trip,
+        route,
+        zones,
+        route_line,
+        projection: None,
+    };
+
+    for location in &fresh {
+        context.projection = context.route.as_ref().and_then(|_| {
+            geo::project_onto_polyline(&context.route_line, location.latitude, location.longitude)
+        });
+
+        process_geofences(location, &context, &mut state);
+        process_speed(location, &context, &mut state);
+        process_progress(location, &context, &mut state);
+
+        state.latitude = location.latitude;
+        state.longitude = location.longitude;
+        state.timestamp = location.timestamp;
     }
-}
 
-impl BoundedStorable for LocationUpdate {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+    TRACKING_STATES.with(|states| states.borrow_mut().insert(matatu_id, state));
+
+    fresh.last().map(|location| (*location).clone())
 }
 
-// Implementing Storable for FinancialReport
-impl Storable for FinancialReport {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+// Helper function to process geofences for a location update
+fn process_geofences(
+    location: &LocationUpdate,
+    context: &TrackingContext,
+    state: &mut TrackingState,
+) {
+    let TrackingContext {
+        sacco_id,
+        trip,
+        route,
+        zones,
+        projection,
+        ..
+    } = context;
+    let sacco_id = *sacco_id;
+    let has_previous_fix = state.timestamp > 0;
+
+    // Stage, depot and restricted zone transitions
+    for zone in zones {
+        let was_inside = has_previous_fix
+            && geo::within_radius(
+                state.latitude,
+                state.longitude,
+                zone.latitude,
+                zone.longitude,
+                zone.radius,
+            );
+        let is_inside = geo::within_radius(
+            location.latitude,
+            location.longitude,
+            zone.latitude,
+            zone.longitude,
+            zone.radius,
+        );
+
+        if was_inside == is_inside {
+            continue;
+        }
+
+        let event = GeofenceEvent {
+            id: next_id(Sequence::GeofenceEvent),
+            matatu_id: location.matatu_id,
+            trip_id: trip.as_ref().map(|t| t.id),
+            zone_type: zone.zone_type.clone(),
+            zone_id: zone.zone_id,
+            zone_name: zone.name.clone(),
+            event_type: if is_inside { "enter" } else { "exit" }.to_string(),
+            latitude: location.latitude,
+            longitude: location.longitude,
+            timestamp: location.timestamp,
+        };
+        GEOFENCE_EVENTS.with(|events| events.borrow_mut().insert(event.id, event));
+
+        if is_inside && zone.zone_type == "restricted" {
+            raise_incident(
+                sacco_id,
+                location,
+                trip.as_ref(),
+                None,
+                "restricted_zone",
+                format!("Entered restricted zone {}", zone.name),
+                0.0,
+            );
+        }
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+    // Route corridor check for the ongoing trip
+    let deviation = match (trip, route, projection) {
+        (Some(_), Some(route), Some(p)) => Some((
+            p.distance_from_line,
+            p.distance_from_line > route.corridor_buffer,
+        )),
+        _ => None,
+    };
+
+    match deviation {
+        Some((distance, true)) if !state.off_route => {
+            let incident_id = raise_incident(
+                sacco_id,
+                location,
+                trip.as_ref(),
+                route.as_ref(),
+                "route_deviation",
+                format!("Matatu is {:.0}m off its assigned route", distance),
+                distance,
+            );
+            state.off_route = true;
+            state.deviation_incident = Some(incident_id);
+        }
+        Some((_, true)) => {}
+        _ => {
+            // Back inside the corridor, or no longer on a trip
+            if let Some(incident_id) = state.deviation_incident.take() {
+                let _ = resolve_incident(incident_id);
+            }
+            state.off_route = false;
+        }
     }
 }
 
-impl BoundedStorable for FinancialReport {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
-}
+// Helper function to detect sustained overspeeding for a location update
+fn process_speed(location: &LocationUpdate, context: &TrackingContext, state: &mut TrackingState) {
+    let policy = speed_policy_for(context.sacco_id);
+
+    // The strictest limit applies: PSV cap, SACCO policy and any speed zone we're in
+    let mut speed_limit = policy.speed_limit.min(PSV_SPEED_LIMIT);
+    let mut zone_id = None;
+    for zone in &context.zones {
+        if let Some(limit) = zone.speed_limit {
+            let inside = geo::within_radius(
+                location.latitude,
+                location.longitude,
+                zone.latitude,
+                zone.longitude,
+                zone.radius,
+            );
+            if inside && limit < speed_limit {
+                speed_limit = limit;
+                zone_id = Some(zone.zone_id);
+            }
+        }
+    }
 
-// Implementing Storable for ExpenseCategory
-impl Storable for ExpenseCategory {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+    if location.speed <= speed_limit {
+        // Close any violation that was in progress
+        if let Some(violation_id) = state.speed_violation.take() {
+            SPEED_VIOLATIONS.with(|violations| {
+                let mut violations_map = violations.borrow_mut();
+                if let Some(mut violation) = violations_map.get(&violation_id) {
+                    violation.ended_at = Some(location.timestamp);
+                    violations_map.insert(violation_id, violation);
+                }
+            });
+        }
+        state.overspeed_pings = 0;
+        return;
+    }
+
+    state.overspeed_pings += 1;
+
+    if let Some(violation_id) = state.speed_violation {
+        // Ongoing violation, keep track of the worst speed
+        SPEED_VIOLATIONS.with(|violations| {
+            let mut violations_map = violations.borrow_mut();
+            if let Some(mut violation) = violations_map.get(&violation_id) {
+                if location.speed > violation.max_speed {
+                    let was_governor = violation.violation_type == "speed_governor";
+                    violation.max_speed = location.speed;
+                    if location.speed > PSV_SPEED_LIMIT && !was_governor {
+                        violation.violation_type = "speed_governor".to_string();
+                        if let Some(driver_id) = violation.driver_id {
+                            apply_compliance_penalty(
+                                driver_id,
+                                GOVERNOR_PENALTY - OVERSPEED_PENALTY,
+                            );
+                        }
+                    }
+                }
+                violations_map.insert(violation_id, violation);
+            }
+        });
+        return;
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+    if state.overspeed_pings < policy.sustained_pings {
+        return;
     }
-}
 
-impl BoundedStorable for ExpenseCategory {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
-}
+    // Going faster than the PSV cap means the speed governor isn't working
+    let governor_breach = location.speed > PSV_SPEED_LIMIT;
+    let driver_id = context.trip.as_ref().map(|t| t.driver_id);
+    let violation = SpeedViolation {
+        id: next_id(Sequence::SpeedViolation),
+        sacco_id: context.sacco_id,
+        matatu_id: location.matatu_id,
+        driver_id,
+        trip_id: context.trip.as_ref().map(|t| t.id),
+        zone_id,
+        violation_type: if governor_breach {
+            "speed_governor"
+        } else {
+            "overspeed"
+        }
+        .to_string(),
+        speed_limit,
+        max_speed: location.speed,
+        latitude: location.latitude,
+        longitude: location.longitude,
+        started_at: location.timestamp,
+        ended_at: None,
+    };
 
-// Implementing Storable for RevenueSource
-impl Storable for RevenueSource {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+    SPEED_VIOLATIONS.with(|violations| {
+        violations
+            .borrow_mut()
+            .insert(violation.id, violation.clone())
+    });
+    state.speed_violation = Some(violation.id);
+
+    if let Some(driver_id) = driver_id {
+        apply_compliance_penalty(
+            driver_id,
+            if governor_breach {
+                GOVERNOR_PENALTY
+            } else {
+                OVERSPEED_PENALTY
+            },
+        );
     }
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+// Helper function to track progress along the route and learn segment speeds
+fn process_progress(
+    location: &LocationUpdate,
+    context: &TrackingContext,
+    state: &mut TrackingState,
+) {
+    state.average_speed = if state.average_speed > 0.0 {
+        RECENT_SPEED_WEIGHT * location.speed + (1.0 - RECENT_SPEED_WEIGHT) * state.average_speed
+    } else {
+        location.speed
+    };
+
+    let (route, projection) = match (&context.trip, &context.route, &context.projection) {
+        (Some(_), Some(route), Some(projection)) => (route, projection),
+        _ => {
+            state.route_id = None;
+            state.route_progress = 0.0;
+            return;
+        }
+    };
+
+    let segment_index = projection.segment_index as u32;
+    let same_segment = state.route_id == Some(route.id) && state.segment_index == segment_index;
+    let on_route = projection.distance_from_line <= route.corridor_buffer;
+
+    // Only learn from forward movement within one segment while on the route
+    if same_segment && on_route && location.timestamp > state.timestamp {
+        let distance = projection.distance_along - state.route_progress;
+        let seconds = (location.timestamp - state.timestamp) as f64 / 1_000_000_000.0;
+        let observed_speed = distance / seconds * 3.6;
+
+        if distance > 0.0 && observed_speed <= PSV_SPEED_LIMIT * 1.5 {
+            record_segment_speed(route.id, segment_index, observed_speed, location.timestamp);
+            record_traffic(route.id, location.timestamp, |stats| {
+                let samples = (stats.segment_samples + 1).min(MAX_SEGMENT_SAMPLES);
+                stats.average_segment_speed +=
+                    (observed_speed - stats.average_segment_speed) / samples as f64;
+                stats.segment_samples = samples;
+            });
+        }
     }
+
+    state.route_id = Some(route.id);
+    state.segment_index = segment_index;
+    state.route_progress = projection.distance_along;
 }
 
-impl BoundedStorable for RevenueSource {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// Helper function to fold an observed speed into the segment's running average
+fn record_segment_speed(route_id: u64, segment_index: u32, speed: f64, timestamp: u64) {
+    let key = SegmentKey {
+        route_id,
+        segment_index,
+    };
+
+    SEGMENT_STATS.with(|stats| {
+        let mut stats_map = stats.borrow_mut();
+        let mut segment = stats_map.get(&key).unwrap_or(SegmentStats {
+            route_id,
+            segment_index,
+            ..Default::default()
+        });
+
+        // Running mean that turns into a moving average once enough samples exist
+        let samples = (segment.samples + 1).min(MAX_SEGMENT_SAMPLES);
+        segment.average_speed += (speed - segment.average_speed) / samples as f64;
+        segment.samples = samples;
+        segment.updated_at = timestamp;
+
+        stats_map.insert(key, segment);
+    });
 }
 
-// Memory management
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
+// Helper function to update the traffic statistics of the hour of the week `timestamp` falls in
+fn record_traffic(route_id: u64, timestamp: u64, update: impl FnOnce(&mut TrafficStats)) {
+    let key = TrafficKey {
+        route_id,
+        day_of_week: calendar::day_of_week(timestamp),
+        hour: calendar::hour_of_day(timestamp),
+    };
 
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create a counter")
-    );
+    TRAFFIC_STATS.with(|stats| {
+        let mut stats_map = stats.borrow_mut();
+        let mut window = stats_map.get(&key).unwrap_or(TrafficStats {
+            route_id,
+            day_of_week: key.day_of_week,
+            hour: key.hour,
+            ..Default::default()
+        });
 
-    static SACCOS: RefCell<StableBTreeMap<u64, SACCO, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
-        ));
+        update(&mut window);
+        window.updated_at = timestamp;
 
-    static MATATUS: RefCell<StableBTreeMap<u64, Matatu, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
-        ));
+        stats_map.insert(key, window);
+    });
+}
 
-    static DRIVERS: RefCell<StableBTreeMap<u64, Driver, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
-        ));
+// Helper function to get all traffic statistics of a route, ordered by day and hour
+fn route_traffic_stats(route_id: u64) -> Vec<TrafficStats> {
+    let first = TrafficKey {
+        route_id,
+        ..Default::default()
+    };
 
-    static EXPENSES: RefCell<StableBTreeMap<u64, Expense, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
-        ));
+    TRAFFIC_STATS.with(|stats| {
+        stats
+            .borrow()
+            .range(first..)
+            .take_while(|(key, _)| key.route_id == route_id)
+            .map(|(_, s)| s.clone())
+            .collect()
+    })
+}
 
-    static REVENUES: RefCell<StableBTreeMap<u64, Revenue, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
-        ));
+// Helper function to count passengers boarding a route in the hour of the week
+// `timestamp` falls in. A service day is folded into the averages once a later
+// day is counted.
+fn record_demand(route_id: u64, timestamp: u64, passengers: u32) {
+    let key = DemandKey {
+        route_id,
+        day_of_week: calendar::day_of_week(timestamp),
+        hour: calendar::hour_of_day(timestamp),
+    };
+    let day = calendar::start_of_day(timestamp);
+
+    DEMAND_STATS.with(|stats| {
+        let mut stats_map = stats.borrow_mut();
+        let mut window = stats_map.get(&key).unwrap_or(DemandStats {
+            route_id,
+            day_of_week: key.day_of_week,
+            hour: key.hour,
+            ..Default::default()
+        });
+
+        if day == window.current_day {
+            window.current_passengers += passengers;
+        } else if day > window.current_day {
+            settle_demand_day(&mut window);
+            window.current_day = day;
+            window.current_passengers = passengers;
+        } else {
+            // A late trip from an earlier day counts on its own
+            observe_demand(&mut window, day, passengers);
+        }
+        window.updated_at = time();
 
-    static TRIPS: RefCell<StableBTreeMap<u64, Trip, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
-        ));
+        stats_map.insert(key, window);
+    });
+}
 
-    static MAINTENANCE_RECORDS: RefCell<StableBTreeMap<u64, Maintenance, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
-        ));
+// Helper function to fold the service day still being counted into the averages
+fn settle_demand_day(stats: &mut DemandStats) {
+    if stats.current_day > 0 {
+        observe_demand(stats, stats.current_day, stats.current_passengers);
+        stats.current_day = 0;
+        stats.current_passengers = 0;
+    }
+}
 
-    static DRIVER_PERFORMANCE: RefCell<StableBTreeMap<u64, DriverPerformance, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
-        ));
+// Helper function to add one service day of passengers to the averages.
+// Holidays and school calendar periods are scaled back to an ordinary day
+// so they don't skew the forecast.
+fn observe_demand(stats: &mut DemandStats, day: u64, passengers: u32) {
+    let ordinary = passengers as f64 / demand_factor(stats.route_id, day);
+    let days = (stats.days + 1).min(MAX_DEMAND_SAMPLES);
 
-    static FUEL_RECORDS: RefCell<StableBTreeMap<u64, FuelConsumption, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
-        ));
+    stats.average_passengers += (ordinary - stats.average_passengers) / days as f64;
+    stats.smoothed_passengers = if stats.days == 0 {
+        ordinary
+    } else {
+        DEMAND_SMOOTHING * ordinary + (1.0 - DEMAND_SMOOTHING) * stats.smoothed_passengers
+    };
+    stats.days = days;
+}
 
-    static ROUTES: RefCell<StableBTreeMap<u64, Route, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
-        ));
+// Helper function to get the combined holiday and school calendar multiplier of a route's day
+fn demand_factor(route_id: u64, day: u64) -> f64 {
+    DEMAND_ADJUSTMENTS.with(|adjustments| {
+        adjustments
+            .borrow()
+            .iter()
+            .filter(|(_, a)| a.route_id.unwrap_or(route_id) == route_id)
+            .filter(|(_, a)| a.start_date <= day && a.end_date > day)
+            .map(|(_, a)| a.factor)
+            .product()
+    })
+}
 
-    static CUSTOMER_FEEDBACK: RefCell<StableBTreeMap<u64, CustomerFeedback, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
-        ));
+// Helper function to forecast a route's passengers for each hour of a day
+// with enough service days observed
+fn route_demand_forecast(route_id: u64, day_start: u64) -> Vec<DemandForecast> {
+    let day_of_week = calendar::day_of_week(day_start);
+    let first = DemandKey {
+        route_id,
+        day_of_week,
+        hour: 0,
+    };
+    let observed: Vec<DemandStats> = DEMAND_STATS.with(|stats| {
+        stats
+            .borrow()
+            .range(first..)
+            .take_while(|(key, _)| key.route_id == route_id && key.day_of_week == day_of_week)
+            .map(|(_, s)| s.clone())
+            .collect()
+    });
 
-    static SCHEDULES: RefCell<StableBTreeMap<u64, Schedule, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
-        ));
+    let adjustment = demand_factor(route_id, day_start);
+    observed
+        .into_iter()
+        .filter_map(|mut stats| {
+            // A day still being counted is complete once the forecast day has begun
+            if stats.current_day < day_start {
+                settle_demand_day(&mut stats);
+            }
+            if stats.days < MIN_DEMAND_SAMPLES {
+                return None;
+            }
 
-    static LOCATION_UPDATES: RefCell<StableBTreeMap<u64, LocationUpdate, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
-        ));
+            let start_time = day_start + stats.hour as u64 * HOUR_NANOS;
+            Some(DemandForecast {
+                route_id,
+                start_time,
+                end_time: start_time + HOUR_NANOS,
+                days: stats.days,
+                seasonal_average: stats.average_passengers,
+                smoothed: stats.smoothed_passengers,
+                adjustment,
+                passengers: (stats.smoothed_passengers * adjustment).round() as u32,
+            })
+        })
+        .collect()
+}
 
-    static FINANCIAL_REPORTS: RefCell<StableBTreeMap<u64, FinancialReport, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
-        ));
+// Helper function to turn a route's traffic statistics into traffic patterns.
+// Each hour's travel time comes from completed trips, or from segment speeds
+// when too few trips started in that hour. Only congested hours become
+// patterns; consecutive hours at the same level are merged and the most
+// congested windows are kept. Returns None when nothing has been observed.
+fn learned_patterns(route: &Route, limit: usize) -> Option<Vec<TrafficPattern>> {
+    if route.estimated_time == 0 {
+        return None;
+    }
 
-    static EXPENSE_CATEGORIES: RefCell<StableBTreeMap<u64, ExpenseCategory, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
-        ));
+    let planned = route.estimated_time as f64;
+    let observed: Vec<(u8, u8, f64)> = route_traffic_stats(route.id)
+        .into_iter()
+        .filter_map(|stats| {
+            let minutes = if stats.trips >= MIN_TRAFFIC_SAMPLES {
+                stats.average_duration
+            } else if stats.segment_samples >= MIN_TRAFFIC_SAMPLES
+                && stats.average_segment_speed > 0.0
+            {
+                planned * route_average_speed(route) / stats.average_segment_speed
+            } else {
+                return None;
+            };
+            Some((stats.day_of_week, stats.hour, minutes))
+        })
+        .collect();
+    if observed.is_empty() {
+        return None;
+    }
 
-    static REVENUE_SOURCES: RefCell<StableBTreeMap<u64, RevenueSource, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
-        ));
+    let mut patterns: Vec<(TrafficPattern, u32)> = Vec::new(); // pattern and hours merged into it
+    for (day, hour, minutes) in observed {
+        let congestion_level = match minutes / planned {
+            r if r <= 1.1 => 1,
+            r if r <= 1.3 => 2,
+            r if r <= 1.6 => 3,
+            r if r <= 2.0 => 4,
+            _ => 5,
+        };
+        if congestion_level < 2 {
+            continue;
+        }
+        let delay = (minutes - planned).max(0.0);
 
-    static TIME_WINDOWS: RefCell<StableBTreeMap<u64, TimeWindow, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
-        ));
+        match patterns.last_mut() {
+            Some((last, hours))
+                if last.time_window.day_of_week == day
+                    && last.time_window.end_hour == hour
+                    && last.congestion_level == congestion_level =>
+            {
+                last.average_delay = ((last.average_delay as f64 * *hours as f64 + delay)
+                    / (*hours + 1) as f64)
+                    .round() as u32;
+                last.time_window.end_hour = hour + 1;
+                *hours += 1;
+            }
+            _ => patterns.push((
+                TrafficPattern {
+                    time_window: TimeWindow {
+                        start_hour: hour,
+                        end_hour: hour + 1,
+                        day_of_week: day,
+                    },
+                    congestion_level,
+                    average_delay: delay.round() as u32,
+                },
+                1,
+            )),
+        }
+    }
 
-    static TRAFFIC_PATTERNS: RefCell<StableBTreeMap<u64, TrafficPattern, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
-        ));
+    let mut patterns: Vec<TrafficPattern> = patterns.into_iter().map(|(p, _)| p).collect();
+    patterns.sort_by_key(|p| std::cmp::Reverse((p.congestion_level, p.average_delay)));
+    patterns.truncate(limit);
+    patterns.sort_by_key(|p| (p.time_window.day_of_week, p.time_window.start_hour));
 
+    Some(patterns)
 }
 
-// Functions
+// Helper function to validate, deduplicate, store and process location pings.
+// Tracking, ETA and compaction run once per matatu no matter how many pings it sent.
+fn ingest_locations(
+    payloads: Vec<LocationUpdatePayload>,
+) -> (LocationBatchResult, Vec<LocationUpdate>) {
+    let now = time();
+    let caller = caller();
+    let mut result = LocationBatchResult::default();
+    let mut by_matatu: BTreeMap<u64, Vec<LocationUpdate>> = BTreeMap::new();
+    let mut senders: BTreeMap<u64, Result<PingSender, String>> = BTreeMap::new();
+    let mut nonces: BTreeMap<PingSender, NonceWindow> = BTreeMap::new();
+
+    for (index, payload) in payloads.into_iter().enumerate() {
+        let checked = validate_location(&payload, now)
+            .and_then(|_| {
+                senders
+                    .entry(payload.matatu_id)
+                    .or_insert_with(|| authorize_ping(caller, payload.matatu_id))
+                    .clone()
+            })
+            .and_then(|sender| {
+                nonces
+                    .entry(sender)
+                    .or_insert_with(|| NonceWindow::load(sender))
+                    .accept(payload.nonce)
+            });
 
-// Create SACCO
-#[ic_cdk::update]
-fn create_sacco(payload: CreateSACCOPayload) -> Result<SACCO, Message> {
-    if payload.name.is_empty() || payload.contact.is_empty() || payload.email.is_empty() {
-        return Err(Message::InvalidPayload(
-            "Missing required fields".to_string(),
-        ));
+        if let Err(reason) = checked {
+            result.rejected.push(RejectedLocation {
+                index: index as u32,
+                reason,
+            });
+            continue;
+        }
+
+        by_matatu
+            .entry(payload.matatu_id)
+            .or_default()
+            .push(LocationUpdate {
+                matatu_id: payload.matatu_id,
+                latitude: payload.latitude,
+                longitude: payload.longitude,
+                speed: payload.speed,
+                timestamp: payload.timestamp.unwrap_or(now),
+            });
     }
 
-    let sacco_id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Counter increment failed");
+    // Remember the nonces we've seen and when each sender was last heard from
+    for (sender, window) in nonces {
+        window.save(sender, now);
+    }
 
-    let sacco = SACCO {
-        id: sacco_id,
-        name: payload.name,
-        location: payload.location,
-        contact: payload.contact,
-        email: payload.email,
-        created_at: time(),
-    };
+    let mut stored = Vec::new();
+    for (matatu_id, mut points) in by_matatu {
+        let received = points.len();
+
+        // Put out-of-order pings back in order and drop repeats, within the batch and against history
+        points.sort_by_key(|p| p.timestamp);
+        points.dedup_by_key(|p| p.timestamp);
+        points.retain(|p| {
+            !LOCATION_UPDATES.with(|updates| {
+                updates.borrow().contains_key(&LocationKey {
+                    matatu_id,
+                    timestamp: p.timestamp,
+                })
+            })
+        });
+        result.duplicates += (received - points.len()) as u32;
 
-    SACCOS.with(|saccos| {
-        saccos.borrow_mut().insert(sacco_id, sacco.clone());
-    });
+        if points.is_empty() {
+            continue;
+        }
 
-    Ok(sacco)
-}
+        LOCATION_UPDATES.with(|updates| {
+            let mut updates_map = updates.borrow_mut();
+            for point in &points {
+                updates_map.insert(
+                    LocationKey {
+                        matatu_id,
+                        timestamp: point.timestamp,
+                    },
+                    point.clone(),
+                );
+            }
+        });
+        result.accepted += points.len() as u32;
 
-// Register Matatu
-#[ic_cdk::update]
-fn register_matatu(payload: RegisterMatatuPayload) -> Result<Matatu, Message> {
-    if payload.plate_number.is_empty() || payload.route.is_empty() {
-        return Err(Message::InvalidPayload(
-            "Missing required fields".to_string(),
-        ));
-    }
+        // Record geofence transitions, route deviations and speed violations
+        if let Some(latest) = track_locations(matatu_id, &points) {
+            // Update estimated arrival times for affected schedules
+            update_arrival_estimates(matatu_id, &latest);
+        }
 
-    let sacco_exists = SACCOS.with(|saccos| saccos.borrow().contains_key(&payload.sacco_id));
-    if !sacco_exists {
-        return Err(Message::NotFound("SACCO not found".to_string()));
-    }
+        // Compress history that has aged out of the raw window
+        compact_matatu_history(matatu_id, now);
 
-    let matatu_id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Counter increment failed");
+        stored.extend(points);
+    }
 
-    let matatu = Matatu {
-        id: matatu_id,
-        sacco_id: payload.sacco_id,
-        plate_number: payload.plate_number,
-        capacity: payload.capacity,
-        route: payload.route,
-        status: "active".to_string(),
-    };
+    (result, stored)
+}
 
-    MATATUS.with(|matatus| {
-        matatus.borrow_mut().insert(matatu_id, matatu.clone());
-    });
+// Who a location ping came from
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum PingSender {
+    Device(u64),
+    Driver(u64),
+}
 
-    Ok(matatu)
+// Nonces accepted from one sender during a call
+struct NonceWindow {
+    floor: u64, // highest nonce accepted before this call
+    highest: u64,
+    seen: BTreeSet<u64>,
 }
 
-// Register Driver
-#[ic_cdk::update]
-fn register_driver(payload: RegisterDriverPayload) -> Result<Driver, Message> {
-    if payload.name.is_empty() || payload.license_number.is_empty() {
-        return Err(Message::InvalidPayload(
-            "Missing required fields".to_string(),
-        ));
+impl NonceWindow {
+    fn load(sender: PingSender) -> Self {
+        let floor = match sender {
+            PingSender::Device(device_id) => DEVICES
+                .with(|devices| devices.borrow().get(&device_id))
+                .map(|d| d.last_nonce)
+                .unwrap_or_default(),
+            PingSender::Driver(driver_id) => DRIVER_PING_NONCES
+                .with(|nonces| nonces.borrow().get(&driver_id))
+                .unwrap_or_default(),
+        };
+
+        NonceWindow {
+            floor,
+            highest: floor,
+            seen: BTreeSet::new(),
+        }
     }
 
-    let sacco_exists = SACCOS.with(|saccos| saccos.borrow().contains_key(&payload.sacco_id));
-    if !sacco_exists {
-        return Err(Message::NotFound("SACCO not found".to_string()));
+    // Pings within a batch may arrive out of order, but each nonce is only good once
+    fn accept(&mut self, nonce: Option<u64>) -> Result<(), String> {
+        let nonce = nonce.ok_or("Missing nonce".to_string())?;
+        if nonce <= self.floor || !self.seen.insert(nonce) {
+            return Err("Replayed nonce".to_string());
+        }
+
+        self.highest = self.highest.max(nonce);
+        Ok(())
     }
 
-    let driver_id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Counter increment failed");
+    fn save(&self, sender: PingSender, now: u64) {
+        match sender {
+            PingSender::Device(device_id) => DEVICES.with(|devices| {
+                let mut devices_map = devices.borrow_mut();
+                if let Some(mut device) = devices_map.get(&device_id) {
+                    device.last_nonce = self.highest;
+                    device.last_seen = Some(now);
+                    devices_map.insert(device_id, device);
+                }
+            }),
+            PingSender::Driver(driver_id) => {
+                DRIVER_PING_NONCES
+                    .with(|nonces| nonces.borrow_mut().insert(driver_id, self.highest));
+            }
+        }
+    }
+}
 
-    let driver = Driver {
-        id: driver_id,
-        sacco_id: payload.sacco_id,
-        name: payload.name,
-        license_number: payload.license_number,
-        contact: payload.contact,
-        assigned_matatu: None,
-    };
+// Helper function to check that the caller may report locations for a matatu
+fn authorize_ping(caller: Principal, matatu_id: u64) -> Result<PingSender, String> {
+    let device = DEVICES.with(|devices| {
+        devices
+            .borrow()
+            .iter()
+            .find(|(_, d)| {
+                d.matatu_id == matatu_id && d.status == "active" && d.principal == caller
+            })
+            .map(|(id, _)| id)
+    });
+    if let Some(device_id) = device {
+        return Ok(PingSender::Device(device_id));
+    }
 
-    DRIVERS.with(|drivers| {
-        drivers.borrow_mut().insert(driver_id, driver.clone());
+    let driver = DRIVERS.with(|drivers| {
+        drivers
+            .borrow()
+            .iter()
+            .find(|(_, d)| d.assigned_matatu == Some(matatu_id) && d.principal == Some(caller))
+            .map(|(id, _)| id)
     });
 
-    Ok(driver)
+    driver
+        .map(PingSender::Driver)
+        .ok_or("Caller is not the matatu's tracker or assigned driver".to_string())
 }
-
-// Assign Driver to Matatu
-#[ic_cdk::update]
-fn assign_driver_to_matatu(driver_id: u64, matatu_id: u64) -> Result<Driver, Message> {
-    let matatu_exists = MATATUS.with(|matatus| matatus.borrow().contains_key(&matatu_id));
-    if !matatu_exists {
-        return Err(Message::NotFound("Matatu not found".to_string()));
+
+// Helper function to validate a single location ping
+fn validate_location(payload: &LocationUpdatePayload, now: u64) -> Result<(), String> {
+    if !geo::is_valid_coordinate(payload.latitude, payload.longitude) {
+        return Err("Invalid coordinates".to_string());
     }
 
-    DRIVERS.with(|drivers| {
-        let mut drivers_map = drivers.borrow_mut();
+    if !payload.speed.is_finite() || payload.speed < 0.0 {
+        return Err("Invalid speed".to_string());
+    }
 
-        if let Some(driver) = drivers_map.get(&driver_id) {
-            let mut updated_driver = driver.clone();
-            updated_driver.assigned_matatu = Some(matatu_id);
-            drivers_map.insert(driver_id, updated_driver.clone());
-            Ok(updated_driver)
-        } else {
-            Err(Message::NotFound("Driver not found".to_string()))
-        }
-    })
-}
+    if payload.timestamp.is_some_and(|t| t > now + MAX_CLOCK_SKEW) {
+        return Err("Timestamp is in the future".to_string());
+    }
 
-#[ic_cdk::update]
-fn start_trip(payload: StartTripPayload) -> Result<Trip, Message> {
-    // Validate matatu and driver existence
     let matatu_exists = MATATUS.with(|matatus| matatus.borrow().contains_key(&payload.matatu_id));
-    let driver_exists = DRIVERS.with(|drivers| drivers.borrow().contains_key(&payload.driver_id));
-
-    if !matatu_exists || !driver_exists {
-        return Err(Message::NotFound("Matatu or Driver not found".to_string()));
+    if !matatu_exists {
+        return Err("Matatu not found".to_string());
     }
 
-    let trip_id = ID_COUNTER.with(|counter| {
-        let current_value = *counter.borrow().get();
-        counter.borrow_mut().set(current_value + 1).unwrap();
-        current_value + 1
-    });
+    Ok(())
+}
 
-    let trip = Trip {
-        id: trip_id,
-        matatu_id: payload.matatu_id,
-        driver_id: payload.driver_id,
-        start_time: time(),
-        end_time: None,
-        passengers: 0,
-        route: payload.route,
-        status: "ongoing".to_string(),
-        revenue: 0.0,
+// Helper function to read a matatu's history between two timestamps
+fn location_history(matatu_id: u64, start_time: u64, end_time: u64) -> Vec<LocationUpdate> {
+    let range = LocationKey {
+        matatu_id,
+        timestamp: start_time,
+    }..=LocationKey {
+        matatu_id,
+        timestamp: end_time,
     };
 
-    TRIPS.with(|trips| trips.borrow_mut().insert(trip_id, trip.clone()));
-    Ok(trip)
+    LOCATION_UPDATES.with(|updates| updates.borrow().range(range).map(|(_, u)| u).collect())
 }
 
-#[ic_cdk::update]
-fn end_trip(payload: EndTripPayload) -> Result<Trip, Message> {
-    TRIPS.with(|trips| {
-        let mut trips_map = trips.borrow_mut();
-        if let Some(mut trip) = trips_map.get(&payload.trip_id) {
-            if trip.status != "ongoing" {
-                return Err(Message::Error("Trip is not ongoing".to_string()));
+// Helper function to compress whole hourly buckets that are older than the raw window.
+// Returns the number of points removed.
+fn compact_matatu_history(matatu_id: u64, now: u64) -> u64 {
+    let retention = LOCATION_RETENTION.with(|cell| cell.borrow().get().clone());
+    let mut state = match TRACKING_STATES.with(|states| states.borrow().get(&matatu_id)) {
+        Some(state) => state,
+        None => return 0,
+    };
+
+    // Only compress buckets that have completely left the raw window
+    let cutoff = now.saturating_sub(retention.raw_window);
+    let cutoff = cutoff - cutoff % HISTORY_BUCKET_NANOS;
+    if cutoff <= state.compacted_until {
+        return 0;
+    }
+
+    let mut removed = 0;
+    let mut bucket_start = state.compacted_until;
+    while bucket_start < cutoff {
+        // Jump straight to the next bucket that has data
+        let next_point = LOCATION_UPDATES.with(|updates| {
+            updates
+                .borrow()
+                .range(
+                    LocationKey {
+                        matatu_id,
+                        timestamp: bucket_start,
+                    }..LocationKey {
+                        matatu_id,
+                        timestamp: cutoff,
+                    },
+                )
+                .next()
+                .map(|(key, _)| key.timestamp)
+        });
+        let points = match next_point {
+            Some(timestamp) => {
+                bucket_start = timestamp - timestamp % HISTORY_BUCKET_NANOS;
+                location_history(
+                    matatu_id,
+                    bucket_start,
+                    bucket_start + HISTORY_BUCKET_NANOS - 1,
+                )
+            }
+            None => break,
+        };
+
+        let line: Vec<(f64, f64)> = points.iter().map(|p| (p.latitude, p.longitude)).collect();
+        let kept = geo::simplify(&line, retention.tolerance);
+
+        LOCATION_UPDATES.with(|updates| {
+            let mut updates_map = updates.borrow_mut();
+            for (index, point) in points.iter().enumerate() {
+                if kept.binary_search(&index).is_err() {
+                    updates_map.remove(&LocationKey {
+                        matatu_id,
+                        timestamp: point.timestamp,
+                    });
+                    removed += 1;
+                }
             }
+        });
 
-            trip.end_time = Some(time());
-            trip.passengers = payload.passengers;
-            trip.revenue = payload.revenue;
-            trip.status = "completed".to_string();
+        bucket_start += HISTORY_BUCKET_NANOS;
+    }
 
-            // Update driver performance
-            update_driver_performance(trip.driver_id, payload.revenue);
+    state.compacted_until = cutoff;
+    TRACKING_STATES.with(|states| states.borrow_mut().insert(matatu_id, state));
 
-            trips_map.insert(payload.trip_id, trip.clone());
-            Ok(trip)
-        } else {
-            Err(Message::NotFound("Trip not found".to_string()))
-        }
-    })
+    removed
 }
 
-#[ic_cdk::query]
-fn get_driver_performance(driver_id: u64, month: u64) -> Result<DriverPerformance, Message> {
-    DRIVER_PERFORMANCE.with(|performances| {
-        performances
+// Helper function to rebuild a matatu's route position from its tracking state
+fn tracked_projection(state: &TrackingState) -> geo::Projection {
+    geo::Projection {
+        segment_index: state.segment_index as usize,
+        distance_from_line: 0.0,
+        distance_along: state.route_progress,
+    }
+}
+
+// Helper function to get the tickets of passengers still on board a trip
+fn onboard_tickets(trip_id: u64) -> Vec<Ticket> {
+    TICKETS.with(|tickets| {
+        tickets
             .borrow()
             .iter()
-            .find(|(_, p)| p.driver_id == driver_id && p.month == month)
-            .map(|(_, p)| p.clone())
-            .ok_or(Message::NotFound(
-                "Performance record not found".to_string(),
-            ))
+            .filter(|(_, t)| t.trip_id == trip_id && t.status == "onboard")
+            .map(|(_, t)| t.clone())
+            .collect()
     })
 }
 
-#[ic_cdk::query]
-fn get_matatu_analytics(matatu_id: u64) -> Result<MatatuAnalytics, Message> {
-    let total_trips = TRIPS.with(|trips| {
-        trips
-            .borrow()
-            .iter()
-            .filter(|(_, t)| t.matatu_id == matatu_id)
-            .count()
+// Helper function to check whether a passenger will still be on board past a stop
+fn stays_on_past(ticket: &Ticket, stop_sequence: u32) -> bool {
+    match ticket
+        .alighting_stop_id
+        .and_then(|id| ROUTE_STOPS.with(|stops| stops.borrow().get(&id)))
+    {
+        Some(alighting) => alighting.sequence > stop_sequence,
+        None => true, // unknown destination, assume they ride to the end
+    }
+}
+
+// Helper function to close all open tickets of a trip
+fn complete_trip_tickets(trip_id: u64) {
+    let onboard = onboard_tickets(trip_id);
+    TICKETS.with(|tickets| {
+        let mut tickets_map = tickets.borrow_mut();
+        for mut ticket in onboard {
+            ticket.status = "completed".to_string();
+            tickets_map.insert(ticket.id, ticket);
+        }
     });
+}
 
-    let total_revenue = TRIPS.with(|trips| {
-        trips
+// Helper function to get the speed policy of a SACCO, falling back to the PSV limit
+fn speed_policy_for(sacco_id: u64) -> SpeedPolicy {
+    SPEED_POLICIES
+        .with(|policies| policies.borrow().get(&sacco_id))
+        .unwrap_or(SpeedPolicy {
+            sacco_id,
+            speed_limit: PSV_SPEED_LIMIT,
+            sustained_pings: DEFAULT_SUSTAINED_PINGS,
+            updated_at: 0,
+        })
+}
+
+// Helper function to collect the zones relevant to a matatu
+fn zones_for(sacco_id: u64, route: Option<&Route>) -> Vec<Zone> {
+    let mut zones: Vec<Zone> = GEOFENCES.with(|geofences| {
+        geofences
             .borrow()
             .iter()
-            .filter(|(_, t)| t.matatu_id == matatu_id)
-            .map(|(_, t)| t.revenue)
-            .sum()
+            .filter(|(_, g)| g.sacco_id == sacco_id)
+            .map(|(_, g)| Zone {
+                zone_type: g.zone_type.clone(),
+                zone_id: g.id,
+                name: g.name.clone(),
+                latitude: g.latitude,
+                longitude: g.longitude,
+                radius: g.radius,
+                speed_limit: g.speed_limit,
+            })
+            .collect()
     });
 
-    let maintenance_costs = MAINTENANCE_RECORDS.with(|records| {
-        records
+    if let Some(route) = route {
+        zones.extend(get_ordered_stops(route.id).into_iter().map(|stop| Zone {
+            zone_type: "stage".to_string(),
+            zone_id: stop.id,
+            name: stop.name,
+            latitude: stop.latitude,
+            longitude: stop.longitude,
+            radius: stop.radius,
+            speed_limit: None,
+        }));
+    }
+
+    zones
+}
+
+// Helper function to record a new incident
+fn raise_incident(
+    sacco_id: u64,
+    location: &LocationUpdate,
+    trip: Option<&Trip>,
+    route: Option<&Route>,
+    incident_type: &str,
+    description: String,
+    distance_from_route: f64,
+) -> u64 {
+    let incident = Incident {
+        id: next_id(Sequence::Incident),
+        sacco_id,
+        matatu_id: location.matatu_id,
+        trip_id: trip.map(|t| t.id),
+        driver_id: trip.map(|t| t.driver_id),
+        route_id: route.map(|r| r.id),
+        incident_type: incident_type.to_string(),
+        description,
+        latitude: location.latitude,
+        longitude: location.longitude,
+        distance_from_route,
+        status: "open".to_string(),
+        created_at: location.timestamp,
+        resolved_at: None,
+    };
+
+    INCIDENTS.with(|incidents| incidents.borrow_mut().insert(incident.id, incident.clone()));
+    incident.id
+}
+
+// Helper function to get a route passengers may look up, archived routes are hidden
+fn public_route(route_id: u64) -> Result<Route, Message> {
+    ROUTES
+        .with(|routes| routes.borrow().get(&route_id))
+        .filter(|route| route.status != "archived")
+        .ok_or(Message::NotFound("Route not found".to_string()))
+}
+
+// Helper function to find the ongoing trip of a matatu
+fn find_ongoing_trip(matatu_id: u64) -> Option<Trip> {
+    TRIPS.with(|trips| {
+        trips
             .borrow()
             .iter()
-            .filter(|(_, r)| r.matatu_id == matatu_id)
-            .map(|(_, r)| r.cost)
-            .sum()
-    });
+            .find(|(_, t)| t.matatu_id == matatu_id && t.status == "ongoing")
+            .map(|(_, t)| t.clone())
+    })
+}
 
-    let fuel_costs = FUEL_RECORDS.with(|records| {
-        records
+// Helper function to find the ongoing trip of a driver
+fn find_driver_ongoing_trip(driver_id: u64) -> Option<Trip> {
+    TRIPS.with(|trips| {
+        trips
             .borrow()
             .iter()
-            .filter(|(_, r)| r.matatu_id == matatu_id)
-            .map(|(_, r)| r.cost)
-            .sum()
-    });
-
-    Ok(MatatuAnalytics {
-        total_trips,
-        total_revenue,
-        maintenance_costs,
-        fuel_costs,
-        net_profit: total_revenue - maintenance_costs - fuel_costs,
+            .find(|(_, t)| t.driver_id == driver_id && t.status == "ongoing")
+            .map(|(_, t)| t.clone())
     })
 }
 
-// Route Optimization Functions
-#[ic_cdk::update]
-fn optimize_route(route_id: u64, current_time: u64) -> Result<RouteOptimization, Message> {
-    ROUTES.with(|routes| {
-        if let Some(route) = routes.borrow().get(&route_id) {
-            let current_hour = (current_time / 3600) % 24;
-            let current_day = ((current_time / 86400) % 7) as u8;
-
-            // Find current traffic pattern
-            let default_pattern = TrafficPattern::default();
-            let traffic_pattern = route
-                .traffic_patterns
-                .iter()
-                .find(|tp| {
-                    let window = &tp.time_window;
-                    window.day_of_week == current_day
-                        && window.start_hour <= current_hour as u8
-                        && window.end_hour > current_hour as u8
-                })
-                .unwrap_or(&default_pattern);
+// Helper function to get why a matatu can't start a trip, if anything
+fn matatu_unavailability(matatu: &Matatu) -> Option<String> {
+    if matatu.status == "inactive" || matatu.status == "archived" {
+        return Some(format!("Matatu is {}", matatu.status));
+    }
 
-            // Calculate optimized route details
-            let base_time = route.estimated_time as f64;
-            let delay_factor = 1.0 + (traffic_pattern.congestion_level as f64 / 5.0);
-            let optimized_time = (base_time * delay_factor) as u32;
+    SACCOS
+        .with(|saccos| saccos.borrow().get(&matatu.sacco_id))
+        .filter(|sacco| sacco.status != "active")
+        .map(|sacco| format!("SACCO is {}", sacco.status))
+}
 
-            Ok(RouteOptimization {
-                route_id,
-                optimal_start_time: current_time + 600, // 10 minutes buffer
-                estimated_duration: optimized_time,
-                congestion_level: traffic_pattern.congestion_level,
-                alternate_routes: vec![], // Could be implemented with actual alternatives
-            })
-        } else {
-            Err(Message::NotFound("Route not found".to_string()))
-        }
-    })
+// Helper function to get why a matatu can't be taken out of service, if anything
+fn matatu_in_use(matatu_id: u64, now: u64) -> Option<String> {
+    if find_ongoing_trip(matatu_id).is_some() {
+        return Some("Matatu has an ongoing trip".to_string());
+    }
+
+    let upcoming = upcoming_schedules(|s| s.matatu_id == matatu_id, now);
+    (upcoming > 0).then(|| format!("Matatu has {} upcoming departures", upcoming))
 }
 
+// Helper function to get why a driver can't be taken out of service, if anything
+fn driver_in_use(driver_id: u64, now: u64) -> Option<String> {
+    if find_driver_ongoing_trip(driver_id).is_some() {
+        return Some("Driver has an ongoing trip".to_string());
+    }
+    if open_shift(driver_id).is_some() {
+        return Some("Driver is checked in".to_string());
+    }
 
+    let upcoming = upcoming_schedules(|s| s.driver_id == driver_id, now);
+    (upcoming > 0).then(|| format!("Driver has {} upcoming departures", upcoming))
+}
 
-// Customer Feedback System
-#[ic_cdk::update]
-fn submit_feedback(payload: CustomerFeedbackPayload) -> Result<CustomerFeedback, Message> {
-    let feedback_id = ID_COUNTER.with(|counter| {
-        let current_value = *counter.borrow().get();
-        counter.borrow_mut().set(current_value + 1).unwrap();
-        current_value + 1
+// Helper function to get why a route can't be taken out of service, if anything
+fn route_in_use(route: &Route, now: u64) -> Option<String> {
+    let running = TRIPS.with(|trips| {
+        trips.borrow().iter().any(|(_, t)| {
+            t.status == "ongoing"
+                && t.route == route.name
+                && trip_route(&t).is_some_and(|r| r.id == route.id)
+        })
     });
+    if running {
+        return Some("Route has ongoing trips".to_string());
+    }
 
-    let feedback = CustomerFeedback {
-        id: feedback_id,
-        trip_id: payload.trip_id,
-        rating: payload.rating,
-        cleanliness: payload.cleanliness,
-        punctuality: payload.punctuality,
-        safety: payload.safety,
-        comment: payload.comment,
-        timestamp: time(),
-    };
+    let upcoming = upcoming_schedules(|s| s.route_id == route.id, now);
+    (upcoming > 0).then(|| format!("Route has {} upcoming departures", upcoming))
+}
 
-    CUSTOMER_FEEDBACK
-        .with(|feedbacks| feedbacks.borrow_mut().insert(feedback_id, feedback.clone()));
+// Helper function to count schedules still to run that match a filter
+fn upcoming_schedules(filter: impl Fn(&Schedule) -> bool, now: u64) -> usize {
+    SCHEDULES.with(|schedules| {
+        schedules
+            .borrow()
+            .iter()
+            .filter(|(_, s)| {
+                (s.status == "scheduled" || s.status == "in_progress")
+                    && s.end_time > now
+                    && filter(s)
+            })
+            .count()
+    })
+}
 
-    // Update driver performance based on feedback
-    update_driver_performance(feedback.trip_id, feedback.rating as f64);
+// Helper function to find one of a SACCO's routes by its name
+fn find_route_by_name(sacco_id: u64, name: &str) -> Option<Route> {
+    ROUTES.with(|routes| {
+        routes
+            .borrow()
+            .iter()
+            .find(|(_, r)| r.sacco_id == sacco_id && r.name == name)
+            .map(|(_, r)| r.clone())
+    })
+}
 
-    Ok(feedback)
+// Helper function to find the route a trip runs on, among its matatu's SACCO's routes
+fn trip_route(trip: &Trip) -> Option<Route> {
+    let matatu = MATATUS.with(|matatus| matatus.borrow().get(&trip.matatu_id))?;
+    find_route_by_name(matatu.sacco_id, &trip.route)
 }
 
-// Automated Scheduling System
-#[ic_cdk::update]
-fn create_automated_schedule(sacco_id: u64, date: u64) -> Result<Vec<Schedule>, Message> {
-    let mut schedules = Vec::new();
+// Helper function to get the stops of a route in travel order
+fn get_ordered_stops(route_id: u64) -> Vec<RouteStop> {
+    let mut stops: Vec<RouteStop> = ROUTE_STOPS.with(|stops| {
+        stops
+            .borrow()
+            .iter()
+            .filter(|(_, s)| s.route_id == route_id)
+            .map(|(_, s)| s.clone())
+            .collect()
+    });
+    stops.sort_by_key(|s| s.sequence);
+    stops
+}
 
-    // Get all available matatus and drivers
-    let available_matatus = get_available_matatus(sacco_id, date);
-    let available_drivers = get_available_drivers(sacco_id, date);
-    let routes: Vec<Route> =
-        ROUTES.with(|r| r.borrow().iter().map(|(_, route)| route.clone()).collect());
-
-    // Create optimal schedule based on historical data and availability
-    for (matatu, driver) in available_matatus.iter().zip(available_drivers.iter()) {
-        for route in routes.iter() {
-            let optimal_times = calculate_optimal_times(route, date);
-
-            for (start_time, end_time) in optimal_times {
-                let schedule = Schedule {
-                    id: generate_id(),
-                    matatu_id: matatu.id,
-                    driver_id: driver.id,
-                    route_id: route.id,
-                    start_time,
-                    end_time,
-                    status: "scheduled".to_string(),
-                    created_at: time(),
-                };
+// Helper function to build the route line from its ordered stops
+fn route_polyline(route_id: u64) -> Vec<(f64, f64)> {
+    get_ordered_stops(route_id)
+        .iter()
+        .map(|s| (s.latitude, s.longitude))
+        .collect()
+}
 
-                SCHEDULES.with(|s| s.borrow_mut().insert(schedule.id, schedule.clone()));
+// Helper function to refuse an update made against a stale copy of a record
+fn check_version(entity: &str, current: u64, expected: u64) -> Result<(), Message> {
+    if current == expected {
+        Ok(())
+    } else {
+        Err(Message::Error(format!(
+            "{} has changed since version {}, it is now at version {}",
+            entity, expected, current
+        )))
+    }
+}
 
-                schedules.push(schedule);
-            }
+// Helper function to check an email address looks like local@domain.tld
+fn validate_email(email: &str) -> Result<(), Message> {
+    let valid = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain
+                    .split('.')
+                    .all(|part| !part.is_empty() && !part.starts_with('-'))
+                && domain.contains('.')
+                && !email.chars().any(|c| c.is_whitespace())
         }
+        None => false,
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Message::InvalidPayload("Invalid email address".to_string()))
     }
+}
 
-    Ok(schedules)
+// Helper function to reject text too long to store
+fn check_text(field: &str, value: &str, max: usize) -> Result<(), Message> {
+    if value.len() > max {
+        return Err(Message::InvalidPayload(format!(
+            "{} can be at most {} bytes",
+            field, max
+        )));
+    }
+    Ok(())
 }
 
-// Real-time Tracking System
-#[ic_cdk::update]
-fn update_location(payload: LocationUpdatePayload) -> Result<LocationUpdate, Message> {
-    let update_id = generate_id();
-    let location_update = LocationUpdate {
-        id: update_id,
-        matatu_id: payload.matatu_id,
-        latitude: payload.latitude,
-        longitude: payload.longitude,
-        speed: payload.speed,
-        timestamp: time(),
-    };
+// Helper function to check a Kenyan mobile number and store it as +254
+// followed by nine digits. Accepts 07.., 01.., 254.. and +254.. with spaces
+// or dashes; landlines such as 020.. are not accepted.
+fn normalize_phone(phone: &str) -> Result<String, Message> {
+    let digits: String = phone
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect();
+    let digits = digits.strip_prefix('+').unwrap_or(&digits);
+
+    let national = digits
+        .strip_prefix("254")
+        .or_else(|| digits.strip_prefix('0'))
+        .unwrap_or_default();
+
+    if national.len() == 9
+        && national.chars().all(|c| c.is_ascii_digit())
+        && (national.starts_with('7') || national.starts_with('1'))
+    {
+        Ok(format!("+254{}", national))
+    } else {
+        Err(Message::InvalidPayload(
+            "Contact must be a Kenyan phone number such as 0712345678 or +254712345678".to_string(),
+        ))
+    }
+}
+
+// Helper function to check a plate against the national registry pattern,
+// e.g. KAB 123C, and store it in upper case without spaces
+fn normalize_plate(plate: &str) -> Result<String, Message> {
+    let plate: String = plate
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let bytes = plate.as_bytes();
+
+    let valid = bytes.len() == 7
+        && bytes[0] == b'K'
+        && bytes[1..3].iter().all(u8::is_ascii_uppercase)
+        && bytes[3..6].iter().all(u8::is_ascii_digit)
+        && bytes[6].is_ascii_uppercase();
+    if valid {
+        Ok(plate)
+    } else {
+        Err(Message::InvalidPayload(
+            "Plate number must follow the national pattern, e.g. KAB 123C".to_string(),
+        ))
+    }
+}
+
+// Helper function to refuse a plate already carried by another matatu still in service
+fn check_plate_available(plate_number: &str, matatu_id: Option<u64>) -> Result<(), Message> {
+    let taken = MATATUS.with(|matatus| {
+        matatus.borrow().iter().any(|(id, m)| {
+            Some(id) != matatu_id && m.status != "archived" && m.plate_number == plate_number
+        })
+    });
+
+    if taken {
+        Err(Message::Error(format!(
+            "Plate number {} is already registered",
+            plate_number
+        )))
+    } else {
+        Ok(())
+    }
+}
 
-    LOCATION_UPDATES.with(|updates| {
-        updates
+// Helper function to append an entry for the current call to the audit trail,
+// then prune the oldest entries past the retention policy
+fn audit<T: candid::CandidType>(
+    method: &str,
+    entity_type: &str,
+    entity_id: u64,
+    before: Option<&T>,
+    after: Option<&T>,
+) {
+    let now = time();
+    let id = AUDIT_SEQUENCE.with(|sequence| {
+        let next = *sequence.borrow().get() + 1;
+        sequence
             .borrow_mut()
-            .insert(update_id, location_update.clone())
+            .set(next)
+            .expect("Audit sequence increment failed");
+        next
+    });
+
+    let entry = AuditEntry {
+        id,
+        caller: Some(caller()),
+        method: method.to_string(),
+        entity_type: entity_type.to_string(),
+        entity_id,
+        before_hash: before.map(content_hash),
+        after_hash: after.map(content_hash),
+        timestamp: now,
+        sacco_id: audit_sacco(entity_type, entity_id),
+    };
+
+    let retention = AUDIT_RETENTION.with(|cell| cell.borrow().get().clone());
+    AUDIT_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        log.insert(id, entry);
+
+        for _ in 0..AUDIT_PRUNE_BATCH {
+            let oldest = log.iter().next();
+            match oldest {
+                Some((oldest_id, oldest))
+                    if log.len() > retention.max_entries
+                        || now.saturating_sub(oldest.timestamp) > retention.max_age =>
+                {
+                    log.remove(&oldest_id);
+                }
+                _ => break,
+            }
+        }
     });
+}
 
-    // Update estimated arrival times for affected schedules
-    update_arrival_estimates(payload.matatu_id, &location_update);
+// Helper function to find the SACCO an audited entity belongs to, None for
+// canister-wide entities and calls that touch many entities
+fn audit_sacco(entity_type: &str, entity_id: u64) -> Option<u64> {
+    let matatu = |id: u64| {
+        MATATUS
+            .with(|matatus| matatus.borrow().get(&id))
+            .map(|m| m.sacco_id)
+    };
+    let driver = |id: u64| {
+        DRIVERS
+            .with(|drivers| drivers.borrow().get(&id))
+            .map(|d| d.sacco_id)
+    };
+    let route = |id: u64| {
+        ROUTES
+            .with(|routes| routes.borrow().get(&id))
+            .map(|r| r.sacco_id)
+    };
+    let trip = |id: u64| {
+        TRIPS
+            .with(|trips| trips.borrow().get(&id))
+            .and_then(|t| matatu(t.matatu_id))
+    };
 
-    Ok(location_update)
+    if entity_id == 0 {
+        return None;
+    }
+    match entity_type {
+        // these are keyed by their SACCO
+        "sacco" | "sacco_member" | "hours_of_service_policy" | "speed_policy" | "roster" => {
+            Some(entity_id)
+        }
+        "matatu" | "location" => matatu(entity_id),
+        "driver" => driver(entity_id),
+        "route" | "fare_rule" => route(entity_id),
+        "trip" => trip(entity_id),
+        "driver_leave" => DRIVER_LEAVE
+            .with(|leave| leave.borrow().get(&entity_id))
+            .and_then(|l| driver(l.driver_id)),
+        "shift" => SHIFTS
+            .with(|shifts| shifts.borrow().get(&entity_id))
+            .and_then(|s| driver(s.driver_id)),
+        "ticket" => TICKETS
+            .with(|tickets| tickets.borrow().get(&entity_id))
+            .and_then(|t| matatu(t.matatu_id)),
+        "maintenance" => MAINTENANCE_RECORDS
+            .with(|records| records.borrow().get(&entity_id))
+            .and_then(|m| matatu(m.matatu_id)),
+        "customer_feedback" => CUSTOMER_FEEDBACK
+            .with(|feedback| feedback.borrow().get(&entity_id))
+            .and_then(|f| trip(f.trip_id)),
+        "demand_adjustment" => DEMAND_ADJUSTMENTS
+            .with(|adjustments| adjustments.borrow().get(&entity_id))
+            .and_then(|a| a.route_id)
+            .and_then(route),
+        "device" => DEVICES
+            .with(|devices| devices.borrow().get(&entity_id))
+            .map(|d| d.sacco_id),
+        "geofence" => GEOFENCES
+            .with(|geofences| geofences.borrow().get(&entity_id))
+            .map(|g| g.sacco_id),
+        "incident" => INCIDENTS
+            .with(|incidents| incidents.borrow().get(&entity_id))
+            .map(|i| i.sacco_id),
+        _ => None,
+    }
 }
 
-// Financial Reporting System
-#[ic_cdk::query]
-fn generate_financial_report(
-    sacco_id: u64,
-    start_time: u64,
-    end_time: u64,
-) -> Result<FinancialReport, Message> {
-    let revenues = calculate_total_revenues(sacco_id, start_time, end_time);
-    let expenses = calculate_total_expenses(sacco_id, start_time, end_time);
+// Helper function to hash a value's candid encoding (64-bit FNV-1a)
+fn content_hash<T: candid::CandidType>(value: &T) -> u64 {
+    Encode!(value)
+        .unwrap_or_default()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
 
-    let revenue_breakdown = analyze_revenue_sources(sacco_id, start_time, end_time);
-    let expense_breakdown = analyze_expense_categories(sacco_id, start_time, end_time);
+// Helper function to check whether a principal is a member of a SACCO
+fn is_sacco_member(sacco_id: u64, principal: Principal) -> bool {
+    SACCO_MEMBERS.with(|members| {
+        members.borrow().contains_key(&MemberKey {
+            sacco_id,
+            principal,
+        })
+    })
+}
+
+// Helper function to get the members of a SACCO
+fn sacco_members(sacco_id: u64) -> Vec<SaccoMember> {
+    SACCO_MEMBERS.with(|members| {
+        members
+            .borrow()
+            .iter()
+            .filter(|(key, _)| key.sacco_id == sacco_id)
+            .map(|(_, m)| m.clone())
+            .collect()
+    })
+}
 
-    let profit_margin = if revenues > 0.0 {
-        ((revenues - expenses) / revenues) * 100.0
+// Helper function to check the caller may act for a SACCO, as one of its
+// members or as a canister controller
+fn authorize_sacco(sacco_id: u64) -> Result<(), Message> {
+    let caller = caller();
+    if ic_cdk::api::is_controller(&caller)
+        || (caller != Principal::anonymous() && is_sacco_member(sacco_id, caller))
+    {
+        Ok(())
     } else {
-        0.0
-    };
+        Err(Message::Error(
+            "Caller is not a member of this SACCO".to_string(),
+        ))
+    }
+}
 
-    let report = FinancialReport {
-        id: generate_id(),
-        sacco_id,
-        period_start: start_time,
-        period_end: end_time,
-        total_revenue: revenues,
-        total_expenses: expenses,
-        expense_breakdown,
-        revenue_breakdown,
-        profit_margin,
-        created_at: time(),
-    };
+// Helper function to check the caller may manage a SACCO and its members
+fn authorize_sacco_admin(sacco_id: u64) -> Result<(), Message> {
+    let caller = caller();
+    let admin = SACCO_MEMBERS
+        .with(|members| {
+            members.borrow().get(&MemberKey {
+                sacco_id,
+                principal: caller,
+            })
+        })
+        .is_some_and(|m| m.role == "admin");
+    if ic_cdk::api::is_controller(&caller) || (caller != Principal::anonymous() && admin) {
+        Ok(())
+    } else {
+        Err(Message::Error(
+            "Caller is not an admin of this SACCO".to_string(),
+        ))
+    }
+}
 
-    FINANCIAL_REPORTS.with(|reports| reports.borrow_mut().insert(report.id, report.clone()));
+// Helper function to check the caller is the driver or acts for their SACCO
+fn authorize_driver(driver: &Driver) -> Result<(), Message> {
+    let caller = caller();
+    if caller != Principal::anonymous() && driver.principal == Some(caller) {
+        return Ok(());
+    }
+    authorize_sacco(driver.sacco_id)
+}
 
-    Ok(report)
+// Helper function to check the caller is the trip's driver or acts for its SACCO
+fn authorize_trip(trip: &Trip) -> Result<(), Message> {
+    match DRIVERS.with(|drivers| drivers.borrow().get(&trip.driver_id)) {
+        Some(driver) => authorize_driver(&driver),
+        None => Err(Message::NotFound("Driver not found".to_string())),
+    }
 }
 
-// Helper Functions
+// Helper function to get a matatu of a SACCO the caller acts for
+fn tenant_matatu(matatu_id: u64) -> Result<Matatu, Message> {
+    let matatu = MATATUS
+        .with(|matatus| matatus.borrow().get(&matatu_id))
+        .ok_or(Message::NotFound("Matatu not found".to_string()))?;
+    authorize_sacco(matatu.sacco_id)?;
+    Ok(matatu)
+}
 
-fn calculate_optimal_times(route: &Route, date: u64) -> Vec<(u64, u64)> {
-    let mut optimal_times = Vec::new();
+// Helper function to get a driver of a SACCO the caller acts for
+fn tenant_driver(driver_id: u64) -> Result<Driver, Message> {
+    let driver = DRIVERS
+        .with(|drivers| drivers.borrow().get(&driver_id))
+        .ok_or(Message::NotFound("Driver not found".to_string()))?;
+    authorize_sacco(driver.sacco_id)?;
+    Ok(driver)
+}
 
-    // Calculate optimal departure times based on:
-    // 1. Historical peak hours
-    // 2. Traffic patterns
-    // 3. Average passenger demand
-    // 4. Vehicle availability
+// Helper function to get a route of a SACCO the caller acts for
+fn tenant_route(route_id: u64) -> Result<Route, Message> {
+    let route = ROUTES
+        .with(|routes| routes.borrow().get(&route_id))
+        .ok_or(Message::NotFound("Route not found".to_string()))?;
+    authorize_sacco(route.sacco_id)?;
+    Ok(route)
+}
 
-    for peak_hour in &route.peak_hours {
-        let start_time = date + (peak_hour.start_hour as u64 * 3600);
-        let end_time = start_time + (route.estimated_time as u64 * 60);
-        optimal_times.push((start_time, end_time));
+// Helper function to check a matatu is licensed for one of its SACCO's own routes
+fn check_sacco_route(sacco_id: u64, name: &str) -> Result<(), Message> {
+    match find_route_by_name(sacco_id, name) {
+        Some(route) if route.status == "archived" => {
+            Err(Message::Error("Route is archived".to_string()))
+        }
+        Some(_) => Ok(()),
+        None => Err(Message::InvalidPayload(
+            "Route is not one of the SACCO's routes".to_string(),
+        )),
     }
+}
 
-    optimal_times
+// Helper function to get the principal a call acts for: the holder of the API
+// token on an HTTP gateway request, otherwise the Candid caller
+fn caller() -> Principal {
+    HTTP_CALLER
+        .with(|holder| *holder.borrow())
+        .unwrap_or_else(ic_cdk::caller)
 }
 
-fn update_arrival_estimates(matatu_id: u64, location: &LocationUpdate) {
-    SCHEDULES.with(|schedules| {
-        let mut schedules_map = schedules.borrow_mut();
-        let keys_to_update: Vec<u64> = schedules_map
+// Helper function to get the principal an API token was issued to, None when
+// the token is unknown, revoked or expired
+fn api_token_holder(token: &str) -> Option<Principal> {
+    let (id, secret) = token.split_once('.')?;
+    let token = API_TOKENS.with(|tokens| tokens.borrow().get(&id.parse().ok()?))?;
+    let matches = token.secret_hash.len() == 32
+        && token
+            .secret_hash
             .iter()
-            .filter_map(|(key, schedule)| {
-                if schedule.matatu_id == matatu_id && schedule.status == "in_progress" {
-                    Some(key)
-                } else {
-                    None
-                }
-            })
-            .collect();
+            .zip(secret_hash(secret))
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+    (token.status == "active" && token.expires_at > time() && matches).then_some(token.principal)
+}
 
-        for key in keys_to_update {
-            if let Some(schedule) = schedules_map.get(&key) {
-                let mut updated_schedule = schedule.clone(); // Clone the schedule for modification
-                let new_end_time = calculate_new_arrival_time(&updated_schedule, location);
-                updated_schedule.end_time = new_end_time;
-                schedules_map.insert(key, updated_schedule);
-            }
-        }
-    });
+// Helper function to hash an API token secret for storage. Digests are compared
+// in full rather than stopping at the first difference.
+fn secret_hash(secret: &str) -> Vec<u8> {
+    Sha256::digest(secret.as_bytes()).to_vec()
 }
 
+// Helper function to reject callers that don't control the canister
+fn require_controller() -> Result<(), Message> {
+    if ic_cdk::api::is_controller(&caller()) {
+        Ok(())
+    } else {
+        Err(Message::Error(
+            "Only canister controllers can do this".to_string(),
+        ))
+    }
+}
 
+// Helper function to take the next id of a kind of record. Sequences start where
+// the shared counter stopped, so they never reuse an id handed out before.
+fn next_id(sequence: Sequence) -> u64 {
+    ID_SEQUENCES.with(|sequences| {
+        let mut sequences = sequences.borrow_mut();
+        let current = sequences
+            .get(&(sequence as u8))
+            .unwrap_or_else(|| ID_COUNTER.with(|counter| *counter.borrow().get()));
+        sequences.insert(sequence as u8, current + 1);
+        current + 1
+    })
+}
 
+// Helper function to list the keys and ids of up to `limit` records numbered
+// by a sequence, starting at key `from`
+fn sequence_ids(sequence: Sequence, from: u64, limit: usize) -> Vec<(u64, u64)> {
+    fn ids<T: BoundedStorable + Clone>(
+        map: &'static std::thread::LocalKey<RefCell<StableBTreeMap<u64, T, Memory>>>,
+        id: fn(&T) -> u64,
+        from: u64,
+        limit: usize,
+    ) -> Vec<(u64, u64)> {
+        map.with(|map| {
+            map.borrow()
+                .range(from..)
+                .take(limit)
+                .map(|(key, r)| (key, id(&r)))
+                .collect()
+        })
+    }
 
-fn calculate_total_revenues(sacco_id: u64, start_time: u64, end_time: u64) -> f64 {
-    REVENUES.with(|revenues| {
-        revenues
-            .borrow()
-            .iter()
-            .filter(|(_, r)| r.sacco_id == sacco_id && r.date >= start_time && r.date <= end_time)
-            .map(|(_, r)| r.amount)
-            .sum()
-    })
+    match sequence {
+        Sequence::Sacco => ids(&SACCOS, |r| r.id, from, limit),
+        Sequence::Matatu => ids(&MATATUS, |r| r.id, from, limit),
+        Sequence::Driver => ids(&DRIVERS, |r| r.id, from, limit),
+        Sequence::Trip => ids(&TRIPS, |r| r.id, from, limit),
+        Sequence::Route => ids(&ROUTES, |r| r.id, from, limit),
+        Sequence::RouteStop => ids(&ROUTE_STOPS, |r| r.id, from, limit),
+        Sequence::Schedule => ids(&SCHEDULES, |r| r.id, from, limit),
+        Sequence::Ticket => ids(&TICKETS, |r| r.id, from, limit),
+        Sequence::Shift => ids(&SHIFTS, |r| r.id, from, limit),
+        Sequence::DriverLeave => ids(&DRIVER_LEAVE, |r| r.id, from, limit),
+        Sequence::Maintenance => ids(&MAINTENANCE_RECORDS, |r| r.id, from, limit),
+        Sequence::CustomerFeedback => ids(&CUSTOMER_FEEDBACK, |r| r.id, from, limit),
+        Sequence::DriverPerformance => ids(&DRIVER_PERFORMANCE, |r| r.id, from, limit),
+        Sequence::DemandAdjustment => ids(&DEMAND_ADJUSTMENTS, |r| r.id, from, limit),
+        Sequence::Device => ids(&DEVICES, |r| r.id, from, limit),
+        Sequence::Geofence => ids(&GEOFENCES, |r| r.id, from, limit),
+        Sequence::GeofenceEvent => ids(&GEOFENCE_EVENTS, |r| r.id, from, limit),
+        Sequence::Incident => ids(&INCIDENTS, |r| r.id, from, limit),
+        Sequence::SpeedViolation => ids(&SPEED_VIOLATIONS, |r| r.id, from, limit),
+        Sequence::FinancialReport => ids(&FINANCIAL_REPORTS, |r| r.id, from, limit),
+        Sequence::ApiToken => ids(&API_TOKENS, |r| r.id, from, limit),
+    }
 }
 
-fn analyze_revenue_sources(sacco_id: u64, start_time: u64, end_time: u64) -> Vec<RevenueSource> {
-    // Analyze revenue by different categories:
-    // 1. Regular trips
-    // 2. Special routes
-    // 3. Peak hour surcharges
-    // 4. Additional services
-    let total_revenue = calculate_total_revenues(sacco_id, start_time, end_time);
+// Helper function to take the highest key stored for a sequence, found by
+// probing forward in growing steps rather than walking the map
+fn sequence_last_key(sequence: Sequence) -> u64 {
+    let mut last: u64 = 0;
+    let mut step: u64 = 1;
+    loop {
+        match sequence_ids(sequence, last.saturating_add(step), 1).first() {
+            Some(&(key, _)) if key > last => {
+                last = key;
+                step = step.saturating_mul(2);
+            }
+            _ if step == 1 => return last,
+            _ => step /= 2,
+        }
+    }
+}
 
-    REVENUES.with(|revenues| {
-        let revenues_map = revenues.borrow();
-        let mut sources = HashMap::new();
+// Steps of the upgrade migration in the order they run. Each looks at one
+// batch from the migration's cursor and returns true once it has finished.
+const MIGRATION_STEPS: [fn(&mut UpgradeMigration) -> bool; 9] = [
+    migrate_legacy_pings,
+    migrate_performance_months,
+    migrate_traffic_hours,
+    assign_route_saccos,
+    assign_sacco_codes,
+    assign_fleet_numbers,
+    assign_staff_numbers,
+    check_record_ids,
+    hash_api_token_secrets,
+];
+
+// Runs one batch of the upgrade migration and sets a timer for the next one
+// until every step has finished. Progress is kept in stable memory, so an
+// upgrade in the middle starts the migration again from the first step.
+fn run_upgrade_migration() {
+    let mut migration = UPGRADE_MIGRATION.with(|cell| cell.borrow().get().clone());
+    if migration.finished_at.is_some() {
+        return;
+    }
 
-        for (_, revenue) in revenues_map.iter() {
-            if revenue.sacco_id == sacco_id
-                && revenue.date >= start_time
-                && revenue.date <= end_time
-            {
-                *sources.entry(revenue.description.clone()).or_insert(0.0) += revenue.amount;
+    match MIGRATION_STEPS.get(migration.step as usize) {
+        Some(step) => {
+            if step(&mut migration) {
+                migration.step += 1;
+                migration.part = 0;
+                migration.cursor = 0;
+                migration.numbered.clear();
             }
         }
+        None => migration.finished_at = Some(time()),
+    }
 
-        sources
-            .into_iter()
-            .map(|(source, amount)| RevenueSource {
-                source,
-                amount,
-                percentage: if total_revenue > 0.0 {
-                    (amount / total_revenue) * 100.0
-                } else {
-                    0.0
-                },
-            })
-            .collect()
-    })
+    let finished = migration.finished_at.is_some();
+    UPGRADE_MIGRATION
+        .with(|cell| cell.borrow_mut().set(migration))
+        .expect("Cannot save the upgrade migration");
+    if !finished {
+        ic_cdk_timers::set_timer(Duration::ZERO, run_upgrade_migration);
+    }
 }
 
-// Helper function to get get_available_matatus
-fn get_available_matatus(sacco_id: u64, date: u64) -> Vec<Matatu> {
-    MATATUS.with(|matatus| {
-        matatus
-            .borrow()
-            .iter()
-            .filter(|(_, m)| m.sacco_id == sacco_id && m.status == "active")
-            .map(|(_, m)| m.clone())
-            .collect()
-    })
+// Helper function to move pings stored under the old global IDs into the
+// time-keyed history. Moved pings are removed, so each batch starts at the front.
+fn migrate_legacy_pings(_: &mut UpgradeMigration) -> bool {
+    let legacy: Vec<(u64, LegacyLocationUpdate)> = LEGACY_LOCATION_UPDATES
+        .with(|updates| updates.borrow().iter().take(MIGRATION_BATCH).collect());
+    let done = legacy.len() < MIGRATION_BATCH;
+
+    for (id, update) in legacy {
+        LOCATION_UPDATES.with(|updates| {
+            updates.borrow_mut().insert(
+                LocationKey {
+                    matatu_id: update.matatu_id,
+                    timestamp: update.timestamp,
+                },
+                LocationUpdate {
+                    matatu_id: update.matatu_id,
+                    latitude: update.latitude,
+                    longitude: update.longitude,
+                    speed: update.speed,
+                    timestamp: update.timestamp,
+                },
+            )
+        });
+        LEGACY_LOCATION_UPDATES.with(|updates| updates.borrow_mut().remove(&id));
+    }
+    done
 }
 
-// Helper function to get get_available_drivers
-fn get_available_drivers(sacco_id: u64, date: u64) -> Vec<Driver> {
-    DRIVERS.with(|drivers| {
-        drivers
+// Helper function to move driver performance kept per 30-day period to the
+// calendar month the period started in. Each moved record looks for one to
+// merge with, so fewer records are taken per batch.
+fn migrate_performance_months(migration: &mut UpgradeMigration) -> bool {
+    let batch: Vec<(u64, DriverPerformance)> = DRIVER_PERFORMANCE.with(|performances| {
+        performances
             .borrow()
-            .iter()
-            .filter(|(_, d)| d.sacco_id == sacco_id)
-            .map(|(_, d)| d.clone())
+            .range(migration.cursor..)
+            .take(MIGRATION_BATCH / 20)
             .collect()
-    })
+    });
+    let Some(&(last, _)) = batch.last() else {
+        return true;
+    };
+    migration.cursor = last + 1;
+
+    for (_, mut performance) in batch {
+        if performance.month >= FIRST_CALENDAR_MONTH {
+            continue;
+        }
+        performance.month = calendar::month_of(performance.month * LEGACY_MONTH_NANOS);
+        let existing = DRIVER_PERFORMANCE.with(|performances| {
+            performances
+                .borrow()
+                .iter()
+                .find(|(_, p)| p.driver_id == performance.driver_id && p.month == performance.month)
+                .map(|(_, p)| p)
+        });
+
+        DRIVER_PERFORMANCE.with(|performances| {
+            let mut performances = performances.borrow_mut();
+            match existing {
+                // Two periods starting in the same month are combined
+                Some(mut merged) => {
+                    let trips = merged.trips_completed + performance.trips_completed;
+                    if trips > 0 {
+                        merged.customer_rating = (merged.customer_rating
+                            * merged.trips_completed as f32
+                            + performance.customer_rating * performance.trips_completed as f32)
+                            / trips as f32;
+                    }
+                    merged.trips_completed = trips;
+                    merged.total_revenue += performance.total_revenue;
+                    merged.compliance_score =
+                        merged.compliance_score.min(performance.compliance_score);
+                    performances.insert(merged.id, merged);
+                    performances.remove(&performance.id);
+                }
+                None => {
+                    performances.insert(performance.id, performance);
+                }
+            }
+        });
+    }
+    false
 }
 
-// Helper function to calculate_new_arrival_time
-fn calculate_new_arrival_time(schedule: &Schedule, location: &LocationUpdate) -> u64 {
-    // Calculate new estimated arrival time based on:
-    // 1. Current location
-    // 2. Remaining distance
-    // 3. Current traffic conditions
-    // 4. Historical travel times
-    let remaining_distance = 0.0; // Could be calculated based on route and current location
-    let current_speed = location.speed;
-    let estimated_time = remaining_distance / current_speed;
+// Helper function to move traffic windows learned in UTC to East Africa Time
+// hours. A batch always ends on a route boundary, since a route's windows may
+// merge into one another.
+fn migrate_traffic_hours(migration: &mut UpgradeMigration) -> bool {
+    let mut windows: Vec<(TrafficKey, TrafficStats)> = Vec::new();
+    let mut next_route = None;
+    TRAFFIC_STATS.with(|stats| {
+        let from = TrafficKey {
+            route_id: migration.cursor,
+            day_of_week: 0,
+            hour: 0,
+        };
+        for (key, window) in stats.borrow().range(from..) {
+            if windows.len() >= MIGRATION_BATCH
+                && windows
+                    .last()
+                    .is_some_and(|(last, _)| last.route_id != key.route_id)
+            {
+                next_route = Some(key.route_id);
+                break;
+            }
+            windows.push((key, window));
+        }
+    });
+
+    // A window is in UTC when its last observation falls in another local hour
+    let legacy = windows.into_iter().filter(|(key, s)| {
+        calendar::day_of_week(s.updated_at) != key.day_of_week
+            || calendar::hour_of_day(s.updated_at) != key.hour
+    });
+    for (key, mut window) in legacy {
+        // Any timestamp in the UTC hour of the week gives its local hour, the
+        // epoch fell on a Thursday
+        let utc = ((key.day_of_week as u64 + 3) % 7 * 24 + key.hour as u64) * HOUR_NANOS;
+        let local = TrafficKey {
+            route_id: key.route_id,
+            day_of_week: calendar::day_of_week(utc),
+            hour: calendar::hour_of_day(utc),
+        };
+        window.day_of_week = local.day_of_week;
+        window.hour = local.hour;
+
+        TRAFFIC_STATS.with(|stats| {
+            let mut stats = stats.borrow_mut();
+            stats.remove(&key);
+            if let Some(current) = stats.get(&local) {
+                let trips = window.trips + current.trips;
+                if trips > 0 {
+                    window.average_duration = (window.average_duration * window.trips as f64
+                        + current.average_duration * current.trips as f64)
+                        / trips as f64;
+                }
+                let samples = window.segment_samples + current.segment_samples;
+                if samples > 0 {
+                    window.average_segment_speed = (window.average_segment_speed
+                        * window.segment_samples as f64
+                        + current.average_segment_speed * current.segment_samples as f64)
+                        / samples as f64;
+                }
+                window.trips = trips;
+                window.segment_samples = samples;
+                window.updated_at = window.updated_at.max(current.updated_at);
+            }
+            stats.insert(local, window);
+        });
+    }
 
-    location.timestamp + (estimated_time as u64)
+    match next_route {
+        Some(route_id) => {
+            migration.cursor = route_id;
+            false
+        }
+        None => true,
+    }
 }
 
-// Helper function to calculate_total_expenses
-fn calculate_total_expenses(sacco_id: u64, start_time: u64, end_time: u64) -> f64 {
-    EXPENSES.with(|expenses| {
-        expenses
+// Helper function to give routes created before routes belonged to a SACCO to
+// the SACCO licensing most matatus on them, or to the only SACCO there is.
+// Routes no SACCO can be found for stay unassigned, reachable by controllers.
+fn assign_route_saccos(migration: &mut UpgradeMigration) -> bool {
+    let batch: Vec<(u64, Route)> = ROUTES.with(|routes| {
+        routes
             .borrow()
+            .range(migration.cursor..)
+            .take(MIGRATION_BATCH / 20)
+            .collect()
+    });
+    let Some(&(last, _)) = batch.last() else {
+        return true;
+    };
+    migration.cursor = last + 1;
+
+    let unassigned: Vec<Route> = batch
+        .into_iter()
+        .map(|(_, r)| r)
+        .filter(|r| r.sacco_id == 0)
+        .collect();
+    if unassigned.is_empty() {
+        return false;
+    }
+    let saccos: Vec<u64> =
+        SACCOS.with(|saccos| saccos.borrow().iter().take(2).map(|(id, _)| id).collect());
+
+    for mut route in unassigned {
+        let mut licensed: BTreeMap<u64, usize> = BTreeMap::new();
+        MATATUS.with(|matatus| {
+            for (_, matatu) in matatus.borrow().iter() {
+                if matatu.route == route.name {
+                    *licensed.entry(matatu.sacco_id).or_default() += 1;
+                }
+            }
+        });
+
+        // Ties go to the older SACCO
+        let owner = licensed
             .iter()
-            .filter(|(_, e)| e.sacco_id == sacco_id && e.date >= start_time && e.date <= end_time)
-            .map(|(_, e)| e.amount)
-            .sum()
-    })
+            .max_by_key(|&(id, count)| (*count, std::cmp::Reverse(*id)))
+            .map(|(id, _)| *id)
+            .or(match saccos.as_slice() {
+                [only] => Some(*only),
+                _ => None,
+            });
+        if let Some(sacco_id) = owner {
+            route.sacco_id = sacco_id;
+            ROUTES.with(|routes| routes.borrow_mut().insert(route.id, route));
+        }
+    }
+    false
 }
 
-// Helper function to analyze_expense_categories
-fn analyze_expense_categories(
-    sacco_id: u64,
-    start_time: u64,
-    end_time: u64,
-) -> Vec<ExpenseCategory> {
-    // Analyze expenses by different categories:
-    // 1. Fuel
-    // 2. Maintenance
-    // 3. Salaries
-    // 4. Insurance
-    let total_expenses = calculate_total_expenses(sacco_id, start_time, end_time);
+// Helper function to give SACCOs stored before codes existed a code. Each code
+// is checked against every SACCO, so fewer are taken per batch.
+fn assign_sacco_codes(migration: &mut UpgradeMigration) -> bool {
+    let batch: Vec<(u64, SACCO)> = SACCOS.with(|saccos| {
+        saccos
+            .borrow()
+            .range(migration.cursor..)
+            .take(MIGRATION_BATCH / 20)
+            .collect()
+    });
+    let Some(&(last, _)) = batch.last() else {
+        return true;
+    };
+    migration.cursor = last + 1;
 
-    EXPENSES.with(|expenses| {
-        let expenses_map = expenses.borrow();
-        let mut categories = HashMap::new();
+    for (_, mut sacco) in batch.into_iter().filter(|(_, s)| s.code.is_empty()) {
+        sacco.code = derive_sacco_code(&sacco.name);
+        SACCOS.with(|saccos| saccos.borrow_mut().insert(sacco.id, sacco));
+    }
+    false
+}
 
-        for (_, expense) in expenses_map.iter() {
-            if expense.sacco_id == sacco_id
-                && expense.date >= start_time
-                && expense.date <= end_time
-            {
-                *categories.entry(expense.category.clone()).or_insert(0.0) += expense.amount;
-            }
+// Helper function to give matatus stored before numbering a fleet number in
+// the order they were registered with their SACCO
+fn assign_fleet_numbers(migration: &mut UpgradeMigration) -> bool {
+    let batch: Vec<(u64, Matatu)> = MATATUS.with(|matatus| {
+        matatus
+            .borrow()
+            .range(migration.cursor..)
+            .take(MIGRATION_BATCH)
+            .collect()
+    });
+    let Some(&(last, _)) = batch.last() else {
+        return true;
+    };
+    migration.cursor = last + 1;
+
+    let mut fleet_sizes: BTreeMap<u64, u64> = migration.numbered.iter().copied().collect();
+    for (_, mut matatu) in batch {
+        let position = fleet_sizes.entry(matatu.sacco_id).or_default();
+        *position += 1;
+        if !matatu.fleet_number.is_empty() {
+            continue;
+        }
+        if let Some(sacco) = SACCOS.with(|saccos| saccos.borrow().get(&matatu.sacco_id)) {
+            matatu.fleet_number = format!("{}-{:03}", sacco.code, position);
+            MATATUS.with(|matatus| matatus.borrow_mut().insert(matatu.id, matatu));
         }
+    }
+    migration.numbered = fleet_sizes.into_iter().collect();
+    false
+}
 
-        categories
-            .into_iter()
-            .map(|(category, amount)| ExpenseCategory {
-                category,
-                amount,
-                percentage: if total_expenses > 0.0 {
-                    (amount / total_expenses) * 100.0
-                } else {
-                    0.0
-                },
-            })
+// Helper function to give drivers stored before numbering a staff number in
+// the order they were registered with their SACCO
+fn assign_staff_numbers(migration: &mut UpgradeMigration) -> bool {
+    let batch: Vec<(u64, Driver)> = DRIVERS.with(|drivers| {
+        drivers
+            .borrow()
+            .range(migration.cursor..)
+            .take(MIGRATION_BATCH)
             .collect()
-    })
+    });
+    let Some(&(last, _)) = batch.last() else {
+        return true;
+    };
+    migration.cursor = last + 1;
+
+    let mut staff_sizes: BTreeMap<u64, u64> = migration.numbered.iter().copied().collect();
+    for (_, mut driver) in batch {
+        let position = staff_sizes.entry(driver.sacco_id).or_default();
+        *position += 1;
+        if !driver.staff_number.is_empty() {
+            continue;
+        }
+        if let Some(sacco) = SACCOS.with(|saccos| saccos.borrow().get(&driver.sacco_id)) {
+            driver.staff_number = format!("{}-D{:03}", sacco.code, position);
+            DRIVERS.with(|drivers| drivers.borrow_mut().insert(driver.id, driver));
+        }
+    }
+    migration.numbered = staff_sizes.into_iter().collect();
+    false
 }
 
-// Helper function to update driver performance
-fn update_driver_performance(driver_id: u64, trip_revenue: f64) {
-    let current_month = time() / (30 * 24 * 60 * 60 * 1_000_000_000);
+// Helper function to check that every record is stored under its own id, one
+// sequence at a time. A record stored under another key may share its id with
+// another record; these are reported on the migration rather than fixed.
+fn check_record_ids(migration: &mut UpgradeMigration) -> bool {
+    let Some(&sequence) = Sequence::ALL.get(migration.part as usize) else {
+        return true;
+    };
+    let batch = sequence_ids(sequence, migration.cursor, MIGRATION_BATCH);
 
-    DRIVER_PERFORMANCE.with(|performances| {
-        let mut performances_map = performances.borrow_mut();
-        let performance = performances_map
-            .iter()
-            .find(|(_, p)| p.driver_id == driver_id && p.month == current_month)
-            .map(|(_, p)| p.clone())
-            .unwrap_or_else(|| {
-                // Create new performance record if none exists
-                DriverPerformance {
-                    id: ID_COUNTER.with(|counter| {
-                        let current_value = *counter.borrow().get();
-                        counter.borrow_mut().set(current_value + 1).unwrap();
-                        current_value + 1
-                    }),
-                    driver_id,
-                    month: current_month,
-                    trips_completed: 0,
-                    total_revenue: 0.0,
-                    customer_rating: 0.0,
-                    compliance_score: 100.0,
-                }
-            });
+    for &(key, id) in &batch {
+        if key != id && migration.problems.len() < MAX_MIGRATION_PROBLEMS {
+            migration
+                .problems
+                .push(format!("{:?} {} is stored under {}", sequence, id, key));
+        }
+    }
+    match batch.last() {
+        Some(&(key, _)) if batch.len() == MIGRATION_BATCH => migration.cursor = key + 1,
+        _ => {
+            migration.part += 1;
+            migration.cursor = 0;
+        }
+    }
+    false
+}
 
-        let mut updated_performance = performance.clone();
-        updated_performance.trips_completed += 1;
-        updated_performance.total_revenue += trip_revenue;
+// Helper function to store API tokens kept with their secret in the clear
+// again, hashed as they were decoded, so the secrets leave stable memory
+fn hash_api_token_secrets(migration: &mut UpgradeMigration) -> bool {
+    let batch: Vec<(u64, ApiToken)> = API_TOKENS.with(|tokens| {
+        tokens
+            .borrow()
+            .range(migration.cursor..)
+            .take(MIGRATION_BATCH)
+            .collect()
+    });
+    let Some(&(last, _)) = batch.last() else {
+        return true;
+    };
+    migration.cursor = last + 1;
 
-        performances_map.insert(updated_performance.id, updated_performance);
+    API_TOKENS.with(|tokens| {
+        let mut tokens = tokens.borrow_mut();
+        for (key, token) in batch {
+            tokens.insert(key, token);
+        }
     });
+    false
 }
 
-// Generate a new unique ID
-fn generate_id() -> u64 {
-    ID_COUNTER.with(|counter| {
-        let current_value = *counter.borrow().get();
-        counter.borrow_mut().set(current_value + 1).unwrap();
-        current_value + 1
-    })
+// Helper function to check whether a SACCO code is in use
+fn sacco_code_taken(code: &str) -> bool {
+    SACCOS.with(|saccos| saccos.borrow().iter().any(|(_, s)| s.code == code))
+}
+
+// Helper function to make a free SACCO code from the initials of its name,
+// numbering it when the initials are already taken
+fn derive_sacco_code(name: &str) -> String {
+    let words: Vec<&str> = name
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect();
+    let mut base: String = words
+        .iter()
+        .filter_map(|w| w.chars().next())
+        .take(MAX_SACCO_CODE - 2)
+        .collect();
+    if base.len() < 2 {
+        base = words.concat().chars().take(3).collect();
+    }
+    if base.len() < 2 {
+        base = "SC".to_string();
+    }
+    let base = base.to_uppercase();
+
+    let mut code = base.clone();
+    let mut suffix = 2;
+    while sacco_code_taken(&code) {
+        code = format!("{}{}", base, suffix);
+        suffix += 1;
+    }
+    code
 }
 
 // Exporting the candid interface